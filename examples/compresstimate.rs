@@ -1,29 +1,1239 @@
-use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use compresstimator::Compresstimator;
+use compresstimator::{
+    format_bytes, format_ratio, ByteUnits, Codec, Compresstimator, Confidence, Estimate,
+    RatioStyle, Sampling,
+};
 
-fn main() -> std::io::Result<()> {
-    let estimator = Compresstimator::default();
+fn invalid(var: &str, value: &str, expected: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}={:?}: expected {}", var, value, expected),
+    )
+}
+
+fn env_var(var: &str) -> io::Result<Option<String>> {
+    match env::var(var) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: not valid UTF-8", var),
+        )),
+    }
+}
+
+fn parse_confidence(value: &str) -> Option<Confidence> {
+    match value.trim().trim_end_matches('%') {
+        "80" => Some(Confidence::C80),
+        "85" => Some(Confidence::C85),
+        "90" => Some(Confidence::C90),
+        "95" => Some(Confidence::C95),
+        "99" => Some(Confidence::C99),
+        _ => None,
+    }
+}
+
+/// Override `estimator` with `COMPRESSTIMATE_BLOCK_SIZE`, `COMPRESSTIMATE_MARGIN`
+/// and `COMPRESSTIMATE_CONFIDENCE`, leaving anything unset alone.
+fn estimator_from_env(mut estimator: Compresstimator) -> io::Result<Compresstimator> {
+    if let Some(value) = env_var("COMPRESSTIMATE_BLOCK_SIZE")? {
+        match value.trim().parse::<u64>() {
+            Ok(size) if size > 0 => estimator.set_block_size(size),
+            _ => {
+                return Err(invalid(
+                    "COMPRESSTIMATE_BLOCK_SIZE",
+                    &value,
+                    "a positive number of bytes",
+                ))
+            }
+        };
+    }
+
+    if let Some(value) = env_var("COMPRESSTIMATE_MARGIN")? {
+        match value.trim().parse::<f32>() {
+            Ok(margin) if margin > 0.0 && margin < 1.0 => estimator.set_error_margin(margin),
+            _ => {
+                return Err(invalid(
+                    "COMPRESSTIMATE_MARGIN",
+                    &value,
+                    "a number between 0 and 1, exclusive",
+                ))
+            }
+        };
+    }
+
+    if let Some(value) = env_var("COMPRESSTIMATE_CONFIDENCE")? {
+        match parse_confidence(&value) {
+            Some(confidence) => estimator.set_confidence(confidence),
+            None => {
+                return Err(invalid(
+                    "COMPRESSTIMATE_CONFIDENCE",
+                    &value,
+                    "one of 80, 85, 90, 95, 99",
+                ))
+            }
+        };
+    }
+
+    Ok(estimator)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Format {
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Ndjson,
+}
+
+/// How to print ratios in text output.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Unit {
+    /// Compressed over original, like "0.42"
+    Ratio,
+    /// How many times smaller, like "2.4x"
+    Factor,
+    /// The compressed size as a percentage, like "42% of original"
+    #[default]
+    Percent,
+}
+
+impl Unit {
+    fn default_precision(self) -> usize {
+        match self {
+            Unit::Ratio => 2,
+            Unit::Factor => 1,
+            Unit::Percent => 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    profile: Option<String>,
+    codec: Option<Codec>,
+    format: Format,
+    len: Option<u64>,
+    recursive: bool,
+    excludes: Vec<String>,
+    jobs: usize,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    verbose: bool,
+    human: bool,
+    sample_info: bool,
+    throughput: bool,
+    watch: bool,
+    fail_fast: bool,
+    units: ByteUnits,
+    unit: Unit,
+    precision: Option<usize>,
+    top: Option<usize>,
+    bottom: Option<usize>,
+    group_by_dir: bool,
+    paths: Vec<PathBuf>,
+}
+
+impl Options {
+    fn ranking(&self) -> bool {
+        self.top.is_some() || self.bottom.is_some()
+    }
+
+    /// Whether to only estimate, and summarise the estimates at the end
+    /// rather than printing each file.
+    fn summarising(&self) -> bool {
+        self.ranking() || self.group_by_dir
+    }
+
+    /// Whether a file of `len` bytes is within `--min-size` and `--max-size`.
+    fn wanted(&self, len: u64) -> bool {
+        self.min_size.map_or(true, |min| len >= min) && self.max_size.map_or(true, |max| len <= max)
+    }
+}
+
+fn usage(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Split `--flag=value` into its parts, taking the value from the next
+/// argument for a bare `--flag` if it needs one.
+fn flag_value(
+    flag: &str,
+    inline: Option<&str>,
+    args: &mut impl Iterator<Item = OsString>,
+) -> io::Result<String> {
+    match inline {
+        Some(value) => Ok(value.to_string()),
+        None => args
+            .next()
+            .ok_or_else(|| usage(format!("{}: missing value", flag)))?
+            .into_string()
+            .map_err(|_| usage(format!("{}: not valid UTF-8", flag))),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str, expected: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| usage(format!("{}={:?}: expected {}", flag, value, expected)))
+}
+
+fn parse_args() -> io::Result<Options> {
+    let mut options = Options::default();
+    let mut args = env::args_os().skip(1);
+    let mut only_paths = false;
+    let mut codec = None;
+    let mut level = None;
+
+    while let Some(arg) = args.next() {
+        let flag = match arg.to_str() {
+            Some(flag) if !only_paths && flag.starts_with("--") => flag.to_string(),
+            _ => {
+                options.paths.push(PathBuf::from(arg));
+                continue;
+            }
+        };
+
+        let (flag, inline) = match flag.find('=') {
+            Some(idx) => (&flag[..idx], Some(&flag[idx + 1..])),
+            None => (&flag[..], None),
+        };
+
+        match flag {
+            "--" => only_paths = true,
+            "--len" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.len = Some(parse_number(flag, &value, "a number of bytes")?);
+            }
+            "--recursive" => options.recursive = true,
+            "--exclude" => options.excludes.push(flag_value(flag, inline, &mut args)?),
+            "--jobs" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.jobs = parse_number(flag, &value, "a number of threads")?;
+            }
+            "--verbose" => options.verbose = true,
+            "--human" => options.human = true,
+            "--sample-info" => options.sample_info = true,
+            "--throughput" => options.throughput = true,
+            "--watch" => options.watch = true,
+            "--fail-fast" => options.fail_fast = true,
+            "--format" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.format = match value.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    "ndjson" => Format::Ndjson,
+                    _ => {
+                        return Err(usage(format!(
+                            "--format={:?}: expected text, json, csv or ndjson",
+                            value
+                        )))
+                    }
+                };
+            }
+            "--si" => options.units = ByteUnits::Decimal,
+            "--unit" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.unit = match value.as_str() {
+                    "ratio" => Unit::Ratio,
+                    "factor" => Unit::Factor,
+                    "percent" => Unit::Percent,
+                    _ => {
+                        return Err(usage(format!(
+                            "--unit={:?}: expected ratio, factor or percent",
+                            value
+                        )))
+                    }
+                };
+            }
+            "--precision" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.precision = Some(parse_number(flag, &value, "a number of decimal places")?);
+            }
+            "--min-size" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.min_size = Some(parse_number(flag, &value, "a number of bytes")?);
+            }
+            "--max-size" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.max_size = Some(parse_number(flag, &value, "a number of bytes")?);
+            }
+            "--profile" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                match value.as_str() {
+                    "quick" | "thorough" => options.profile = Some(value),
+                    _ => {
+                        return Err(usage(format!(
+                            "--profile={:?}: expected quick or thorough",
+                            value
+                        )))
+                    }
+                }
+            }
+            "--codec" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                match value.as_str() {
+                    "lz4" | "zstd" | "gzip" => codec = Some(value),
+                    _ => {
+                        return Err(usage(format!(
+                            "--codec={:?}: expected lz4, zstd or gzip",
+                            value
+                        )))
+                    }
+                }
+            }
+            "--level" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                level = Some(parse_number::<u32>(flag, &value, "a compression level")?);
+            }
+            "--group-by-dir" => options.group_by_dir = true,
+            "--top" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.top = Some(parse_number(flag, &value, "a number of files")?);
+            }
+            "--bottom" => {
+                let value = flag_value(flag, inline, &mut args)?;
+                options.bottom = Some(parse_number(flag, &value, "a number of files")?);
+            }
+            _ => return Err(usage(format!("{}: unknown option", flag))),
+        }
+    }
+
+    // A level alone applies to the default codec, lz4
+    if codec.is_some() || level.is_some() {
+        let name = codec.as_deref().unwrap_or("lz4");
+        let param = match level {
+            Some(level) => format!("{}:level={}", name, level),
+            None => name.to_string(),
+        };
+        options.codec = Some(Codec::from_param_str(&param).map_err(|e| usage(e.to_string()))?);
+    }
+
+    if options.summarising() && options.format != Format::Text {
+        return Err(usage(
+            "--top, --bottom and --group-by-dir only support --format=text".to_string(),
+        ));
+    }
+
+    if options.sample_info && options.format != Format::Text {
+        return Err(usage(
+            "--sample-info only supports --format=text".to_string(),
+        ));
+    }
+
+    if options.throughput && (options.format != Format::Text || options.summarising()) {
+        return Err(usage(
+            "--throughput only supports --format=text, without --top, --bottom or --group-by-dir"
+                .to_string(),
+        ));
+    }
+
+    if options.watch && (options.format != Format::Text || options.summarising()) {
+        return Err(usage(
+            "--watch only supports --format=text, without --top, --bottom or --group-by-dir"
+                .to_string(),
+        ));
+    }
+
+    Ok(options)
+}
+
+fn describe(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => "not found".to_string(),
+        io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+        _ => err.to_string(),
+    }
+}
+
+/// Whether `path` matches the glob `pattern`, where `*` matches within a
+/// path component, `**` across them, and `?` any one character but `/`.
+/// `**/` also matches no directories at all.
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            rest.strip_prefix(b"/")
+                .is_some_and(|after| glob_match(after, path))
+                || (0..=path.len()).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let component = path.iter().position(|&b| b == b'/').unwrap_or(path.len());
+            (0..=component).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [c, tail @ ..] if *c != b'/' => glob_match(rest, tail),
+            _ => false,
+        },
+        [c, rest @ ..] => path.first() == Some(c) && glob_match(rest, &path[1..]),
+    }
+}
+
+/// Whether anything matching one of `excludes` covers `relative`, a path
+/// from the walk root, which is a directory if `dir`.  Patterns without a
+/// `/` match the last component at any depth, and others the whole relative
+/// path.  A directory also matches anything a file directly inside it
+/// could, other than by name, so `node_modules/**` prunes the directory.
+fn excluded(excludes: &[String], relative: &Path, dir: bool) -> bool {
+    let path = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let name = path.rsplit('/').next().unwrap_or(&path);
+
+    excludes.iter().any(|pattern| {
+        let pattern = pattern.as_bytes();
+        if !pattern.contains(&b'/') {
+            glob_match(pattern, name.as_bytes())
+        } else {
+            glob_match(pattern, path.as_bytes())
+                || (dir && glob_match(pattern, format!("{}/", path).as_bytes()))
+        }
+    })
+}
+
+/// Expand the given paths into a list of files, descending into directories
+/// if asked to, and leaving out anything matching `excludes` found on the
+/// way.  Directory entries are sorted so output is stable, and symlinks to
+/// directories aren't followed to avoid loops.
+fn collect_files(
+    paths: &[PathBuf],
+    recursive: bool,
+    excludes: &[String],
+) -> Vec<io::Result<PathBuf>> {
+    fn walk(root: &Path, dir: &Path, excludes: &[String], files: &mut Vec<io::Result<PathBuf>>) {
+        let mut entries = match fs::read_dir(dir).and_then(|rd| rd.collect::<io::Result<Vec<_>>>())
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                files.push(Err(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", dir.display(), describe(&e)),
+                )));
+                return;
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let file_type = entry.file_type();
+            let is_dir = matches!(&file_type, Ok(ft) if ft.is_dir());
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if excluded(excludes, relative, is_dir) {
+                continue;
+            }
+
+            match file_type {
+                Ok(ft) if ft.is_dir() => walk(root, &path, excludes, files),
+                Ok(ft) if ft.is_symlink() && path.is_dir() => (),
+                _ => files.push(Ok(path)),
+            }
+        }
+    }
+
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            files.push(Ok(path.clone()));
+        } else if recursive {
+            walk(path, path, excludes, &mut files);
+        }
+    }
+    files
+}
+
+fn estimate_only(
+    estimator: &Compresstimator,
+    options: &Options,
+    path: &Path,
+) -> io::Result<Estimate> {
+    match options.len {
+        Some(len) => estimator.compresstimate_len_detailed(File::open(path)?, len),
+        None => estimator.compresstimate_file_detailed(path),
+    }
+}
+
+/// A ratio in the `--unit` and `--precision` asked for.
+fn describe_ratio(options: &Options, ratio: f32) -> String {
+    let precision = match options.precision {
+        None if options.unit == Unit::Percent => {
+            return format_ratio(ratio, RatioStyle::OfOriginal)
+        }
+        None => options.unit.default_precision(),
+        Some(precision) => precision,
+    };
+
+    match options.unit {
+        Unit::Ratio => format!("{:.*}", precision, ratio),
+        Unit::Factor => format!("{:.*}x", precision, 1.0 / ratio),
+        Unit::Percent => format!("{:.*}% of original", precision, ratio * 100.0),
+    }
+}
+
+/// The ratio, followed by the sizes if `--human` was given.
+fn describe_estimate(options: &Options, estimate: &Estimate) -> String {
+    let ratio = describe_ratio(options, estimate.ratio());
+    if options.human {
+        format!("{} ({})", ratio, estimate.human_sizes(options.units))
+    } else {
+        ratio
+    }
+}
+
+/// How a file was read, for `--sample-info`.
+fn describe_sampling(sampling: &Sampling) -> String {
+    if sampling.full_read {
+        format!(
+            "read in full, {} blocks of {} bytes",
+            sampling.blocks, sampling.block_size
+        )
+    } else {
+        format!(
+            "sampled {} blocks of {} bytes, every {} bytes",
+            sampling.blocks, sampling.block_size, sampling.stride
+        )
+    }
+}
+
+/// How much estimation got through in how long, for `--throughput`.
+#[derive(Debug, Default, Clone, Copy)]
+struct Throughput {
+    original: u64,
+    sampled: u64,
+    time: Duration,
+}
+
+impl Throughput {
+    fn of(m: &Measured) -> Self {
+        Self {
+            original: m.estimate.total_original,
+            sampled: m.estimate.sampled_original,
+            time: m.estimate_time,
+        }
+    }
+
+    fn add(&mut self, other: Self) {
+        self.original += other.original;
+        self.sampled += other.sampled;
+        self.time += other.time;
+    }
+
+    /// Decimal megabytes of original data estimated per second, followed by
+    /// how fast the sampled part of it was read and compressed.
+    fn describe(&self) -> String {
+        let secs = self.time.as_secs_f64().max(f64::MIN_POSITIVE);
+        format!(
+            "{:.1} MB/s ({:.1} MB/s sampled)",
+            self.original as f64 / secs / 1e6,
+            self.sampled as f64 / secs / 1e6
+        )
+    }
+}
+
+/// An estimate compared with actually compressing the file.
+struct Measured {
+    estimate: Estimate,
+    sampling: Sampling,
+    estimate_time: Duration,
+    actual: f32,
+    actual_time: Duration,
+}
+
+/// What became of one file.  Errors from walking directories have no path of
+/// their own, it's in the message.
+struct Outcome {
+    path: Option<PathBuf>,
+    result: Result<Measured, String>,
+}
 
-    for path in std::env::args_os()
-        .skip(1)
-        .map(std::path::PathBuf::from)
-        .filter(|p| p.is_file())
-    {
-        println!("Path: {}", path.display());
+fn measure(estimator: &Compresstimator, options: &Options, path: &Path) -> io::Result<Measured> {
+    let start = Instant::now();
+    let estimate = estimate_only(estimator, options, path)?;
+    let estimate_time = start.elapsed();
 
-        let start = Instant::now();
-        let est = estimator.compresstimate_file(&path)?;
-        let est_time = start.elapsed();
+    let start = Instant::now();
+    let actual = File::open(path).and_then(|file| match options.len {
+        Some(len) => estimator.base_truth(file.take(len)),
+        None => estimator.base_truth(file),
+    })?;
 
-        println!("  Estimate: {:.2}x, Time: {:.2?}", est, est_time);
+    Ok(Measured {
+        // Nothing's trimmed, so this is the length that was sampled
+        sampling: estimator.sampling(estimate.total_original),
+        estimate,
+        estimate_time,
+        actual,
+        actual_time: start.elapsed(),
+    })
+}
+
+fn format_text(options: &Options, outcome: &Outcome) -> String {
+    match (outcome.path.as_deref(), &outcome.result) {
+        (None, Err(e)) => format!("Error: {}", e),
+        (Some(path), Err(e)) => format!("Path: {}\n  Error: {}", path.display(), e),
+        (path, Ok(m)) => format!(
+            "Path: {}\n  Estimate: {}, Time: {:.2?}\n    Actual: {}, Time: {:.2?}{}{}{}",
+            path.expect("measured files have paths").display(),
+            describe_estimate(options, &m.estimate),
+            m.estimate_time,
+            describe_ratio(options, m.actual),
+            m.actual_time,
+            if options.sample_info {
+                format!("\n  Sampling: {}", describe_sampling(&m.sampling))
+            } else {
+                String::new()
+            },
+            if options.throughput {
+                format!("\n  Throughput: {}", Throughput::of(m).describe())
+            } else {
+                String::new()
+            },
+            if m.estimate.likely_incompressible() {
+                "\n  Warning: effectively incompressible - possibly already compressed or encrypted"
+            } else {
+                ""
+            }
+        ),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The version of the JSON and CSV fields, to be bumped whenever they change
+/// so consumers can refuse output they don't understand.
+const SCHEMA_VERSION: u32 = 1;
+
+/// A JSON object on one line, as used by both `json` and `ndjson`.
+fn format_json(outcome: &Outcome) -> String {
+    let path = match &outcome.path {
+        Some(path) => json_string(&path.to_string_lossy()),
+        None => "null".to_string(),
+    };
+
+    match &outcome.result {
+        Err(e) => format!(
+            "{{\"schema_version\":{},\"path\":{},\"error\":{}}}",
+            SCHEMA_VERSION,
+            path,
+            json_string(e)
+        ),
+        Ok(m) => format!(
+            "{{\"schema_version\":{},\"path\":{},\"estimate\":{},\"sampled_original\":{},\
+             \"sampled_compressed\":{},\"total_original\":{},\"extrapolated_compressed\":{},\
+             \"estimate_secs\":{},\"actual\":{},\"actual_secs\":{}}}",
+            SCHEMA_VERSION,
+            path,
+            m.estimate.ratio(),
+            m.estimate.sampled_original,
+            m.estimate.sampled_compressed,
+            m.estimate.total_original,
+            m.estimate.extrapolated_compressed,
+            m.estimate_time.as_secs_f64(),
+            m.actual,
+            m.actual_time.as_secs_f64()
+        ),
+    }
+}
+
+const CSV_HEADER: &str = "schema_version,path,estimate,sampled_original,sampled_compressed,\
+    total_original,extrapolated_compressed,estimate_secs,actual,actual_secs,error";
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn format_csv(outcome: &Outcome) -> String {
+    let path = outcome
+        .path
+        .as_ref()
+        .map(|p| csv_field(&p.to_string_lossy()))
+        .unwrap_or_default();
+
+    match &outcome.result {
+        Err(e) => format!("{},{},,,,,,,,,{}", SCHEMA_VERSION, path, csv_field(e)),
+        Ok(m) => format!(
+            "{},{},{},{},{},{},{},{},{},{},",
+            SCHEMA_VERSION,
+            path,
+            m.estimate.ratio(),
+            m.estimate.sampled_original,
+            m.estimate.sampled_compressed,
+            m.estimate.total_original,
+            m.estimate.extrapolated_compressed,
+            m.estimate_time.as_secs_f64(),
+            m.actual,
+            m.actual_time.as_secs_f64()
+        ),
+    }
+}
+
+/// Prints outcomes as they arrive in the chosen format, along with whatever
+/// needs to go before and after them.
+struct Report<'a> {
+    options: &'a Options,
+    rows: usize,
+}
+
+impl<'a> Report<'a> {
+    fn new(options: &'a Options) -> Self {
+        match options.format {
+            Format::Json => print!("["),
+            Format::Csv => println!("{}", CSV_HEADER),
+            Format::Text | Format::Ndjson => (),
+        }
+        Self { options, rows: 0 }
+    }
+
+    fn add(&mut self, outcome: &Outcome) {
+        match self.options.format {
+            Format::Text => println!("{}", format_text(self.options, outcome)),
+            Format::Json if self.rows == 0 => print!("\n  {}", format_json(outcome)),
+            Format::Json => print!(",\n  {}", format_json(outcome)),
+            Format::Csv => println!("{}", format_csv(outcome)),
+            Format::Ndjson => println!("{}", format_json(outcome)),
+        }
+        self.rows += 1;
+    }
+
+    /// Informational notes, kept out of the way of machine-readable output.
+    fn note(&self, msg: &str) {
+        match self.options.format {
+            Format::Text => println!("{}", msg),
+            _ => eprintln!("{}", msg),
+        }
+    }
+
+    fn finish(self) {
+        if self.options.format == Format::Json {
+            println!("{}]", if self.rows > 0 { "\n" } else { "" });
+        }
+    }
+}
+
+/// What became of each file, worked out in parallel and handled in order.
+enum Row {
+    Done(Outcome),
+    Skipped(PathBuf, u64),
+    Ranked(PathBuf, Result<Estimate, String>),
+}
+
+fn process(estimator: &Compresstimator, options: &Options, path: io::Result<PathBuf>) -> Row {
+    let path = match path {
+        Ok(path) => path,
+        Err(e) => {
+            return Row::Done(Outcome {
+                path: None,
+                result: Err(e.to_string()),
+            })
+        }
+    };
+
+    // Anything we can't stat is left for estimation to report
+    if let Ok(meta) = fs::metadata(&path) {
+        if !options.wanted(meta.len()) {
+            return Row::Skipped(path, meta.len());
+        }
+    }
+
+    if options.summarising() {
+        let result = estimate_only(estimator, options, &path).map_err(|e| describe(&e));
+        return Row::Ranked(path, result);
+    }
+
+    let result = measure(estimator, options, &path).map_err(|e| describe(&e));
+    Row::Done(Outcome {
+        path: Some(path),
+        result,
+    })
+}
+
+/// The lines of a `--group-by-dir` summary of `estimates`: every directory
+/// holding any of the files, from each of `roots` they were found under, or
+/// just their own directory if none, down to the ones holding them directly.
+/// Each gives its ratio weighted by original size, the total original size,
+/// and how many files it held, at any depth, indented as a tree.
+fn directory_summary(
+    options: &Options,
+    estimates: &[(Estimate, PathBuf)],
+    roots: &[PathBuf],
+) -> Vec<String> {
+    #[derive(Default)]
+    struct Totals {
+        original: u64,
+        compressed: u64,
+        files: u64,
+        depth: usize,
+    }
+
+    let mut dirs: BTreeMap<&Path, Totals> = BTreeMap::new();
+    for (estimate, path) in estimates {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let root = roots.iter().find(|root| parent.starts_with(root));
+        let ancestors = parent
+            .ancestors()
+            .take_while(|dir| root.is_some_and(|root| dir.starts_with(root)));
+        let ancestors: Vec<&Path> = match root {
+            Some(_) => ancestors.collect(),
+            None => vec![parent],
+        };
+
+        let top = ancestors.len();
+        for (i, dir) in ancestors.into_iter().enumerate() {
+            let totals = dirs.entry(dir).or_default();
+            totals.original += estimate.total_original;
+            totals.compressed += estimate.extrapolated_compressed;
+            totals.files += 1;
+            totals.depth = top - 1 - i;
+        }
+    }
+
+    dirs.into_iter()
+        .map(|(dir, totals)| {
+            let name = match totals.depth {
+                0 => dir.display().to_string(),
+                _ => dir
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let ratio = match totals.original {
+                0 => 1.0,
+                original => totals.compressed as f32 / original as f32,
+            };
+            format!(
+                "  {:indent$}{}/  {}, {} in {} file{}",
+                "",
+                name,
+                describe_ratio(options, ratio),
+                format_bytes(totals.original, options.units),
+                totals.files,
+                if totals.files == 1 { "" } else { "s" },
+                indent = totals.depth * 2
+            )
+        })
+        .collect()
+}
+
+/// Run `work` over `items` on up to `jobs` threads, passing each result to
+/// `emit` in the original order of `items` however they complete, so output
+/// is the same from run to run.  Stops early if `emit` returns false.
+fn run_ordered<T, R, W, E>(items: Vec<T>, jobs: usize, work: W, mut emit: E)
+where
+    T: Send,
+    R: Send,
+    W: Fn(T) -> R + Sync,
+    E: FnMut(R) -> bool,
+{
+    if jobs <= 1 {
+        for item in items {
+            if !emit(work(item)) {
+                break;
+            }
+        }
+        return;
+    }
 
-        let start = Instant::now();
-        let act = std::fs::File::open(&path).and_then(|file| estimator.base_truth(file))?;
-        let act_time = start.elapsed();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
 
-        println!("    Actual: {:.2}x, Time: {:.2?}", act, act_time);
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let (queue, stop, work, tx) = (&queue, &stop, &work, tx.clone());
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let Some((index, item)) = queue.lock().expect("queue").next() else {
+                        break;
+                    };
+                    if tx.send((index, work(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // Results which arrived before their turn
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+
+        for (index, result) in rx {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&next) {
+                next += 1;
+                if !emit(result) {
+                    stop.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// How often `--watch` looks for changes, unless walking the paths given
+/// takes long enough that it should wait longer.
+const WATCH_POLL: Duration = Duration::from_millis(250);
+
+/// How many times longer than a walk of the paths `--watch` waits before the
+/// next, so big trees aren't walked end to end.
+const WATCH_IDLE_FACTOR: u32 = 4;
+
+/// How long a file must go unchanged before `--watch` estimates it again, so
+/// a burst of writes gives one estimate rather than a flood of them.
+const WATCH_SETTLE: Duration = Duration::from_millis(500);
+
+/// What `--watch` compares to spot a change: the length and modification
+/// time, or nothing if the file can't be statted.
+fn stamp(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    fs::metadata(path)
+        .ok()
+        .map(|meta| (meta.len(), meta.modified().ok()))
+}
+
+/// Poll the given paths forever, estimating each file again once it's
+/// changed and settled.  Files turning up in watched directories count as
+/// changed, and ones disappearing, alone or with their directory, report
+/// their error once.
+fn watch(estimator: &Compresstimator, options: &Options) -> ! {
+    let files = || collect_files(&options.paths, options.recursive, &options.excludes).into_iter();
+    let mut seen: BTreeMap<PathBuf, _> = files()
+        .flatten()
+        .map(|path| {
+            let stamp = stamp(&path);
+            (path, stamp)
+        })
+        .collect();
+    // Changed files, and when they last changed
+    let mut pending: BTreeMap<PathBuf, Instant> = BTreeMap::new();
+    let mut poll = WATCH_POLL;
+
+    loop {
+        thread::sleep(poll);
+
+        let walked = Instant::now();
+        let mut found = BTreeSet::new();
+        for path in files().flatten() {
+            let now = stamp(&path);
+            if seen.get(&path) != Some(&now) {
+                seen.insert(path.clone(), now);
+                pending.insert(path.clone(), Instant::now());
+            }
+            found.insert(path);
+        }
+        poll = WATCH_POLL.max(walked.elapsed() * WATCH_IDLE_FACTOR);
+
+        // Forget what's gone, once it's been reported
+        let gone: Vec<PathBuf> = seen
+            .keys()
+            .filter(|path| !found.contains(*path))
+            .cloned()
+            .collect();
+        for path in gone {
+            seen.remove(&path);
+            pending.insert(path, Instant::now());
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= WATCH_SETTLE)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            pending.remove(&path);
+            if let Some(seen) = seen.get_mut(&path) {
+                *seen = stamp(&path);
+            }
+            match process(estimator, options, Ok(path)) {
+                Row::Done(outcome) => println!("{}", format_text(options, &outcome)),
+                Row::Skipped(path, len) if options.verbose => {
+                    println!("Skipped: {} ({} bytes)", path.display(), len)
+                }
+                Row::Skipped(..) | Row::Ranked(..) => (),
+            }
+        }
+    }
+}
+
+fn main() {
+    let (estimator, options) = parse_args()
+        .and_then(|options| {
+            let profile = match options.profile.as_deref() {
+                Some("quick") => Compresstimator::quick(),
+                Some("thorough") => Compresstimator::thorough(),
+                _ => Compresstimator::default(),
+            };
+            let mut estimator = estimator_from_env(profile)?;
+            if let Some(codec) = &options.codec {
+                estimator.codec(codec.clone());
+            }
+            Ok((estimator, options))
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(64);
+        });
+
+    let mut attempted = 0;
+    let mut failed = 0;
+    let mut ranked = vec![];
+    let mut throughput = Throughput::default();
+    let mut report = Report::new(&options);
+
+    let files = collect_files(&options.paths, options.recursive, &options.excludes);
+    let work = |path| process(&estimator, &options, path);
+
+    run_ordered(files, options.jobs, work, |row| {
+        match row {
+            Row::Skipped(path, len) => {
+                if options.verbose {
+                    report.note(&format!("Skipped: {} ({} bytes)", path.display(), len));
+                }
+                return true;
+            }
+            Row::Ranked(path, Ok(estimate)) => ranked.push((estimate, path)),
+            Row::Ranked(path, Err(e)) => {
+                failed += 1;
+                println!("Error: {}: {}", path.display(), e);
+            }
+            Row::Done(outcome) => {
+                match &outcome.result {
+                    Ok(m) => throughput.add(Throughput::of(m)),
+                    Err(_) => failed += 1,
+                }
+                report.add(&outcome);
+            }
+        }
+
+        attempted += 1;
+        !(options.fail_fast && failed > 0)
+    });
+    report.finish();
+
+    if options.throughput && attempted > failed {
+        // Summed over files, so this is per thread with --jobs
+        println!(
+            "Throughput: {} over {} files",
+            throughput.describe(),
+            attempted - failed
+        );
     }
 
-    Ok(())
+    // A partial ranking would be misleading
+    if options.ranking() && !(options.fail_fast && failed > 0) {
+        // Least compressible first
+        ranked.sort_by(|a, b| {
+            b.0.ratio()
+                .total_cmp(&a.0.ratio())
+                .then_with(|| a.1.cmp(&b.1))
+        });
+
+        if let Some(n) = options.top {
+            println!("Least compressible:");
+            for (estimate, path) in ranked.iter().take(n) {
+                println!(
+                    "  {:>16}  {}",
+                    describe_estimate(&options, estimate),
+                    path.display()
+                );
+            }
+        }
+
+        if let Some(n) = options.bottom {
+            println!("Most compressible:");
+            for (estimate, path) in ranked.iter().rev().take(n) {
+                println!(
+                    "  {:>16}  {}",
+                    describe_estimate(&options, estimate),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    if options.group_by_dir && !(options.fail_fast && failed > 0) {
+        println!("By directory:");
+        for line in directory_summary(&options, &ranked, &options.paths) {
+            println!("{}", line);
+        }
+    }
+
+    if options.watch {
+        watch(&estimator, &options);
+    }
+
+    // 1 if some files failed, 2 if they all did
+    if failed > 0 {
+        std::process::exit(if failed == attempted { 2 } else { 1 });
+    }
+}
+
+#[test]
+fn ordered_output() {
+    let render = |jobs| {
+        let mut out = String::new();
+        run_ordered(
+            (0..200u64).collect(),
+            jobs,
+            |n| {
+                // Finish out of order
+                thread::sleep(Duration::from_micros((n * 7919) % 500));
+                n
+            },
+            |n| {
+                out.push_str(&format!("{}\n", n));
+                true
+            },
+        );
+        out
+    };
+
+    let sequential = render(1);
+    assert_eq!(render(8), sequential);
+    assert_eq!(render(8), sequential);
+
+    // And the real thing, minus the timings
+    let options = Options {
+        recursive: true,
+        top: Some(0),
+        ..Options::default()
+    };
+    let estimator = Compresstimator::default();
+    let render = |jobs| {
+        let mut out = String::new();
+        let files = collect_files(&[PathBuf::from("src"), PathBuf::from("nope")], true, &[]);
+        run_ordered(
+            files,
+            jobs,
+            |path| process(&estimator, &options, path),
+            |row| {
+                match row {
+                    Row::Ranked(path, result) => {
+                        out.push_str(&format!("{} {:?}\n", path.display(), result))
+                    }
+                    Row::Done(outcome) => out.push_str(&format!("{:?}\n", outcome.result.err())),
+                    Row::Skipped(..) => (),
+                }
+                true
+            },
+        );
+        out
+    };
+
+    let first = render(4);
+    assert!(first.contains("lib.rs"));
+    assert_eq!(render(4), first);
+    assert_eq!(render(1), first);
+}
+
+#[test]
+fn ratio_units() {
+    let mut options = Options::default();
+    assert_eq!(describe_ratio(&options, 0.4242), "42% of original");
+
+    options.precision = Some(2);
+    assert_eq!(describe_ratio(&options, 0.4242), "42.42% of original");
+
+    options.unit = Unit::Ratio;
+    options.precision = None;
+    assert_eq!(describe_ratio(&options, 0.9849), "0.98");
+    options.precision = Some(3);
+    assert_eq!(describe_ratio(&options, 0.9849), "0.985");
+
+    options.unit = Unit::Factor;
+    options.precision = None;
+    assert_eq!(describe_ratio(&options, 0.25), "4.0x");
+    options.precision = Some(0);
+    assert_eq!(describe_ratio(&options, 0.25), "4x");
+}
+
+#[test]
+fn excludes() {
+    assert!(glob_match(b"*.jpg", b"cat.jpg"));
+    assert!(!glob_match(b"*.jpg", b"photos/cat.jpg"));
+    assert!(glob_match(b"photos/*.jp?", b"photos/cat.jpg"));
+    assert!(glob_match(b"**/*.jpg", b"cat.jpg"));
+    assert!(glob_match(b"**/*.jpg", b"a/b/cat.jpg"));
+    assert!(glob_match(b"node_modules/**", b"node_modules/x/y"));
+    assert!(!glob_match(b"node_modules/**", b"src/node_modules/x"));
+
+    let excludes = ["*.jpg".to_string(), "node_modules/**".to_string()];
+    assert!(excluded(&excludes, Path::new("photos/cat.jpg"), false));
+    assert!(excluded(&excludes, Path::new("node_modules"), true));
+    assert!(!excluded(&excludes, Path::new("node_modules"), false));
+    assert!(!excluded(&excludes, Path::new("src/lib.rs"), false));
+
+    let files = collect_files(&[PathBuf::from("src")], true, &["*.rs".to_string()]);
+    assert!(files.is_empty());
+    let files = collect_files(&[PathBuf::from(".")], true, &["target/**".to_string()]);
+    let files: Vec<PathBuf> = files.into_iter().flatten().collect();
+    assert!(files.iter().any(|path| path.ends_with("src/lib.rs")));
+    assert!(!files.iter().any(|path| path.starts_with("./target")));
+}
+
+#[test]
+fn directory_summaries() {
+    let estimate = |total_original, extrapolated_compressed| Estimate {
+        sampled_original: total_original,
+        sampled_compressed: extrapolated_compressed,
+        total_original,
+        extrapolated_compressed,
+        estimated_allocated_len: extrapolated_compressed,
+    };
+    let estimates = [
+        (estimate(1000, 100), PathBuf::from("data/logs/a.log")),
+        (estimate(3000, 3000), PathBuf::from("data/media/b.jpg")),
+        (estimate(500, 250), PathBuf::from("data/c.txt")),
+        (estimate(100, 50), PathBuf::from("elsewhere/d.txt")),
+    ];
+    let options = Options {
+        unit: Unit::Ratio,
+        ..Options::default()
+    };
+
+    let lines = directory_summary(&options, &estimates, &[PathBuf::from("data")]);
+    assert_eq!(
+        lines,
+        [
+            "  data/  0.74, 4.4 KiB in 3 files",
+            "    logs/  0.10, 1000 B in 1 file",
+            "    media/  1.00, 2.9 KiB in 1 file",
+            "  elsewhere/  0.50, 100 B in 1 file",
+        ]
+    );
 }
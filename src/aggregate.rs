@@ -0,0 +1,65 @@
+//! Combining per-file estimates into one corpus-wide ratio
+
+use crate::Estimate;
+
+/// How `aggregate_ratio` weighs each estimate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Weighting {
+    /// By original size: overall compressed over overall original, answering
+    /// how much the whole corpus would save
+    #[default]
+    ByOriginal,
+    /// By extrapolated compressed size, so files taking the most space once
+    /// stored count the most
+    ByCompressed,
+    /// Every estimate counts equally, however large
+    Unweighted,
+}
+
+/// Combine the ratios of `estimates` into one, weighted as given.
+///
+/// Returns 1.0, as for an empty input, if there's nothing to weigh.
+pub fn aggregate_ratio(estimates: &[Estimate], weighting: Weighting) -> f32 {
+    let (sum, weights) = estimates
+        .iter()
+        .map(|e| {
+            let weight = match weighting {
+                Weighting::ByOriginal => e.total_original as f64,
+                Weighting::ByCompressed => e.extrapolated_compressed as f64,
+                Weighting::Unweighted => 1.0,
+            };
+            (f64::from(e.ratio()) * weight, weight)
+        })
+        .fold((0.0, 0.0), |(s, w), (es, ew)| (s + es, w + ew));
+
+    if weights == 0.0 {
+        1.0
+    } else {
+        ((sum / weights) as f32).min(1.0)
+    }
+}
+
+#[test]
+fn weightings() {
+    let estimate = |total_original, ratio: f64| Estimate {
+        sampled_original: 100,
+        sampled_compressed: (100.0 * ratio) as u64,
+        total_original,
+        extrapolated_compressed: (total_original as f64 * ratio) as u64,
+        estimated_allocated_len: (total_original as f64 * ratio) as u64,
+    };
+    // A big compressible file and a small incompressible one
+    let estimates = [estimate(900, 0.1), estimate(100, 1.0)];
+
+    let by_original = aggregate_ratio(&estimates, Weighting::ByOriginal);
+    assert!((by_original - 0.19).abs() < 1e-6);
+
+    // 90 bytes stored at 0.1, 100 at 1.0
+    let by_compressed = aggregate_ratio(&estimates, Weighting::ByCompressed);
+    assert!((by_compressed - 109.0 / 190.0).abs() < 1e-6);
+
+    let unweighted = aggregate_ratio(&estimates, Weighting::Unweighted);
+    assert!((unweighted - 0.55).abs() < 1e-6);
+
+    assert_eq!(aggregate_ratio(&[], Weighting::Unweighted), 1.0);
+}
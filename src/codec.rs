@@ -0,0 +1,517 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::CompresstimatorError;
+
+/// The highest lz4 level, beyond which it gains nothing
+pub(crate) const LZ4_MAX_LEVEL: u32 = 16;
+
+/// The highest zstd level, and the highest it allows without `--ultra`
+const ZSTD_MAX_LEVEL: u32 = 22;
+const ZSTD_MAX_NORMAL_LEVEL: u32 = 19;
+
+/// The highest gzip level
+const GZIP_MAX_LEVEL: u32 = 9;
+
+/// The log2 of lz4's fixed window
+const LZ4_WINDOW_LOG: u32 = 16;
+
+/// zstd's level when it isn't given, its window with `--long` and no size,
+/// and the smallest window it allows
+const ZSTD_DEFAULT_LEVEL: u32 = 3;
+const ZSTD_LONG_WINDOW_LOG: u32 = 27;
+const ZSTD_MIN_WINDOW_LOG: u32 = 10;
+
+/// The log2 of zstd's window at each level from 1, for inputs large enough
+/// to use all of it
+const ZSTD_LEVEL_WINDOW_LOGS: [u32; ZSTD_MAX_LEVEL as usize] = [
+    19, 20, 21, 21, 21, 21, 21, 22, 22, 22, 22, 22, 22, 22, 22, 22, 23, 23, 23, 25, 26, 27,
+];
+
+/// The compressor used to estimate compressibility.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// lz4 at the given level.  0 to 2 select the fast mode, 3 and up the high
+    /// compression mode, up to a maximum of 16.
+    Lz4 {
+        /// Compression level
+        level: u32,
+    },
+    /// An external compressor program - see `ExternalCommandCodec`.
+    External(ExternalCommandCodec),
+}
+
+impl Default for Codec {
+    /// lz4 level 1.
+    fn default() -> Self {
+        Codec::Lz4 { level: 1 }
+    }
+}
+
+impl Codec {
+    /// Check the codec could be used: that an lz4 level is in range, and an
+    /// external command names a program.  Whether that program exists isn't
+    /// known until it's run.
+    pub(crate) fn validate(&self) -> Result<(), CompresstimatorError> {
+        let problem = match self {
+            Codec::Lz4 { level } if *level > LZ4_MAX_LEVEL => {
+                format!("level must be at most {}", LZ4_MAX_LEVEL)
+            }
+            Codec::External(cmd) if cmd.program.is_empty() => "no program given".to_string(),
+            _ => return Ok(()),
+        };
+
+        Err(CompresstimatorError::Codec {
+            codec: self.to_string(),
+            message: problem,
+        })
+    }
+
+    /// Parse a codec from a parameter string like `lz4:level=9` or
+    /// `zstd:level=19,long=27`, as a storage system's configuration might
+    /// carry.
+    ///
+    /// The grammar is a codec name, optionally followed by a colon and
+    /// comma-separated `key=value` parameters, each given at most once:
+    ///
+    /// * `lz4` - `level`, from 0 to 16, defaulting to 1.  This is the form
+    ///   `Display` gives, so lz4 codecs round trip.
+    /// * `zstd` - run as an external `zstd -c`, taking `level` from 1 to 22,
+    ///   `long` for long-distance matching with a window of 2^`long` bytes,
+    ///   `window` to set the window log alone, and `dict`, a path to a
+    ///   dictionary file.
+    /// * `gzip` - run as an external `gzip -c`, taking `level` from 1 to 9.
+    ///
+    /// Unknown codecs and keys, repeats, and values out of range are all
+    /// `CompresstimatorError::Codec`.  lz4 takes no dictionary here, since
+    /// lz4 dictionaries belong to the estimator - use
+    /// `Compresstimator::dictionary`.
+    pub fn from_param_str(s: &str) -> Result<Codec, CompresstimatorError> {
+        let error = |message: String| CompresstimatorError::Codec {
+            codec: s.to_string(),
+            message,
+        };
+
+        let (name, params) = match s.trim().split_once(':') {
+            Some((name, params)) => (name, params),
+            None => (s.trim(), ""),
+        };
+
+        let mut seen = vec![];
+        let mut pairs = vec![];
+        for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| error(format!("{:?}: expected key=value", param)))?;
+            let key = key.trim();
+            if seen.contains(&key) {
+                return Err(error(format!("{} given more than once", key)));
+            }
+            seen.push(key);
+            pairs.push((key, value.trim()));
+        }
+
+        let number = |key: &str, value: &str, min: u32, max: u32| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| error(format!("{}={:?}: expected {} to {}", key, value, min, max)))
+        };
+
+        match name {
+            "lz4" => {
+                let mut level = 1;
+                for (key, value) in pairs {
+                    match key {
+                        "level" => level = number(key, value, 0, LZ4_MAX_LEVEL)?,
+                        "dict" => {
+                            return Err(error(
+                                "lz4 dictionaries are set with Compresstimator::dictionary"
+                                    .to_string(),
+                            ))
+                        }
+                        _ => return Err(error(format!("unknown lz4 parameter {}", key))),
+                    }
+                }
+                Ok(Codec::Lz4 { level })
+            }
+            "zstd" => {
+                let mut args: Vec<OsString> = vec![];
+                for (key, value) in pairs {
+                    match key {
+                        "level" => {
+                            let level = number(key, value, 1, ZSTD_MAX_LEVEL)?;
+                            if level > ZSTD_MAX_NORMAL_LEVEL {
+                                args.push("--ultra".into());
+                            }
+                            args.push(format!("-{}", level).into());
+                        }
+                        "long" => {
+                            args.push(format!("--long={}", number(key, value, 10, 31)?).into())
+                        }
+                        "window" => {
+                            args.push(format!("--zstd=wlog={}", number(key, value, 10, 31)?).into())
+                        }
+                        "dict" if value.is_empty() => {
+                            return Err(error("dict: expected a path".to_string()))
+                        }
+                        "dict" => args.extend(["-D".into(), value.into()]),
+                        _ => return Err(error(format!("unknown zstd parameter {}", key))),
+                    }
+                }
+                args.push("-c".into());
+                Ok(Codec::External(ExternalCommandCodec::new("zstd", &args)))
+            }
+            "gzip" => {
+                let mut args: Vec<OsString> = vec![];
+                for (key, value) in pairs {
+                    match key {
+                        "level" => {
+                            let level = number(key, value, 1, GZIP_MAX_LEVEL)?;
+                            args.push(format!("-{}", level).into());
+                        }
+                        _ => return Err(error(format!("unknown gzip parameter {}", key))),
+                    }
+                }
+                args.push("-c".into());
+                Ok(Codec::External(ExternalCommandCodec::new("gzip", &args)))
+            }
+            _ => Err(error(format!("unknown codec {:?}", name))),
+        }
+    }
+
+    /// The log2 of the window this codec compresses with, which bounds the
+    /// memory it needs: 16 for lz4, and for `zstd` commands, whatever window
+    /// their arguments ask for or their level implies.  `None` for any other
+    /// external command, whose options aren't understood.
+    pub fn window_log(&self) -> Option<u32> {
+        match self {
+            Codec::Lz4 { .. } => Some(LZ4_WINDOW_LOG),
+            Codec::External(cmd) => cmd.zstd_window_log(),
+        }
+    }
+
+    /// This codec with its window cut down to at most 2^`max_log` bytes if
+    /// it's larger, as far as that's possible: zstd's `--long` and window log
+    /// options are lowered, or one added if its level implies a larger
+    /// window, though never below zstd's minimum of 2^10.  lz4's window is
+    /// fixed, and other commands' options aren't understood, so they're left
+    /// as they are.
+    pub(crate) fn clamp_window(&self, max_log: u32) -> Codec {
+        match self {
+            Codec::External(cmd) if cmd.zstd_window_log().is_some_and(|log| log > max_log) => {
+                Codec::External(cmd.clamp_zstd_window(max_log.max(ZSTD_MIN_WINDOW_LOG)))
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// This codec configured with the given window, for codecs which support
+    /// one - see `ExternalCommandCodec::with_window`.  lz4's window is fixed.
+    pub(crate) fn with_window(&self, window: u32) -> Result<Codec, CompresstimatorError> {
+        let problem = match self {
+            Codec::Lz4 { .. } => "lz4's window is fixed at 64KiB",
+            Codec::External(cmd) if cmd.args.iter().any(|a| has_window(a)) => {
+                return Ok(Codec::External(cmd.with_window(window)))
+            }
+            Codec::External(_) => "no {window} placeholder in its arguments",
+        };
+
+        Err(CompresstimatorError::Codec {
+            codec: self.to_string(),
+            message: problem.to_string(),
+        })
+    }
+}
+
+const WINDOW_PLACEHOLDER: &str = "{window}";
+
+fn has_window(arg: &OsStr) -> bool {
+    arg.to_str().is_some_and(|a| a.contains(WINDOW_PLACEHOLDER))
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Lz4 { level } => write!(f, "lz4:level={}", level),
+            Codec::External(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+/// Estimate using an external compressor, such as `zstd`, `xz` or `gzip`,
+/// which reads uncompressed data on stdin and writes compressed data to
+/// stdout.
+///
+/// A new process is spawned for each estimate, with the sampled blocks piped
+/// through it and the length of its output counted, so this is only really
+/// worthwhile for larger inputs.  Failure to spawn the program, or it exiting
+/// unsuccessfully, is reported as `CompresstimatorError::Codec`.
+///
+/// Dictionaries aren't passed to external compressors.
+///
+/// For `Compresstimator::compresstimate_window_sweep`, any `{window}` in the
+/// arguments is replaced with each window size in turn, such as
+/// `--long={window}` for zstd's long-distance matching.
+///
+/// The program and arguments are shared, so cloning a codec, or an estimator
+/// holding one, doesn't copy them.
+///
+/// ```no_run
+/// use compresstimator::{Codec, Compresstimator, ExternalCommandCodec};
+///
+/// let mut est = Compresstimator::default();
+/// est.codec(Codec::External(ExternalCommandCodec::new("zstd", &["-9", "-c"])));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExternalCommandCodec {
+    /// The program to run, searched for in `PATH`
+    pub program: Arc<OsStr>,
+    /// Its arguments
+    pub args: Arc<[OsString]>,
+}
+
+impl ExternalCommandCodec {
+    /// Run `program` with `args`.
+    pub fn new<P, A>(program: P, args: &[A]) -> Self
+    where
+        P: AsRef<OsStr>,
+        A: AsRef<OsStr>,
+    {
+        Self {
+            program: program.as_ref().into(),
+            args: args.iter().map(|arg| arg.as_ref().to_os_string()).collect(),
+        }
+    }
+
+    /// This command with every `{window}` in its arguments replaced by `window`.
+    pub fn with_window(&self, window: u32) -> Self {
+        let window = window.to_string();
+        let args: Vec<OsString> = self
+            .args
+            .iter()
+            .map(|arg| match arg.to_str() {
+                Some(a) if has_window(arg) => a.replace(WINDOW_PLACEHOLDER, &window).into(),
+                _ => arg.clone(),
+            })
+            .collect();
+        Self::new(&self.program, &args)
+    }
+}
+
+impl ExternalCommandCodec {
+    fn is_zstd(&self) -> bool {
+        Path::new(&*self.program).file_stem() == Some(OsStr::new("zstd"))
+    }
+
+    /// The window log a `zstd` command would use, from the last of
+    /// `--long`, `--long=N` or `wlog=N` in `--zstd=` given, or else its level.
+    fn zstd_window_log(&self) -> Option<u32> {
+        if !self.is_zstd() {
+            return None;
+        }
+
+        let mut level = ZSTD_DEFAULT_LEVEL;
+        let mut window = None;
+        let mut args = self.args.iter().filter_map(|arg| arg.to_str());
+        while let Some(arg) = args.next() {
+            if arg == "-D" {
+                // A dictionary path, which might look like anything
+                args.next();
+            } else if arg == "--long" {
+                window = Some(ZSTD_LONG_WINDOW_LOG);
+            } else if let Some(log) = arg.strip_prefix("--long=") {
+                window = log.parse().ok().or(window);
+            } else if let Some(params) = arg.strip_prefix("--zstd=") {
+                for param in params.split(',') {
+                    if let Some(log) = param.strip_prefix("wlog=") {
+                        window = log.parse().ok().or(window);
+                    }
+                }
+            } else if let Some(n) = arg.strip_prefix('-').and_then(|n| n.parse::<u32>().ok()) {
+                level = n.clamp(1, ZSTD_MAX_LEVEL);
+            }
+        }
+
+        window.or(Some(ZSTD_LEVEL_WINDOW_LOGS[level as usize - 1]))
+    }
+
+    /// This `zstd` command with any window options above `max_log` lowered to
+    /// it, or if there are none, one setting it.
+    fn clamp_zstd_window(&self, max_log: u32) -> Self {
+        let clamp = |log: &str| match log.parse::<u32>() {
+            Ok(log) => log.min(max_log).to_string(),
+            Err(_) => log.to_string(),
+        };
+
+        let mut explicit = false;
+        let mut dictionary = false;
+        let mut args: Vec<OsString> = self
+            .args
+            .iter()
+            .map(|arg| {
+                let after_dictionary = std::mem::replace(&mut dictionary, arg == "-D");
+                match arg.to_str() {
+                    _ if after_dictionary => arg.clone(),
+                    Some("--long") => {
+                        explicit = true;
+                        format!("--long={}", ZSTD_LONG_WINDOW_LOG.min(max_log)).into()
+                    }
+                    Some(a) if a.starts_with("--long=") => {
+                        explicit = true;
+                        format!("--long={}", clamp(&a["--long=".len()..])).into()
+                    }
+                    Some(a) if a.starts_with("--zstd=") && a.contains("wlog=") => {
+                        explicit = true;
+                        let params: Vec<String> = a["--zstd=".len()..]
+                            .split(',')
+                            .map(|param| match param.strip_prefix("wlog=") {
+                                Some(log) => format!("wlog={}", clamp(log)),
+                                None => param.to_string(),
+                            })
+                            .collect();
+                        format!("--zstd={}", params.join(",")).into()
+                    }
+                    _ => arg.clone(),
+                }
+            })
+            .collect();
+
+        if !explicit {
+            args.insert(0, format!("--zstd=wlog={}", max_log).into());
+        }
+
+        Self::new(&self.program, &args)
+    }
+}
+
+impl fmt::Display for ExternalCommandCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program.to_string_lossy())?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn window_placeholder() {
+    let cmd = ExternalCommandCodec::new("zstd", &["-c", "--long={window}"]);
+    assert_eq!(
+        cmd.with_window(27),
+        ExternalCommandCodec::new("zstd", &["-c", "--long=27"])
+    );
+    // Clones share the command rather than copying it
+    assert!(Arc::ptr_eq(&cmd.clone().args, &cmd.args));
+    assert!(Codec::External(cmd).with_window(27).is_ok());
+
+    let plain = ExternalCommandCodec::new("zstd", &["-c"]);
+    assert!(Codec::External(plain).with_window(27).is_err());
+    assert!(Codec::default().with_window(27).is_err());
+}
+
+#[test]
+fn param_strings() {
+    assert_eq!(Codec::from_param_str("lz4"), Ok(Codec::default()));
+    assert_eq!(
+        Codec::from_param_str("lz4:level=9"),
+        Ok(Codec::Lz4 { level: 9 })
+    );
+    let lz4 = Codec::Lz4 { level: 12 };
+    assert_eq!(Codec::from_param_str(&lz4.to_string()), Ok(lz4));
+
+    assert_eq!(
+        Codec::from_param_str("zstd:level=9, long=27"),
+        Ok(Codec::External(ExternalCommandCodec::new(
+            "zstd",
+            &["-9", "--long=27", "-c"]
+        )))
+    );
+    assert_eq!(
+        Codec::from_param_str("zstd:level=22,window=24,dict=/etc/zstd.dict"),
+        Ok(Codec::External(ExternalCommandCodec::new(
+            "zstd",
+            &[
+                "--ultra",
+                "-22",
+                "--zstd=wlog=24",
+                "-D",
+                "/etc/zstd.dict",
+                "-c"
+            ]
+        )))
+    );
+    assert_eq!(
+        Codec::from_param_str("gzip:level=9"),
+        Ok(Codec::External(ExternalCommandCodec::new(
+            "gzip",
+            &["-9", "-c"]
+        )))
+    );
+
+    for bad in [
+        "brotli",
+        "gzip:level=10",
+        "gzip:long=27",
+        "lz4:level=17",
+        "lz4:speed=1",
+        "lz4:dict=x",
+        "lz4:level",
+        "zstd:level=0",
+        "zstd:level=3,level=4",
+        "zstd:long=abc",
+        "zstd:dict=",
+    ] {
+        assert!(
+            matches!(
+                Codec::from_param_str(bad),
+                Err(CompresstimatorError::Codec { .. })
+            ),
+            "{}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn window_clamping() {
+    let zstd = |params: &str| Codec::from_param_str(params).expect("zstd");
+    assert_eq!(Codec::default().window_log(), Some(16));
+    assert_eq!(Codec::default().clamp_window(10), Codec::default());
+    assert_eq!(zstd("zstd").window_log(), Some(21));
+    assert_eq!(zstd("zstd:level=19").window_log(), Some(23));
+
+    let long = zstd("zstd:level=19,long=30");
+    assert_eq!(long.window_log(), Some(30));
+    let clamped = long.clamp_window(24);
+    assert_eq!(
+        clamped,
+        Codec::External(ExternalCommandCodec::new(
+            "zstd",
+            &["-19", "--long=24", "-c"]
+        ))
+    );
+    assert_eq!(clamped.window_log(), Some(24));
+
+    // A window implied by the level gets one set explicitly
+    let ultra = zstd("zstd:level=22").clamp_window(24);
+    assert_eq!(ultra.window_log(), Some(24));
+    assert_eq!(ultra.to_string(), "zstd --zstd=wlog=24 --ultra -22 -c");
+
+    // Anything already small enough is left alone, as are paths after -D
+    let small = zstd("zstd:level=1,window=18,dict=--long");
+    assert_eq!(small.window_log(), Some(18));
+    assert_eq!(small.clamp_window(20), small);
+    assert_eq!(
+        small.clamp_window(12).to_string(),
+        "zstd -1 --zstd=wlog=12 -D --long -c"
+    );
+
+    let gzip = Codec::External(ExternalCommandCodec::new("gzip", &["-c"]));
+    assert_eq!(gzip.window_log(), None);
+    assert_eq!(gzip.clamp_window(10), gzip);
+}
@@ -0,0 +1,378 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use lz4::{Encoder, EncoderBuilder};
+
+use crate::{Codec, CompresstimatorError, ExternalCommandCodec};
+
+/// The window lz4 can reference, and so the most of a dictionary it can use
+pub(crate) const LZ4_WINDOW: usize = 64 * 1024;
+
+/// Counts what's written, passing it on to `sink` if there is one, and
+/// noting if that failed.
+#[derive(Default)]
+pub(crate) struct WriteCount<'a> {
+    pub(crate) written: u64,
+    sink: Option<&'a mut dyn Write>,
+    sink_failed: bool,
+}
+
+impl Write for WriteCount<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = sink.write_all(buf) {
+                self.sink_failed = true;
+                return Err(e);
+            }
+        }
+        self.written += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A compressor which counts its output, and optionally passes it on to a
+/// sink.
+pub(crate) enum Compressor<'a> {
+    Lz4(Lz4Compressor<'a>),
+    External(ExternalCompressor<'a>),
+}
+
+impl<'a> Compressor<'a> {
+    pub(crate) fn new(
+        codec: &Codec,
+        dictionary: Option<&[u8]>,
+        sink: Option<&'a mut dyn Write>,
+    ) -> io::Result<Self> {
+        match codec {
+            Codec::Lz4 { level } => {
+                Lz4Compressor::new(*level, dictionary, sink).map(Compressor::Lz4)
+            }
+            Codec::External(cmd) => ExternalCompressor::new(cmd, sink).map(Compressor::External),
+        }
+    }
+
+    /// Compressed bytes output so far.  This lags behind the input, since
+    /// compressors buffer.
+    pub(crate) fn written(&self) -> u64 {
+        match self {
+            Compressor::Lz4(c) => c.written(),
+            Compressor::External(c) => c.written.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Finish the stream, returning the total compressed size.
+    pub(crate) fn finish(self) -> io::Result<u64> {
+        self.finish_with_sink().map(|(compressed, _)| compressed)
+    }
+
+    /// Finish the stream, returning the total compressed size along with the
+    /// sink, if there was one, to carry on writing to.
+    fn finish_with_sink(self) -> io::Result<(u64, Option<&'a mut dyn Write>)> {
+        match self {
+            Compressor::Lz4(c) => c.finish(),
+            Compressor::External(c) => c.finish(),
+        }
+    }
+}
+
+/// A compressor starting a fresh stream every `chunk` bytes of input, as
+/// storage compressing fixed-size records independently does, counting the
+/// output of them all.  Streams are only started once there's something to
+/// write to them, so a final empty one costs nothing.
+pub(crate) struct ChunkedCompressor<'a, 'd> {
+    codec: Codec,
+    dictionary: Option<&'d [u8]>,
+    chunk: u64,
+    in_chunk: u64,
+    finished: u64,
+    current: Option<Compressor<'a>>,
+    sink: Option<&'a mut dyn Write>,
+}
+
+impl<'a, 'd> ChunkedCompressor<'a, 'd> {
+    /// Compress with `codec`, starting afresh every `chunk` bytes unless it's
+    /// zero.
+    pub(crate) fn new(
+        codec: Codec,
+        dictionary: Option<&'d [u8]>,
+        sink: Option<&'a mut dyn Write>,
+        chunk: u64,
+    ) -> io::Result<Self> {
+        let current = Compressor::new(&codec, dictionary, sink)?;
+        Ok(Self {
+            codec,
+            dictionary,
+            chunk: if chunk == 0 { u64::MAX } else { chunk },
+            in_chunk: 0,
+            finished: 0,
+            current: Some(current),
+            sink: None,
+        })
+    }
+
+    /// Compressed bytes output so far, from finished chunks and the current
+    /// one.
+    pub(crate) fn written(&self) -> u64 {
+        self.finished + self.current.as_ref().map_or(0, Compressor::written)
+    }
+
+    /// Finish the current chunk, returning the total compressed size.
+    pub(crate) fn finish(self) -> io::Result<u64> {
+        let current = match self.current {
+            Some(current) => current.finish()?,
+            None => 0,
+        };
+        Ok(self.finished + current)
+    }
+}
+
+impl Write for ChunkedCompressor<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let current = match &mut self.current {
+                Some(current) => current,
+                None => self.current.insert(Compressor::new(
+                    &self.codec,
+                    self.dictionary,
+                    self.sink.take(),
+                )?),
+            };
+
+            let take = (self.chunk - self.in_chunk).min(rest.len() as u64) as usize;
+            current.write_all(&rest[..take])?;
+            rest = &rest[take..];
+            self.in_chunk += take as u64;
+
+            if self.in_chunk == self.chunk {
+                let current = self.current.take().expect("just written to");
+                let (compressed, sink) = current.finish_with_sink()?;
+                self.finished += compressed;
+                self.sink = sink;
+                self.in_chunk = 0;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for Compressor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::Lz4(c) => {
+                let n = c.encoder.write(buf)?;
+                c.consumed += n as u64;
+                Ok(n)
+            }
+            Compressor::External(c) => c.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::Lz4(c) => c.encoder.flush(),
+            Compressor::External(_) => Ok(()),
+        }
+    }
+}
+
+/// An lz4 encoder, optionally primed with a dictionary.
+///
+/// These are built afresh for every stream rather than reset and reused: the
+/// `lz4` crate's `Encoder::finish` consumes it along with its context, and
+/// offers no way to begin a new frame on an existing one.  Building and
+/// finishing an empty encoder measures around 6µs, next to around 3µs to
+/// compress a 4KiB block through it, so it's noise against opening and
+/// reading a file, though it does dominate estimates compressing each block
+/// on its own.
+///
+/// lz4 frames have no real dictionary support, so we feed the dictionary
+/// through first, flush, and discount whatever that produced.  In linked block
+/// mode later blocks can still reference it, which is all a dictionary is.
+/// It does go to any sink, since the frame can't be decoded without it.
+pub(crate) struct Lz4Compressor<'a> {
+    encoder: Encoder<WriteCount<'a>>,
+    baseline: u64,
+    consumed: u64,
+}
+
+impl<'a> Lz4Compressor<'a> {
+    fn new(
+        level: u32,
+        dictionary: Option<&[u8]>,
+        sink: Option<&'a mut dyn Write>,
+    ) -> io::Result<Self> {
+        let mut encoder = EncoderBuilder::new().level(level).build(WriteCount {
+            sink,
+            ..WriteCount::default()
+        })?;
+
+        let mut baseline = 0;
+        if let Some(dictionary) = dictionary.filter(|d| !d.is_empty()) {
+            let start = dictionary.len().saturating_sub(LZ4_WINDOW);
+            encoder.write_all(&dictionary[start..])?;
+            encoder.flush()?;
+            baseline = encoder.writer().written;
+        }
+
+        Ok(Self {
+            encoder,
+            baseline,
+            consumed: 0,
+        })
+    }
+
+    fn written(&self) -> u64 {
+        self.encoder.writer().written - self.baseline
+    }
+
+    /// Finish the frame, failing with `CompresstimatorError::Finish` for
+    /// context on how far it got.
+    fn finish(self) -> io::Result<(u64, Option<&'a mut dyn Write>)> {
+        let baseline = self.baseline;
+        let (output, result) = self.encoder.finish();
+        match result {
+            Ok(()) => Ok((output.written - baseline, output.sink)),
+            Err(e) => Err(CompresstimatorError::Finish {
+                consumed: self.consumed,
+                produced: output.written.saturating_sub(baseline),
+                sink: output.sink_failed,
+                kind: e.kind(),
+                message: e.to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// A child process compressing stdin to stdout, with a thread counting its
+/// output so it can't block on a full pipe.  With a sink, the thread also
+/// keeps the output for us to hand over on the next write.
+pub(crate) struct ExternalCompressor<'a> {
+    codec: ExternalCommandCodec,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    written: Arc<AtomicU64>,
+    captured: Option<Arc<Mutex<Vec<u8>>>>,
+    sink: Option<&'a mut dyn Write>,
+    reader: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl<'a> ExternalCompressor<'a> {
+    fn new(codec: &ExternalCommandCodec, sink: Option<&'a mut dyn Write>) -> io::Result<Self> {
+        let mut child = Command::new(&*codec.program)
+            .args(codec.args.iter())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| codec_error(codec, &e.to_string()))?;
+
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&written);
+        let captured = sink.as_ref().map(|_| Arc::new(Mutex::new(Vec::new())));
+        let capture = captured.clone();
+
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => {
+                        if let Some(capture) = &capture {
+                            capture
+                                .lock()
+                                .expect("capture")
+                                .extend_from_slice(&buf[..n]);
+                        }
+                        counter.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        });
+
+        Ok(Self {
+            codec: codec.clone(),
+            child,
+            stdin,
+            written,
+            captured,
+            sink,
+            reader: Some(reader),
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let stdin = self.stdin.as_mut().expect("stdin open until finish");
+        let n = stdin
+            .write(buf)
+            .map_err(|e| codec_error(&self.codec, &e.to_string()))?;
+        self.drain()?;
+        Ok(n)
+    }
+
+    /// Pass on whatever output has been captured so far to the sink.
+    fn drain(&mut self) -> io::Result<()> {
+        if let (Some(captured), Some(sink)) = (&self.captured, &mut self.sink) {
+            let output = std::mem::take(&mut *captured.lock().expect("capture"));
+            sink.write_all(&output)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<(u64, Option<&'a mut dyn Write>)> {
+        drop(self.stdin.take());
+
+        let status = self.child.wait()?;
+        if let Some(reader) = self.reader.take() {
+            reader
+                .join()
+                .map_err(|_| codec_error(&self.codec, "output reader panicked"))??;
+        }
+
+        if !status.success() {
+            return Err(codec_error(&self.codec, &status.to_string()));
+        }
+
+        self.drain()?;
+        Ok((self.written.load(Ordering::Relaxed), self.sink.take()))
+    }
+}
+
+impl Drop for ExternalCompressor<'_> {
+    fn drop(&mut self) {
+        // Abandoned part way through, most likely by an error elsewhere
+        if self.reader.is_some() {
+            drop(self.stdin.take());
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn codec_error(codec: &ExternalCommandCodec, message: &str) -> io::Error {
+    CompresstimatorError::Codec {
+        codec: codec.to_string(),
+        message: message.to_string(),
+    }
+    .into()
+}
@@ -0,0 +1,275 @@
+use std::hash::{Hash, Hasher};
+
+use crate::sys::DIRECT_IO_ALIGN;
+use crate::{Codec, CompresstimatorError, Confidence};
+
+pub(crate) const DEFAULT_BLOCK_SIZE: u64 = 4096;
+pub(crate) const DEFAULT_ERROR_MARGIN: f32 = 0.1;
+pub(crate) const DEFAULT_CONFIDENCE: Confidence = Confidence::C95;
+
+/// Where in an input `Compresstimator::sample_strategy` takes its samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SampleStrategy {
+    /// Evenly spaced, at a fixed stride from the start
+    #[default]
+    Uniform,
+    /// Distinct blocks picked uniformly at random, the same ones for the same
+    /// seed and input length
+    Random {
+        /// Seed for the choice of blocks
+        seed: u64,
+    },
+    /// Evenly spaced within a region and outside it, but `weight` times as
+    /// densely within it - see `Compresstimator::sample_strategy`
+    Region {
+        /// Where the region starts, in bytes from the start of what's sampled
+        offset: u64,
+        /// How long the region is
+        len: u64,
+        /// How many times as densely to sample the region as the rest
+        weight: u32,
+    },
+}
+
+/// When to give up sampling an input as incompressible, for
+/// `Compresstimator::early_exit`.
+///
+/// As part of a `Config`, the threshold is compared and hashed quantized to
+/// the nearest 0.0001, like the error margin, so thresholds closer than that
+/// make the same configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EarlyExit {
+    /// How many sampled blocks in a row must reach the threshold
+    pub blocks: u32,
+    /// The ratio, compressing a block on its own, at or above which it counts
+    pub threshold: f32,
+}
+
+impl Default for EarlyExit {
+    /// Eight blocks in a row which the compressor makes no smaller.
+    fn default() -> Self {
+        Self {
+            blocks: 8,
+            threshold: 1.0,
+        }
+    }
+}
+
+/// The full configuration of a `Compresstimator`, for inspecting or replacing
+/// it wholesale with `Compresstimator::reconfigure`.
+///
+/// The fields correspond to the setters of the same names on `Compresstimator`,
+/// which document them in more detail.
+///
+/// `Config` implements `Eq` and `Hash`, so it can form part of a cache key.  To
+/// make that possible the error margin, like the early exit threshold, is
+/// compared quantized to the nearest 0.0001 (0.01%), so margins closer than
+/// that compare and hash the same, and a NaN margin is treated as zero.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Size of each sampled block, in bytes
+    pub block_size: u64,
+    /// Grow the block size for large inputs
+    pub adaptive_block_size: bool,
+    /// Margin of error, between 0 and 1 exclusive
+    pub error_margin: f32,
+    /// Confidence level
+    pub confidence: Confidence,
+    /// Where samples are taken from
+    pub strategy: SampleStrategy,
+    /// Round sampled offsets down to block boundaries
+    pub align_samples: bool,
+    /// Extend sampled blocks to whole lines
+    pub align_lines: bool,
+    /// Always read the full input
+    pub force_exhaustive: bool,
+    /// Allocation unit for `Estimate::estimated_allocated_len`
+    pub min_allocation_unit: u64,
+    /// Bytes of container around each object, added to
+    /// `Estimate::estimated_allocated_len`
+    pub container_overhead: u64,
+    /// The compressor to estimate with
+    pub codec: Codec,
+    /// The largest window, as a log2, to let the codec use
+    pub max_window_log: Option<u32>,
+    /// Stop sampling inputs which start out incompressible
+    pub early_exit: Option<EarlyExit>,
+    /// Bytes at the start of the input to leave out of estimates
+    pub trim_head: u64,
+    /// Bytes at the end of the input to leave out of estimates
+    pub trim_tail: u64,
+    /// Open files with `O_DIRECT`, bypassing the page cache (Linux only)
+    pub direct_io: bool,
+    /// Read everything twice and fail if the two reads differ
+    pub verify_reads: bool,
+    /// Bytes at the start of the input to use as a dictionary for the rest
+    pub prefix_dictionary: u64,
+    /// A filler byte whose long runs are left out of what's compressed
+    pub strip_padding: Option<u8>,
+    /// Compress independent chunks of this many bytes, or one stream if zero
+    pub chunk_size: u64,
+    /// Sample only the parts of files with data written to them (Linux only)
+    pub sample_extents: bool,
+    /// Run-length encode blocks before compressing them
+    pub rle_preanalysis: bool,
+}
+
+impl Default for Config {
+    /// A block size of 4096 bytes, growing for large inputs, 10% margin of
+    /// error, and 95% confidence level using lz4 level 1, with everything else
+    /// off.
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            adaptive_block_size: true,
+            error_margin: DEFAULT_ERROR_MARGIN,
+            confidence: DEFAULT_CONFIDENCE,
+            strategy: SampleStrategy::Uniform,
+            align_samples: false,
+            align_lines: false,
+            force_exhaustive: false,
+            min_allocation_unit: 1,
+            container_overhead: 0,
+            codec: Codec::default(),
+            max_window_log: None,
+            early_exit: None,
+            trim_head: 0,
+            trim_tail: 0,
+            direct_io: false,
+            verify_reads: false,
+            prefix_dictionary: 0,
+            strip_padding: None,
+            chunk_size: 0,
+            sample_extents: false,
+            rle_preanalysis: false,
+        }
+    }
+}
+
+impl Config {
+    /// Check the configuration makes sense: a non-zero block size, a margin of
+    /// error between 0 and 1, alignment for direct I/O, and a usable codec.
+    pub fn validate(&self) -> Result<(), CompresstimatorError> {
+        if self.block_size == 0 {
+            return Err(CompresstimatorError::ZeroBlockSize);
+        }
+
+        if !(self.error_margin > 0.0 && self.error_margin < 1.0) {
+            return Err(CompresstimatorError::InvalidErrorMargin(self.error_margin));
+        }
+
+        let align = DIRECT_IO_ALIGN as u64;
+        if self.direct_io && (self.block_size % align != 0 || self.trim_head % align != 0) {
+            return Err(CompresstimatorError::DirectIoAlignment {
+                block_size: self.block_size,
+                trim_head: self.trim_head,
+            });
+        }
+
+        self.codec.validate()
+    }
+}
+
+/// The margin of error in units of 0.0001
+fn quantize(margin: f32) -> i64 {
+    // `as` saturates, and maps NaN to zero
+    (f64::from(margin) * 10_000.0).round() as i64
+}
+
+impl Config {
+    /// Everything which determines equality, with floats quantized.
+    fn key(&self) -> impl Eq + Hash + '_ {
+        // Destructure so new fields can't be forgotten here
+        let Config {
+            block_size,
+            adaptive_block_size,
+            error_margin,
+            confidence,
+            strategy,
+            align_samples,
+            align_lines,
+            force_exhaustive,
+            min_allocation_unit,
+            container_overhead,
+            codec,
+            max_window_log,
+            early_exit,
+            trim_head,
+            trim_tail,
+            direct_io,
+            verify_reads,
+            prefix_dictionary,
+            strip_padding,
+            chunk_size,
+            sample_extents,
+            rle_preanalysis,
+        } = self;
+
+        // Grouped, since tuples only implement Eq and Hash up to 12 long
+        (
+            (block_size, adaptive_block_size),
+            (quantize(*error_margin), confidence, strategy),
+            (align_samples, align_lines),
+            force_exhaustive,
+            (min_allocation_unit, container_overhead),
+            (codec, max_window_log),
+            early_exit.map(|e| (e.blocks, quantize(e.threshold))),
+            (trim_head, trim_tail),
+            (direct_io, verify_reads, sample_extents),
+            (
+                prefix_dictionary,
+                strip_padding,
+                chunk_size,
+                rle_preanalysis,
+            ),
+        )
+    }
+}
+
+impl PartialEq for Config {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Config {}
+
+impl Hash for Config {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+#[test]
+fn quantized_equality() {
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash(config: &Config) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        config.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let a = Config {
+        error_margin: 0.1,
+        ..Config::default()
+    };
+    let b = Config {
+        error_margin: 0.100_001,
+        ..Config::default()
+    };
+    let c = Config {
+        error_margin: 0.11,
+        ..Config::default()
+    };
+
+    assert_eq!(a, b);
+    assert_eq!(hash(&a), hash(&b));
+    assert_ne!(a, c);
+
+    let nan = Config {
+        error_margin: f32::NAN,
+        ..Config::default()
+    };
+    assert_eq!(nan, nan.clone());
+}
@@ -0,0 +1,146 @@
+//! Recognising compressed inputs and reading them decompressed
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use crate::CompresstimatorError;
+
+/// The container format of an input, as recognised by its leading magic
+/// bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputFormat {
+    /// Nothing recognised, taken as it is
+    Plain,
+    /// gzip, decompressed with `gzip -dc`
+    Gzip,
+    /// Zstandard, decompressed with `zstd -dc`
+    Zstd,
+    /// xz, decompressed with `xz -dc`
+    Xz,
+    /// bzip2, decompressed with `bzip2 -dc`
+    Bzip2,
+    /// An lz4 frame, decompressed in-process
+    Lz4,
+}
+
+impl InputFormat {
+    /// The most magic bytes `detect` looks at.
+    pub const MAGIC_LEN: usize = 6;
+
+    /// Recognise the format of an input starting with `magic`.
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            Self::Xz
+        } else if magic.starts_with(b"BZh") && matches!(magic.get(3), Some(b'1'..=b'9')) {
+            Self::Bzip2
+        } else if magic.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+            Self::Lz4
+        } else {
+            Self::Plain
+        }
+    }
+
+    /// The command which decompresses this format from stdin to stdout.
+    fn command(self) -> Option<&'static str> {
+        match self {
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+            Self::Xz => Some("xz"),
+            Self::Bzip2 => Some("bzip2"),
+            Self::Plain | Self::Lz4 => None,
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Plain => "plain",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bzip2",
+            Self::Lz4 => "lz4",
+        })
+    }
+}
+
+/// `file`, positioned at its start, decompressed as `format`.
+pub(crate) fn decompressed(format: InputFormat, file: File) -> io::Result<Box<dyn Read>> {
+    match format.command() {
+        Some(program) => ExternalDecompressor::new(program, file).map(|d| Box::new(d) as _),
+        None if format == InputFormat::Lz4 => Ok(Box::new(lz4::Decoder::new(file)?)),
+        None => Ok(Box::new(file)),
+    }
+}
+
+/// A child process decompressing a file to its stdout, failing the read at
+/// the end if it exited unsuccessfully.
+struct ExternalDecompressor {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+    done: bool,
+}
+
+impl ExternalDecompressor {
+    fn new(program: &'static str, file: File) -> io::Result<Self> {
+        let mut child = Command::new(program)
+            .arg("-dc")
+            .stdin(file)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| decompress_error(program, &e.to_string()))?;
+        let stdout = child.stdout.take().expect("piped stdout");
+
+        Ok(Self {
+            program,
+            child,
+            stdout,
+            done: false,
+        })
+    }
+}
+
+impl Read for ExternalDecompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.done = true;
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(decompress_error(self.program, &status.to_string()));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ExternalDecompressor {
+    fn drop(&mut self) {
+        // Abandoned before the end, most likely by an error elsewhere
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn decompress_error(program: &str, message: &str) -> io::Error {
+    CompresstimatorError::Codec {
+        codec: format!("{} -dc", program),
+        message: message.to_string(),
+    }
+    .into()
+}
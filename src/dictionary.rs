@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::compressor::LZ4_WINDOW;
+
+/// Build a dictionary of up to `dict_size` bytes from a set of sample files,
+/// for use with `Compresstimator::dictionary`.
+///
+/// Nothing is trained: this isn't a zstd dictionary, and finds no common
+/// substrings.  lz4 dictionaries are just raw content which later data can
+/// reference, so this concatenates an equal share of leading bytes from each
+/// file - for many small files of the same kind, that's where the shared
+/// structure usually sits.
+/// Files are taken in order, with later files nearer the end of the dictionary
+/// where matches are cheapest.  lz4 can only use the last 64KiB, so larger
+/// sizes are capped to that.
+pub fn dictionary_from_samples<P: AsRef<Path>>(
+    paths: &[P],
+    dict_size: usize,
+) -> io::Result<Vec<u8>> {
+    let dict_size = dict_size.min(LZ4_WINDOW);
+    let mut dictionary = Vec::with_capacity(dict_size);
+
+    if paths.is_empty() {
+        return Ok(dictionary);
+    }
+
+    let share = (dict_size / paths.len()).max(1) as u64;
+    for path in paths {
+        let remaining = (dict_size - dictionary.len()) as u64;
+        if remaining == 0 {
+            break;
+        }
+
+        File::open(path)?
+            .take(share.min(remaining))
+            .read_to_end(&mut dictionary)?;
+    }
+
+    Ok(dictionary)
+}
+
+#[test]
+fn sampled_dictionary_size() {
+    let dictionary = dictionary_from_samples(&["Cargo.toml", "README.md"], 256).expect("train");
+    assert_eq!(dictionary.len(), 256);
+    assert!(dictionary.starts_with(b"[package]"));
+
+    let dictionary = dictionary_from_samples(&["Cargo.toml"], 1024 * 1024).expect("train");
+    assert_eq!(dictionary, std::fs::read("Cargo.toml").expect("read"));
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors specific to compresstimation.  Where a method returns `io::Result`,
+/// these are wrapped in an `io::Error`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompresstimatorError {
+    /// The block size was zero
+    ZeroBlockSize,
+    /// The margin of error was not between 0 and 1
+    InvalidErrorMargin(f32),
+    /// Seeking to the end of a stream reported a position before where it
+    /// started, so its remaining length couldn't be determined
+    InvalidStreamLength {
+        /// Where the stream was positioned
+        position: u64,
+        /// Where seeking to the end claimed it finished
+        end: u64,
+    },
+    /// An input being re-estimated after growing was shorter than before, so
+    /// its earlier contents can't be assumed unchanged
+    InputShrank {
+        /// The length of the input when it was last estimated
+        previous: u64,
+        /// Its length now
+        len: u64,
+    },
+    /// Direct I/O was asked for, but the block size or head trim weren't
+    /// multiples of the 4096 byte alignment it needs
+    DirectIoAlignment {
+        /// The configured block size
+        block_size: u64,
+        /// The configured head trim
+        trim_head: u64,
+    },
+    /// Reading the same part of the input twice with `verify_reads` gave
+    /// different bytes, suggesting failing storage
+    ReadMismatch {
+        /// Where the read started
+        offset: u64,
+        /// How many bytes were compared
+        len: u64,
+    },
+    /// Finishing an lz4 stream failed, either in the encoder or writing its
+    /// final output on to a sink
+    Finish {
+        /// Bytes fed to the stream before it was finished
+        consumed: u64,
+        /// Compressed bytes it output before failing
+        produced: u64,
+        /// Whether writing to the sink failed, rather than the encoder
+        sink: bool,
+        /// The kind of the underlying error, kept when this is wrapped in an
+        /// `io::Error`
+        kind: io::ErrorKind,
+        /// What went wrong
+        message: String,
+    },
+    /// The compressor failed, such as an external program not starting or
+    /// exiting unsuccessfully
+    Codec {
+        /// The codec which failed
+        codec: String,
+        /// What went wrong
+        message: String,
+    },
+}
+
+impl fmt::Display for CompresstimatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroBlockSize => write!(f, "block size must be greater than zero"),
+            Self::InvalidErrorMargin(margin) => {
+                write!(f, "error margin {} is not between 0 and 1", margin)
+            }
+            Self::InvalidStreamLength { position, end } => write!(
+                f,
+                "could not determine length: stream ends at {} but is positioned at {}",
+                end, position
+            ),
+            Self::InputShrank { previous, len } => write!(
+                f,
+                "input shrank from {} to {} bytes since it was estimated",
+                previous, len
+            ),
+            Self::DirectIoAlignment {
+                block_size,
+                trim_head,
+            } => write!(
+                f,
+                "direct I/O needs a block size and head trim in multiples of 4096, not {} and {}",
+                block_size, trim_head
+            ),
+            Self::ReadMismatch { offset, len } => write!(
+                f,
+                "reading {} bytes at {} twice gave different data",
+                len, offset
+            ),
+            Self::Finish {
+                consumed,
+                produced,
+                sink,
+                message,
+                ..
+            } => write!(
+                f,
+                "finishing lz4 stream of {} bytes failed {} after {} compressed bytes: {}",
+                consumed,
+                if *sink {
+                    "writing to the sink"
+                } else {
+                    "in the encoder"
+                },
+                produced,
+                message
+            ),
+            Self::Codec { codec, message } => write!(f, "{}: {}", codec, message),
+        }
+    }
+}
+
+impl Error for CompresstimatorError {}
+
+impl From<CompresstimatorError> for io::Error {
+    fn from(err: CompresstimatorError) -> Self {
+        let kind = match err {
+            CompresstimatorError::InvalidStreamLength { .. }
+            | CompresstimatorError::ReadMismatch { .. } => io::ErrorKind::InvalidData,
+            CompresstimatorError::Codec { .. } => io::ErrorKind::Other,
+            CompresstimatorError::Finish { kind, .. } => kind,
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,24 @@
+//! A C interface, enabled with the `ffi` feature.
+//!
+//! Build with `crate-type = ["cdylib"]` or `["staticlib"]` to link it from
+//! C, declaring:
+//!
+//! ```c
+//! float compresstimate_raw(const uint8_t *ptr, size_t len);
+//! ```
+
+use crate::Compresstimator;
+
+/// Estimate the compression ratio of the `len` bytes at `ptr` with the default
+/// settings, returning a value between 0 and 1, or a negative number on error.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes, and the memory must not be
+/// modified until this returns.  It may be null if `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn compresstimate_raw(ptr: *const u8, len: usize) -> f32 {
+    Compresstimator::default()
+        .compresstimate_raw(ptr, len)
+        .unwrap_or(-1.0)
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+
+/// How `format_ratio` should present a compression ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RatioStyle {
+    /// How many times smaller, like "2.4x smaller"
+    Multiplier,
+    /// The proportion saved, like "saves 58%"
+    PercentSaved,
+    /// The compressed size as a proportion of the original, like "42% of original"
+    OfOriginal,
+}
+
+/// Which units `format_bytes` should use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ByteUnits {
+    /// Powers of 1024: KiB, MiB, GiB
+    #[default]
+    Binary,
+    /// Powers of 1000: kB, MB, GB
+    Decimal,
+}
+
+/// Describe a size in bytes for people, like "1.2 GiB" or "340 B".
+pub fn format_bytes(bytes: u64, units: ByteUnits) -> String {
+    let (base, suffixes) = match units {
+        ByteUnits::Binary => (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+        ByteUnits::Decimal => (1000.0, ["kB", "MB", "GB", "TB", "PB", "EB"]),
+    };
+
+    let mut size = bytes as f64;
+    if size < base {
+        return format!("{} B", bytes);
+    }
+
+    let mut suffix = suffixes[0];
+    for next in suffixes {
+        suffix = next;
+        size /= base;
+        // Stop before rounding could show, say, "1024.0 KiB"
+        if size < base - 0.05 {
+            break;
+        }
+    }
+
+    format!("{:.1} {}", size, suffix)
+}
+
+/// Describe a compressed/original `ratio` for people, in the given `style`.
+///
+/// A ratio of 1.0 is described as no savings, and anything above it as
+/// growing rather than as negative savings.  NaN gives "unknown".
+pub fn format_ratio(ratio: f32, style: RatioStyle) -> String {
+    if ratio.is_nan() {
+        return "unknown".to_string();
+    }
+
+    match style {
+        RatioStyle::Multiplier if ratio <= 0.0 => "compresses to nothing".to_string(),
+        RatioStyle::Multiplier if ratio < 1.0 => format!("{:.1}x smaller", 1.0 / ratio),
+        RatioStyle::Multiplier if ratio > 1.0 => format!("{:.1}x larger", ratio),
+        RatioStyle::Multiplier => "no savings".to_string(),
+        RatioStyle::PercentSaved if ratio < 1.0 => format!("saves {:.0}%", (1.0 - ratio) * 100.0),
+        RatioStyle::PercentSaved if ratio > 1.0 => format!("grows {:.0}%", (ratio - 1.0) * 100.0),
+        RatioStyle::PercentSaved => "no savings".to_string(),
+        RatioStyle::OfOriginal => format!("{:.0}% of original", ratio * 100.0),
+    }
+}
+
+/// A coarse label for how well something compresses, from `grade`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Grade {
+    /// Well worth compressing
+    Excellent,
+    /// Worth compressing
+    Good,
+    /// Saves a little, which may not pay for the CPU time
+    Marginal,
+    /// Not worth compressing
+    None,
+}
+
+impl fmt::Display for Grade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Grade::Excellent => "Excellent",
+            Grade::Good => "Good",
+            Grade::Marginal => "Marginal",
+            Grade::None => "None",
+        })
+    }
+}
+
+/// The ratios below which `grade_with` awards each `Grade`.
+///
+/// A ratio below `excellent` is `Excellent`, below `good` is `Good`, below
+/// `marginal` is `Marginal`, and anything else, including NaN, is `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradeThresholds {
+    /// Ratios below this are `Excellent`
+    pub excellent: f32,
+    /// Ratios below this are at least `Good`
+    pub good: f32,
+    /// Ratios below this are at least `Marginal`
+    pub marginal: f32,
+}
+
+impl Default for GradeThresholds {
+    /// Excellent below 0.3, Good below 0.7, Marginal below 0.95.
+    fn default() -> Self {
+        Self {
+            excellent: 0.3,
+            good: 0.7,
+            marginal: 0.95,
+        }
+    }
+}
+
+/// Grade a compressed/original `ratio` with the default thresholds.
+pub fn grade(ratio: f32) -> Grade {
+    grade_with(ratio, &GradeThresholds::default())
+}
+
+/// Grade a compressed/original `ratio` with custom `thresholds`.
+pub fn grade_with(ratio: f32, thresholds: &GradeThresholds) -> Grade {
+    if ratio < thresholds.excellent {
+        Grade::Excellent
+    } else if ratio < thresholds.good {
+        Grade::Good
+    } else if ratio < thresholds.marginal {
+        Grade::Marginal
+    } else {
+        Grade::None
+    }
+}
+
+#[test]
+fn byte_formatting() {
+    assert_eq!(format_bytes(0, ByteUnits::Binary), "0 B");
+    assert_eq!(format_bytes(1023, ByteUnits::Binary), "1023 B");
+    assert_eq!(format_bytes(1024, ByteUnits::Binary), "1.0 KiB");
+    assert_eq!(format_bytes(1024 * 1024 - 1, ByteUnits::Binary), "1.0 MiB");
+    assert_eq!(format_bytes(1288490189, ByteUnits::Binary), "1.2 GiB");
+    assert_eq!(format_bytes(340_000_000, ByteUnits::Decimal), "340.0 MB");
+    assert_eq!(format_bytes(u64::MAX, ByteUnits::Decimal), "18.4 EB");
+}
+
+#[test]
+fn ratio_formatting() {
+    assert_eq!(format_ratio(0.42, RatioStyle::Multiplier), "2.4x smaller");
+    assert_eq!(format_ratio(0.42, RatioStyle::PercentSaved), "saves 58%");
+    assert_eq!(
+        format_ratio(0.42, RatioStyle::OfOriginal),
+        "42% of original"
+    );
+
+    assert_eq!(format_ratio(1.0, RatioStyle::Multiplier), "no savings");
+    assert_eq!(format_ratio(1.0, RatioStyle::PercentSaved), "no savings");
+    assert_eq!(format_ratio(1.5, RatioStyle::Multiplier), "1.5x larger");
+    assert_eq!(format_ratio(1.5, RatioStyle::PercentSaved), "grows 50%");
+    assert_eq!(
+        format_ratio(1.5, RatioStyle::OfOriginal),
+        "150% of original"
+    );
+
+    assert_eq!(
+        format_ratio(0.0, RatioStyle::Multiplier),
+        "compresses to nothing"
+    );
+    assert_eq!(format_ratio(f32::NAN, RatioStyle::PercentSaved), "unknown");
+}
+
+#[test]
+fn grades() {
+    assert_eq!(grade(0.1), Grade::Excellent);
+    assert_eq!(grade(0.3), Grade::Good);
+    assert_eq!(grade(0.8), Grade::Marginal);
+    assert_eq!(grade(0.95), Grade::None);
+    assert_eq!(grade(f32::NAN), Grade::None);
+    assert_eq!(Grade::Marginal.to_string(), "Marginal");
+
+    let strict = GradeThresholds {
+        excellent: 0.1,
+        good: 0.5,
+        marginal: 0.8,
+    };
+    assert_eq!(grade_with(0.2, &strict), Grade::Good);
+    assert_eq!(grade_with(0.85, &strict), Grade::None);
+}
@@ -6,36 +6,160 @@
 //! Simple file compressibility estimation
 //!
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use lz4::EncoderBuilder;
+mod aggregate;
+mod codec;
+mod compressor;
+mod config;
+mod decompress;
+mod dictionary;
+mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod format;
+mod rolling;
+mod stats;
+mod sys;
+mod tree;
 
-#[derive(Debug, Default)]
-struct WriteCount {
-    written: u64,
+pub use aggregate::{aggregate_ratio, Weighting};
+pub use codec::{Codec, ExternalCommandCodec};
+use compressor::{ChunkedCompressor, Compressor};
+pub use config::{Config, EarlyExit, SampleStrategy};
+pub use decompress::InputFormat;
+pub use dictionary::dictionary_from_samples;
+pub use error::CompresstimatorError;
+pub use format::{
+    format_bytes, format_ratio, grade, grade_with, ByteUnits, Grade, GradeThresholds, RatioStyle,
+};
+pub use rolling::RollingEstimator;
+
+/// Emit a `log` debug event, if built with the `trace` feature, for the
+/// embedding program's logger to filter and route.
+#[cfg(feature = "trace")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log::debug!($($arg)*)
+    };
 }
 
-impl Write for WriteCount {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.written += buf.len() as u64;
+#[cfg(not(feature = "trace"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
 
-        Ok(buf.len())
+/// The length of `file`, asking the OS directly for block devices, whose
+/// metadata length is typically zero.
+fn file_len(file: &mut File) -> io::Result<u64> {
+    if let Some(len) = sys::block_device_len(file)? {
+        return Ok(len);
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    let pos = file.stream_position()?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(pos))?;
+    Ok(len)
+}
+
+fn open(path: &Path, direct: bool) -> io::Result<File> {
+    let file = if direct {
+        sys::open_direct(path)
+    } else {
+        File::open(path)
+    };
+
+    file.map_err(|e| {
+        debug!("{}: {}", path.display(), e);
+        e
+    })
+}
+
+/// A reader which copies everything read through it into a writer
+struct Tee<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Tee<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// A reader over the chunks from an iterator, read as one stream across
+/// their boundaries
+struct ChunkReader<I> {
+    chunks: I,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<I> ChunkReader<I> {
+    fn new(chunks: I) -> Self {
+        Self {
+            chunks,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Empty chunks are skipped, so they aren't taken as the end
+        while self.pos == self.chunk.len() {
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
 }
 
 /// A statistical confidence level, 80% - 99%
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Confidence {
+    /// 80% confidence
     C80,
+    /// 85% confidence
     C85,
+    /// 90% confidence
     C90,
+    /// 95% confidence
     C95,
+    /// 99% confidence
     C99,
 }
 
@@ -51,169 +175,5937 @@ impl From<Confidence> for f32 {
     }
 }
 
+/// Inputs are split into at least this many blocks before an adaptive block
+/// size grows.
+const ADAPTIVE_BLOCKS: u64 = 65_536;
+
+/// The most an adaptive block size grows to.
+const MAX_ADAPTIVE_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// The block size to use for an input of `len` bytes if adapting, given the
+/// configured `block_size`: the largest power of two giving at least
+/// `ADAPTIVE_BLOCKS` blocks, but never smaller than configured nor larger than
+/// `MAX_ADAPTIVE_BLOCK_SIZE`.
+fn adaptive_block_size(len: u64, block_size: u64) -> u64 {
+    let per_block = len / ADAPTIVE_BLOCKS;
+    if per_block == 0 {
+        return block_size;
+    }
+
+    let pow2 = 1 << (63 - per_block.leading_zeros());
+    block_size.max(pow2.min(MAX_ADAPTIVE_BLOCK_SIZE))
+}
+
+/// Byte-shuffle `block` in place as an array of `element_size`-byte elements,
+/// leaving any trailing partial element alone.
+fn shuffle(block: &mut [u8], element_size: usize) {
+    if element_size < 2 || block.len() < element_size * 2 {
+        return;
+    }
+
+    let elements = block.len() / element_size;
+    let whole = &mut block[..elements * element_size];
+    let original = whole.to_vec();
+    for (i, element) in original.chunks_exact(element_size).enumerate() {
+        for (byte, &value) in element.iter().enumerate() {
+            whole[byte * elements + i] = value;
+        }
+    }
+}
+
+/// The longest run, and the most literal bytes in a row, `pack_bits` encodes
+/// under one count.
+const PACK_BITS_MAX: usize = 128;
+
+/// PackBits-encode `block` into `out`, as TIFF and MacPaint do: each run of
+/// three to 128 of a byte becomes a count and the byte, and everything else
+/// is copied in up to 128 bytes at a time after a count of its own.
+fn pack_bits(block: &[u8], out: &mut Vec<u8>) {
+    fn literals(literals: &[u8], out: &mut Vec<u8>) {
+        for chunk in literals.chunks(PACK_BITS_MAX) {
+            out.push(chunk.len() as u8 - 1);
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    out.clear();
+    let (mut start, mut i) = (0, 0);
+    while i < block.len() {
+        let byte = block[i];
+        let run = block[i..]
+            .iter()
+            .take(PACK_BITS_MAX)
+            .take_while(|&&b| b == byte)
+            .count();
+
+        if run >= 3 {
+            literals(&block[start..i], out);
+            out.push((257 - run) as u8);
+            out.push(byte);
+            start = i + run;
+        }
+        i += run;
+    }
+    literals(&block[start..], out);
+}
+
+/// The next number from a splitmix64 generator with the given `state`.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// `samples` distinct block indices from `0..blocks` chosen uniformly at
+/// random with Floyd's algorithm, in ascending order.
+fn random_blocks(blocks: u64, samples: u64, seed: u64) -> Vec<u64> {
+    let mut state = seed;
+    let mut chosen = std::collections::BTreeSet::new();
+
+    for j in blocks - samples..blocks {
+        // Uniform in 0..=j, by scaling rather than modulo to avoid bias
+        let pick = ((u128::from(splitmix64(&mut state)) * u128::from(j + 1)) >> 64) as u64;
+        if !chosen.insert(pick) {
+            chosen.insert(j);
+        }
+    }
+
+    chosen.into_iter().collect()
+}
+
+/// `samples` block indices from `0..blocks`, shared between those in
+/// `region` and the rest in proportion to their number, with the region's
+/// counting `weight` times, and spaced evenly within each.  The region is
+/// clipped to `0..blocks`.
+fn region_blocks(blocks: u64, samples: u64, region: Range<u64>, weight: u32) -> Vec<u64> {
+    let end = region.end.min(blocks);
+    let region = region.start.min(end)..end;
+    let inside = region.end - region.start;
+    let outside = blocks - inside;
+
+    let weighted = inside as f64 * f64::from(weight);
+    let share = weighted / (weighted + outside as f64);
+    let mut in_samples = (samples as f64 * share).round() as u64;
+    if outside > 0 && samples > 1 {
+        in_samples = in_samples.min(samples - 1);
+    }
+    let in_samples = in_samples.min(inside).max(samples.saturating_sub(outside));
+    let out_samples = (samples - in_samples).min(outside);
+
+    let mut chosen: Vec<u64> = (0..in_samples)
+        .map(|i| region.start + i * inside / in_samples)
+        .chain((0..out_samples).map(|i| {
+            let block = i * outside / out_samples;
+            if block < region.start {
+                block
+            } else {
+                block + inside
+            }
+        }))
+        .collect();
+    chosen.sort_unstable();
+    chosen
+}
+
 fn sample_size(pop: u64, moe: f32, confidence: Confidence) -> f32 {
     let pop = pop as f32;
     let n_naught = 0.25 * (f32::from(confidence) / moe).powi(2);
     ((pop * n_naught) / (n_naught + pop - 1.0)).ceil()
 }
 
-/// A compression estimator with a configured block size, and (currently) fixed
-/// accuracy (±15%, 90% confidence)
-///
-/// ```no_run
-/// use compresstimator::Compresstimator;
-///
-/// let est = Compresstimator::default();
-/// match est.compresstimate_file("big_file.dat") {
-///     Ok(ratio) => println!("Compression ratio: {}", ratio),
-///     Err(e) => eprintln!("IO Error: {}", e)
-/// };
-/// ```
-#[derive(Debug, Clone, Copy)]
-pub struct Compresstimator {
-    block_size: u64,
-    error_margin: f32,
-    confidence: Confidence,
+/// The ratio at or above which `Estimate::likely_incompressible` flags an
+/// estimate.
+pub const INCOMPRESSIBLE_RATIO: f32 = 0.98;
+
+/// The sizes behind an estimate: how much was sampled, what it compressed to,
+/// and what that implies for the whole input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Bytes read and compressed
+    pub sampled_original: u64,
+    /// What the sampled bytes compressed to
+    pub sampled_compressed: u64,
+    /// Length of the whole input
+    pub total_original: u64,
+    /// `total_original` scaled by the sampled ratio, assuming the unsampled
+    /// remainder compresses like the sample did.  Never more than
+    /// `total_original`.
+    pub extrapolated_compressed: u64,
+    /// `extrapolated_compressed` plus any configured container overhead,
+    /// rounded up to the configured minimum allocation unit, approximating
+    /// what would actually land on disk.
+    pub estimated_allocated_len: u64,
 }
 
-const DEFAULT_BLOCK_SIZE: u64 = 4096;
+impl Estimate {
+    fn new(
+        sampled_original: u64,
+        sampled_compressed: u64,
+        total_original: u64,
+        allocation_unit: u64,
+        container_overhead: u64,
+    ) -> Self {
+        let ratio = (sampled_compressed as f64 / sampled_original as f64).min(1.0);
+        let extrapolated_compressed = (total_original as f64 * ratio).round() as u64;
+        let unit = allocation_unit.max(1);
 
-impl Default for Compresstimator {
-    /// Create a `Compresstimator` with a default block size of 4096 bytes,
-    /// 10% margin of error, and 95% confidence level.
-    fn default() -> Self {
         Self {
-            block_size: DEFAULT_BLOCK_SIZE,
-            error_margin: 0.1,
-            confidence: Confidence::C95,
+            sampled_original,
+            sampled_compressed,
+            total_original,
+            extrapolated_compressed,
+            estimated_allocated_len: (extrapolated_compressed + container_overhead).div_ceil(unit)
+                * unit,
         }
     }
-}
 
-impl Compresstimator {
-    /// Alias for `default()`
-    pub fn new() -> Self {
-        Self::default()
+    /// The sampled compression ratio, between 0 and 1.
+    pub fn ratio(&self) -> f32 {
+        (self.sampled_compressed as f32 / self.sampled_original as f32).min(1.0)
     }
 
-    /// Use a given block size for compresstimation.  This should be some reasonable
-    /// multiple of the underlying filesystem block size.
-    pub fn with_block_size(block_size: u64) -> Self {
-        Self {
-            block_size: block_size as u64,
-            ..Self::default()
-        }
+    /// The estimated stored size over the original, from
+    /// `estimated_allocated_len`, so including container overhead and
+    /// allocation rounding.  Unlike `ratio` this can exceed 1, when those
+    /// cost more than compression saves, as they can for small objects.  An
+    /// empty input stores nothing but overhead, and is infinite.
+    pub fn stored_ratio(&self) -> f64 {
+        self.estimated_allocated_len as f64 / self.total_original as f64
     }
 
-    /// Use a given block size for compresstimation.  This should be some reasonable
-    /// multiple of the underlying filesystem block size.
-    pub fn block_size(&mut self, block_size: u64) -> &Self {
-        self.block_size = block_size;
-        self
+    /// The sampled compression ratio in basis points, between 0 and 10000, for
+    /// storing or comparing exactly without floats.
+    ///
+    /// It's calculated from the byte counts with integer arithmetic, rounding
+    /// halves up, so 0.42315 is 4232.  As with `ratio`, an empty sample counts
+    /// as incompressible, 10000.
+    pub fn ratio_bp(&self) -> u16 {
+        if self.sampled_original == 0 {
+            return 10_000;
+        }
+
+        let original = u128::from(self.sampled_original);
+        let bp = (u128::from(self.sampled_compressed) * 20_000 + original) / (original * 2);
+        bp.min(10_000) as u16
     }
 
-    /// Set the margin of error for the compressibility check.
+    /// Whether the sample was effectively incompressible, with a ratio of at
+    /// least `INCOMPRESSIBLE_RATIO`, suggesting the input is already
+    /// compressed or encrypted and compressing it would be wasted effort.
     ///
-    /// # Panics
-    ///
-    /// Panics if the error margin is not between 0 and 1.
-    pub fn error_margin(&mut self, margin: f32) -> &Self {
-        assert!(margin > 0.0 && margin < 1.0);
-        self.error_margin = margin;
-        self
+    /// This is only a heuristic: a few kinds of data are simply random-looking
+    /// without being either.
+    pub fn likely_incompressible(&self) -> bool {
+        self.ratio() >= INCOMPRESSIBLE_RATIO
     }
 
-    /// Set the confidence level of the compressibility check.
-    pub fn confidence_level(&mut self, confidence: Confidence) -> &Self {
-        self.confidence = confidence;
-        self
+    /// The original and extrapolated compressed sizes for people, like
+    /// "1.2 GiB → 340.0 MiB".
+    pub fn human_sizes(&self, units: ByteUnits) -> String {
+        format!(
+            "{} → {}",
+            format_bytes(self.total_original, units),
+            format_bytes(self.extrapolated_compressed, units)
+        )
     }
+}
 
-    /// Exhaustively compress the stream and return the achieved ratio.
-    pub fn base_truth<R: Read>(&self, mut input: R) -> io::Result<f32> {
-        let output = WriteCount::default();
-        let mut encoder = EncoderBuilder::new().level(1).build(output)?;
-        let written = std::io::copy(&mut input, &mut encoder)?;
+/// How an input would be read, from `Compresstimator::sampling`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampling {
+    /// The block size used, after any adaptive growth
+    pub block_size: u64,
+    /// Whether the input is read in full rather than sampled
+    pub full_read: bool,
+    /// How many blocks are read
+    pub blocks: u64,
+    /// Bytes from the start of one sampled block to the next, on average if
+    /// sampling at random, and the same as the block size when reading in full
+    pub stride: u64,
+}
 
-        let (output, result) = encoder.finish();
-        result.map(|_| (output.written as f32 / written as f32).min(1.0))
+impl Sampling {
+    /// Whether `estimate`, taken this way, read all of its input: because it
+    /// was meant to, or because the samples happened to cover it.
+    fn covered(&self, estimate: &Estimate) -> bool {
+        self.full_read || estimate.sampled_original >= estimate.total_original
     }
+}
 
-    /// Compresstimate the seekable stream `input` from the current position to the
-    /// end.
-    ///
-    /// This function determines the length of the stream by seeking to the end.
-    pub fn compresstimate<P: Read + Seek>(&self, mut input: P) -> io::Result<f32> {
-        // In future consider stream_len() and stream_position()
-        // https://github.com/rust-lang/rust/issues/59359
-        let pos = input.seek(SeekFrom::Current(0))?;
-        let len = input.seek(SeekFrom::End(0))?;
-        input.seek(SeekFrom::Start(pos))?;
-        self.compresstimate_len(&mut input, len - pos)
+/// The result of `compare_first_block`: a normal sampled estimate, and the
+/// ratio achieved by the first block alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirstBlockComparison {
+    /// The estimate from sampling as normal
+    pub sampled: Estimate,
+    /// The ratio of the first block compressed on its own
+    pub first_block: f32,
+}
+
+impl FirstBlockComparison {
+    /// The absolute difference between the sampled and first-block ratios.
+    pub fn divergence(&self) -> f32 {
+        (self.sampled.ratio() - self.first_block).abs()
     }
+}
 
-    /// Compresstimate up to `len` bytes from the seekable `input` stream,
-    /// returning an estimated compression ratio (currently based on lz4 level 1).
-    pub fn compresstimate_len<P: Read + Seek>(&self, mut input: P, len: u64) -> io::Result<f32> {
-        let output = WriteCount::default();
-        let mut encoder = EncoderBuilder::new().level(1).build(output)?;
+/// One codec's result from `compresstimate_codecs`: the estimate it gives,
+/// and how long it spent compressing the samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodecEstimate {
+    /// The estimate from this codec's compressed size
+    pub estimate: Estimate,
+    /// Time spent handing the samples to the codec and finishing its output
+    pub compress_time: Duration,
+}
 
-        let blocks = len / self.block_size;
-        let samples = sample_size(blocks, self.error_margin, self.confidence) as u64;
-        let written;
+impl CodecEstimate {
+    /// The sampled compression ratio, between 0 and 1.
+    pub fn ratio(&self) -> f32 {
+        self.estimate.ratio()
+    }
 
-        // If we're going to be randomly sampling a big chunk of the file anyway,
-        // we might as well read in the lot.
-        if samples == 0 || len < samples * self.block_size * 4 {
-            written = std::io::copy(&mut input.take(len), &mut encoder)?;
-        } else {
-            let step = self.block_size * (blocks / samples);
+    /// Sampled bytes compressed per second, or infinity if too fast to time.
+    pub fn throughput(&self) -> f64 {
+        self.estimate.sampled_original as f64 / self.compress_time.as_secs_f64()
+    }
+}
+
+/// The result of `compresstimate_records_sorted`: the ratio of the records
+/// as given, and sorted by a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortComparison {
+    /// The ratio of the records in their original order
+    pub as_is: f32,
+    /// The ratio of the records once sorted
+    pub sorted: f32,
+}
+
+impl SortComparison {
+    /// How much smaller sorting would make the compressed records, as a
+    /// fraction of their unsorted compressed size.  Negative if sorting hurts.
+    pub fn gain(&self) -> f32 {
+        1.0 - self.sorted / self.as_is
+    }
+}
 
-            let mut buf = vec![0; self.block_size as usize];
-            written = self.block_size * samples;
+/// Where `base_truth_chunked` got to: more to do, or the final ratio.
+#[derive(Debug)]
+pub enum BaseTruthProgress<R> {
+    /// The input isn't finished; `resume` the state to carry on
+    Pending(BaseTruthState<R>),
+    /// The whole input was compressed, achieving this ratio
+    Done(f32),
+}
+
+/// An exhaustive compression started by `base_truth_chunked`, holding the
+/// input and the encoder between chunks.
+pub struct BaseTruthState<R> {
+    input: R,
+    encoder: Compressor<'static>,
+    buf: Vec<u8>,
+    chunk_len: u64,
+    written: u64,
+}
+
+impl<R> fmt::Debug for BaseTruthState<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BaseTruthState")
+            .field("chunk_len", &self.chunk_len)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl<R: Read> BaseTruthState<R> {
+    /// Compress the next chunk of the input.
+    pub fn resume(mut self) -> io::Result<BaseTruthProgress<R>> {
+        let mut read = 0;
 
-            for i in 0..samples {
-                input.seek(SeekFrom::Start(step * i))?;
-                input.read_exact(&mut buf)?;
-                encoder.write_all(&buf)?;
+        while read < self.chunk_len {
+            let n = read_full(&mut self.input, &mut self.buf)?;
+            if n == 0 {
+                let compressed = self.encoder.finish()?;
+                let ratio = (compressed as f32 / self.written as f32).min(1.0);
+                return Ok(BaseTruthProgress::Done(ratio));
             }
+            self.encoder.write_all(&self.buf[..n])?;
+            self.written += n as u64;
+            read += n as u64;
         }
 
-        let (output, result) = encoder.finish();
-        result.map(|_| (output.written as f32 / written as f32).min(1.0))
+        Ok(BaseTruthProgress::Pending(self))
     }
 
-    /// Compresstimate the first `len` bytes of the file located at `path`.
-    ///
-    /// If the file is shorter than `len`, this function may fail with a seek error.
-    pub fn compresstimate_file_len<P: AsRef<Path>>(&self, path: P, len: u64) -> io::Result<f32> {
-        self.compresstimate_len(File::open(path)?, len)
+    /// Bytes read and compressed so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.written
     }
+}
 
-    /// Compresstimate the file located at `path`.
-    pub fn compresstimate_file<P: AsRef<Path>>(&self, path: P) -> io::Result<f32> {
-        self.compresstimate(File::open(path)?)
+/// What `recommend` suggests doing with an input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageAction {
+    /// Compress it, expecting it to shrink to `estimated_ratio` of its size
+    Compress {
+        /// The codec to compress with
+        codec: Codec,
+        /// The estimated compressed/original ratio with that codec
+        estimated_ratio: f32,
+    },
+    /// Store it as it is
+    StoreRaw {
+        /// Why compressing isn't worth it
+        reason: RawReason,
+    },
+}
+
+/// Why `recommend` suggests storing an input raw.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawReason {
+    /// It's shorter than `RecommendPolicy::min_len`, so wasn't estimated
+    TooSmall,
+    /// It wouldn't shrink below `RecommendPolicy::max_ratio`
+    Incompressible {
+        /// The estimated compressed/original ratio
+        estimated_ratio: f32,
+    },
+}
+
+/// The thresholds and codec `recommend_with` decides by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecommendPolicy {
+    /// Compress only if the estimated ratio is below this
+    pub max_ratio: f32,
+    /// Store anything shorter than this raw without estimating it
+    pub min_len: u64,
+    /// The codec to estimate with and recommend, or the estimator's own if
+    /// `None`
+    pub codec: Option<Codec>,
+}
+
+impl Default for RecommendPolicy {
+    /// Compress anything which would shrink below 0.9 with the estimator's
+    /// codec, however small.
+    fn default() -> Self {
+        Self {
+            max_ratio: 0.9,
+            min_len: 0,
+            codec: None,
+        }
     }
 }
 
-#[test]
-fn amazing_test_suite() {
-    let est = Compresstimator::default();
+/// The result of `compresstimate_decompressed`: an estimate of the
+/// decompressed content, and what it was decompressed from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecompressedEstimate {
+    /// The format the input was found to be in
+    pub format: InputFormat,
+    /// The estimate of its decompressed content, with `total_original` the
+    /// decompressed length
+    pub estimate: Estimate,
+}
 
-    assert!(est.compresstimate_file("Cargo.lock").expect("Cargo.lock") < 1.0);
+/// The result of `compresstimate_dedup`: a normal sampled estimate, and the
+/// fraction of sampled blocks which duplicated an earlier one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DedupEstimate {
+    /// The estimate from sampling as normal
+    pub sampled: Estimate,
+    /// Sampled blocks identical to one sampled before them, between 0 and 1
+    pub duplicate_fraction: f32,
+}
 
-    let empty = vec![];
-    assert!(
-        est.compresstimate(std::io::Cursor::new(empty))
-            .expect("empty should work")
-            == 1.0
-    );
+impl DedupEstimate {
+    /// The expected size after deduplicating and then compressing, relative to
+    /// the original, assuming duplicates compress like everything else.
+    pub fn combined_ratio(&self) -> f32 {
+        self.sampled.ratio() * (1.0 - self.duplicate_fraction)
+    }
+}
 
-    if std::path::PathBuf::from("/dev/urandom").exists() {
-        assert!(
-            est.compresstimate_file_len("/dev/urandom", 1024 * 1024)
-                .expect("/dev/urandom")
-                >= 1.0
-        );
+/// The chi-square statistic over sampled bytes at or below which
+/// `compresstimate_randomness` takes incompressible data to be random: the
+/// critical value for 255 degrees of freedom at a significance of 0.001.
+pub const RANDOM_CHI_SQUARE: f64 = 330.52;
+
+/// What kind of data `compresstimate_randomness` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataClass {
+    /// Incompressible, with bytes indistinguishable from uniformly random,
+    /// like encrypted data
+    Random,
+    /// Incompressible, but with bytes unevenly distributed, like data already
+    /// compressed in some structured format
+    Incompressible,
+    /// Compressible, with a ratio below `INCOMPRESSIBLE_RATIO`
+    Compressible,
+}
+
+/// The result of `compresstimate_randomness`: a normal sampled estimate,
+/// statistics on the distribution of sampled bytes, and what they suggest the
+/// data is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RandomnessEstimate {
+    /// The estimate from sampling as normal
+    pub sampled: Estimate,
+    /// The Shannon entropy of sampled bytes, in bits per byte from 0 to 8
+    pub entropy: f64,
+    /// Pearson's chi-square statistic of sampled bytes against a uniform
+    /// distribution, near 255 for random data and larger the less even it is
+    pub chi_square: f64,
+    /// The class of data this suggests
+    pub class: DataClass,
+}
+
+/// The result of `compresstimate_with_quality`: a normal sampled estimate,
+/// and a score for how far to trust it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityEstimate {
+    /// The estimate from sampling as normal
+    pub sampled: Estimate,
+    /// How trustworthy the estimate is, from 0 for nothing sampled to 1 for
+    /// an input read in full
+    pub quality: f32,
+    /// Blocks compressed
+    pub samples: u64,
+    /// Whether the input was read in full rather than sampled
+    pub full_read: bool,
+}
+
+/// The result of `compresstimate_or_exhaustive`: which way the ratio was
+/// found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FallbackEstimate {
+    /// The sampled estimate was trustworthy enough to use
+    Sampled(QualityEstimate),
+    /// The sampled estimate wasn't, so the whole file was compressed
+    Exhaustive {
+        /// The sampled estimate which fell short
+        sampled: QualityEstimate,
+        /// The ratio achieved compressing the whole file
+        ratio: f32,
+    },
+}
+
+impl FallbackEstimate {
+    /// The ratio found, exhaustively if it came to that.
+    pub fn ratio(&self) -> f32 {
+        match self {
+            Self::Sampled(estimate) => estimate.sampled.ratio(),
+            Self::Exhaustive { ratio, .. } => *ratio,
+        }
+    }
+}
+
+/// The result of `compresstimate_bootstrap`: a normal sampled estimate, and
+/// the standard error of its ratio found by bootstrapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootstrapEstimate {
+    /// The estimate from sampling as normal
+    pub sampled: Estimate,
+    /// The bootstrapped standard error of the mean per-block ratio
+    pub standard_error: f32,
+}
+
+/// What the classifier given to `compresstimate_file_classified` wants done
+/// with a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SampleParams {
+    /// Estimate with the estimator's own configuration
+    Default,
+    /// Estimate with this configuration instead, such as a larger block size
+    /// for logs or a higher confidence level for databases
+    With(Config),
+    /// Don't estimate it at all, such as for already-compressed formats
+    Skip,
+}
+
+/// The result of `compresstimate_file_classified`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClassifiedEstimate {
+    /// The file was estimated
+    Estimated(Estimate),
+    /// The classifier asked for the file to be skipped
+    Skipped,
+}
+
+/// The result of `compresstimate_tree_sampled`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeEstimate {
+    /// Files found in the tree
+    pub files_found: u64,
+    /// Files selected and successfully estimated
+    pub files_sampled: u64,
+    /// Files selected but which couldn't be estimated
+    pub files_failed: u64,
+    /// Directories which couldn't be read, so any files in them weren't found
+    pub dirs_failed: u64,
+    /// Total length of the sampled files
+    pub total_original: u64,
+    /// The ratio of the sampled files together, weighted by their size as
+    /// with `Weighting::ByOriginal`
+    pub ratio: f32,
+}
+
+/// The result of `compresstimate_tree_diff`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeDiffEstimate {
+    /// Files new or changed since the old tree, estimated successfully
+    pub files_changed: u64,
+    /// Changed files which couldn't be estimated, plus directories which
+    /// couldn't be read
+    pub files_failed: u64,
+    /// Total length of the changed files
+    pub changed_bytes: u64,
+    /// The ratio of the changed files together, weighted by their size as
+    /// with `Weighting::ByOriginal`
+    pub ratio: f32,
+}
+
+/// Overrides for a single estimate.
+#[derive(Debug, Default)]
+struct Plan<'a> {
+    /// Take this many samples, rather than working out how many are needed
+    samples: Option<u64>,
+    /// Sample blocks at exactly these offsets, rather than spreading them out
+    offsets: Option<&'a [u64]>,
+    /// Give up early once the extrapolated compressed size is certain to
+    /// exceed this
+    stop_above: Option<u64>,
+    /// Apply the configured trim to the input
+    trim: bool,
+    /// The file being read, if it is one, for its extents
+    file: Option<&'a File>,
+    /// Batch samples from `file` into vectored reads, reading through the gaps
+    /// between them
+    batch: bool,
+    /// Sample what's there if the input turns out shorter than its length,
+    /// rather than failing
+    tolerate_short: bool,
+    /// Where to send the compressed output, as well as counting it
+    sink: Option<Sink<'a>>,
+    /// Prime compressors with this rather than the estimator's dictionary
+    dictionary: Option<&'a [u8]>,
+    /// Where to send running `(bytes, ratio)` figures as blocks are compressed
+    updates: Option<&'a Sender<(u64, f32)>>,
+    /// Charge every byte read to this, failing once it runs out
+    budget: Option<&'a ByteBudget>,
+    /// The compressed size when `updates` last heard of it, starting from
+    /// whatever header the codec writes up front
+    reported: u64,
+}
+
+/// A writer for `Plan::sink`.
+struct Sink<'a>(&'a mut dyn Write);
+
+impl fmt::Debug for Sink<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sink")
+    }
+}
+
+impl<'a> Plan<'a> {
+    /// This plan, priming compressors with `dictionary` if it's given.
+    fn primed<'b>(self, dictionary: Option<&'b [u8]>) -> Plan<'b>
+    where
+        'a: 'b,
+    {
+        Plan {
+            samples: self.samples,
+            offsets: self.offsets,
+            stop_above: self.stop_above,
+            trim: self.trim,
+            file: self.file,
+            batch: self.batch,
+            tolerate_short: self.tolerate_short,
+            sink: self.sink.map(|Sink(sink)| Sink(sink)),
+            updates: self.updates,
+            budget: self.budget,
+            reported: self.reported,
+            dictionary: dictionary.or(self.dictionary),
+        }
+    }
+
+    /// Whether `compressed` bytes so far, out of `planned` bytes to read from
+    /// `len`, already guarantee going over `stop_above`.  Output only grows,
+    /// so the final ratio is at least what's been emitted so far over
+    /// everything to be read.
+    fn stopping(&self, compressed: u64, planned: u64, len: u64) -> bool {
+        self.stop_above.is_some_and(|limit| {
+            planned > 0 && compressed as f64 * len as f64 / planned as f64 > limit as f64
+        })
+    }
+
+    /// Charge `bytes` read to the budget, if there is one, failing with
+    /// `BudgetExhausted` once it runs out.
+    fn charge(&self, bytes: u64) -> io::Result<()> {
+        match self.budget {
+            Some(budget) if !budget.take(bytes) => Err(io::Error::other(BudgetExhausted)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send the running ratio after `processed` bytes, of which `written`
+    /// were compressed to `compressed` so far, if that's grown since last
+    /// time.  A receiver which has gone away is ignored.
+    fn update(&mut self, processed: u64, written: u64, compressed: u64) {
+        if let Some(updates) = self.updates.filter(|_| compressed > self.reported) {
+            self.reported = compressed;
+            let ratio = (compressed as f32 / written as f32).min(1.0);
+            let _ = updates.send((processed, ratio));
+        }
+    }
+}
+
+/// A limit on the bytes read across a batch of `compresstimate_file_budgeted`
+/// calls, for a predictable bound on the I/O of a scheduled scan.
+///
+/// It can be shared between threads estimating in parallel.  Once exhausted,
+/// every later file is left unestimated.
+#[derive(Debug, Default)]
+pub struct ByteBudget {
+    remaining: AtomicU64,
+}
+
+impl ByteBudget {
+    /// A budget of `bytes` in total.
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            remaining: AtomicU64::new(bytes),
+        }
+    }
+
+    /// The bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        self.remaining.load(Ordering::Relaxed)
+    }
+
+    /// Whether there's nothing left to read.
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Take `bytes` from the budget, or exhaust it if there isn't enough left.
+    fn take(&self, bytes: u64) -> bool {
+        let prev = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            })
+            .expect("always updates");
+        prev >= bytes
+    }
+}
+
+/// The error failing an estimate whose `Plan::budget` ran out.
+#[derive(Debug)]
+struct BudgetExhausted;
+
+impl fmt::Display for BudgetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("byte budget exhausted")
+    }
+}
+
+impl std::error::Error for BudgetExhausted {}
+
+/// How `compresstimate_file_retry` should retry transient errors.
+///
+/// Only `Interrupted`, `TimedOut` and `WouldBlock` errors are retried - anything
+/// else, like `NotFound` or `PermissionDenied`, is returned immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts to make, including the first
+    pub max_attempts: u32,
+    /// How long to wait before the first retry, doubling for each one after
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, waiting 100ms and then 200ms between them.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_transient(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        )
+    }
+
+    fn run<T, F: FnMut() -> io::Result<T>>(&self, mut f: F) -> io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match f() {
+                Err(ref e) if attempt < self.max_attempts && Self::is_transient(e) => {
+                    debug!("attempt {} failed, retrying: {}", attempt, e);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 }
+
+/// A compression estimator, configured with a block size, margin of error and
+/// confidence level for sampling, a codec, and assorted other settings.
+///
+/// Setters take and return `&mut Self`, so they can be chained.  The block
+/// size, margin and confidence can be read back with the getters of the same
+/// names, and everything else through `config`.
+///
+/// ```no_run
+/// use compresstimator::{Compresstimator, Confidence};
+///
+/// let mut est = Compresstimator::default();
+/// est.set_block_size(8192).set_confidence(Confidence::C99);
+/// match est.compresstimate_file("big_file.dat") {
+///     Ok(ratio) => println!("Compression ratio: {}", ratio),
+///     Err(e) => eprintln!("IO Error: {}", e)
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compresstimator {
+    config: Config,
+    dictionary: Option<Arc<[u8]>>,
+    transform: Option<Transform>,
+}
+
+/// A function transforming a block in place before it's compressed, for
+/// `Compresstimator::with_transform`.
+pub type BlockTransform = dyn Fn(&mut [u8]) + Send + Sync;
+
+#[derive(Clone)]
+enum Transform {
+    Shuffle(usize),
+    Custom(Arc<BlockTransform>),
+}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Shuffle(element_size) => write!(f, "Shuffle({})", element_size),
+            Transform::Custom(_) => f.write_str("Transform"),
+        }
+    }
+}
+
+impl Default for Compresstimator {
+    /// Create a `Compresstimator` with a default block size of 4096 bytes,
+    /// 10% margin of error, and 95% confidence level.
+    fn default() -> Self {
+        Self {
+            config: Config::default(),
+            dictionary: None,
+            transform: None,
+        }
+    }
+}
+
+const BASE_TRUTH_MIN_READ: u64 = 1024 * 1024;
+const FULL_READ_CHUNK: usize = 64 * 1024;
+
+/// A zeroed buffer, aligned for direct I/O if need be.
+struct AlignedBuf {
+    storage: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize, direct: bool) -> Self {
+        if !direct {
+            return Self {
+                storage: vec![0; len],
+                start: 0,
+                len,
+            };
+        }
+
+        let storage = vec![0; len + sys::DIRECT_IO_ALIGN];
+        let start = storage.as_ptr().align_offset(sys::DIRECT_IO_ALIGN);
+        Self {
+            storage,
+            start,
+            len,
+        }
+    }
+}
+
+impl std::ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage[self.start..self.start + self.len]
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[self.start..self.start + self.len]
+    }
+}
+
+/// The most sampled blocks to read in one vectored read.
+const MAX_BATCH_BLOCKS: usize = 32;
+
+/// The biggest gap between sampled blocks to read through rather than start a
+/// new vectored read.
+const MAX_BATCH_GAP: u64 = 64 * 1024;
+
+/// The most bytes a vectored read may read through and discard in total.
+const MAX_BATCH_SKIP: usize = 256 * 1024;
+
+/// The most sampled bytes to read in one vectored read, so big blocks make for
+/// smaller batches.
+const MAX_BATCH_BYTES: u64 = 2 * 1024 * 1024;
+
+/// The most blocks of `block_size` to read in one vectored read.
+fn max_batch_blocks(block_size: u64) -> usize {
+    (MAX_BATCH_BYTES / block_size).clamp(1, MAX_BATCH_BLOCKS as u64) as usize
+}
+
+/// How many of the blocks at `offsets` to read together in one vectored read.
+///
+/// Each following block joins the batch if it starts no more than
+/// `MAX_BATCH_GAP` after the last one ends, up to `MAX_BATCH_BLOCKS` blocks or
+/// `MAX_BATCH_BYTES` of them, and `MAX_BATCH_SKIP` bytes read through in
+/// total.  The gaps are read into a scratch buffer and thrown away: on
+/// high-latency storage reading a little more costs less than another
+/// request.
+fn batch_len(offsets: &[u64], block_size: u64) -> usize {
+    let mut n = 1;
+    let mut skipped = 0;
+
+    while n < offsets.len().min(max_batch_blocks(block_size)) {
+        match offsets[n].checked_sub(offsets[n - 1] + block_size) {
+            Some(gap) if gap <= MAX_BATCH_GAP && skipped + gap as usize <= MAX_BATCH_SKIP => {
+                skipped += gap as usize;
+                n += 1;
+            }
+            _ => break,
+        }
+    }
+
+    n
+}
+
+/// Read the blocks at `offsets` from `file` into consecutive blocks of `buf`
+/// with `preadv`, reading the gaps between them into `skip`.  Returns false
+/// if the file ended first.
+fn read_batch(file: &File, offsets: &[u64], buf: &mut [u8], skip: &mut [u8]) -> io::Result<bool> {
+    let block_size = buf.len() / offsets.len();
+    let mut slices = Vec::with_capacity(offsets.len() * 2);
+    let mut skip = skip;
+
+    for (i, block) in buf.chunks_mut(block_size).enumerate() {
+        if i > 0 {
+            let gap = (offsets[i] - offsets[i - 1]) as usize - block_size;
+            let (gap, rest) = std::mem::take(&mut skip).split_at_mut(gap);
+            skip = rest;
+            if !gap.is_empty() {
+                slices.push(IoSliceMut::new(gap));
+            }
+        }
+        slices.push(IoSliceMut::new(block));
+    }
+
+    let mut slices = &mut slices[..];
+    let mut offset = offsets[0];
+    while !slices.is_empty() {
+        match sys::preadv(file, slices, offset) {
+            Ok(0) => return Ok(false),
+            Ok(n) => {
+                offset += n as u64;
+                IoSliceMut::advance_slices(&mut slices, n);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+/// The shortest run of a padding byte that `with_strip_padding` strips.
+const PADDING_MIN_RUN: usize = 16;
+
+/// Remove runs of at least `PADDING_MIN_RUN` `byte`s from `block`, moving what
+/// remains to the front and returning its length.
+fn strip_padding(block: &mut [u8], byte: u8) -> usize {
+    let (mut read, mut kept) = (0, 0);
+    while read < block.len() {
+        let run = block[read..].iter().take_while(|&&b| b == byte).count();
+        if run >= PADDING_MIN_RUN {
+            read += run;
+            continue;
+        }
+
+        // Keep the short run, and whatever follows up to the next padding
+        let end = read
+            + run
+            + block[read + run..]
+                .iter()
+                .position(|&b| b == byte)
+                .unwrap_or(block.len() - read - run);
+        block.copy_within(read..end, kept);
+        kept += end - read;
+        read = end;
+    }
+    kept
+}
+
+/// Read `expected.len()` bytes at `offset` again into `scratch`, which may be
+/// longer for the sake of direct I/O, failing if they aren't the same.
+fn verify_read<R: Read + Seek>(
+    input: &mut R,
+    offset: u64,
+    expected: &[u8],
+    scratch: &mut [u8],
+) -> io::Result<()> {
+    input.seek(SeekFrom::Start(offset))?;
+    let n = read_full(input, scratch)?;
+    if n < expected.len() || scratch[..expected.len()] != *expected {
+        return Err(CompresstimatorError::ReadMismatch {
+            offset,
+            len: expected.len() as u64,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Read into `buf` until it's full or we hit EOF, returning the bytes read.
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(r) => n += r,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+impl Compresstimator {
+    /// The block size used unless one is given: 4096 bytes.
+    pub const DEFAULT_BLOCK_SIZE: u64 = config::DEFAULT_BLOCK_SIZE;
+
+    /// The margin of error used unless one is given: 10%.
+    pub const DEFAULT_ERROR_MARGIN: f32 = config::DEFAULT_ERROR_MARGIN;
+
+    /// The confidence level used unless one is given: 95%.
+    pub const DEFAULT_CONFIDENCE: Confidence = config::DEFAULT_CONFIDENCE;
+
+    /// Alias for `default()`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A profile favouring speed: 16KiB blocks, with a 15% margin of error at
+    /// 90% confidence.  Only around 30 blocks are sampled from large inputs.
+    pub fn quick() -> Self {
+        Self {
+            config: Config {
+                block_size: 16 * 1024,
+                error_margin: 0.15,
+                confidence: Confidence::C90,
+                ..Config::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// A profile favouring accuracy: 4KiB blocks, with a 5% margin of error at
+    /// 99% confidence.  Up to around 660 blocks are sampled from large inputs.
+    pub fn thorough() -> Self {
+        Self {
+            config: Config {
+                block_size: 4096,
+                error_margin: 0.05,
+                confidence: Confidence::C99,
+                ..Config::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Use a given block size for compresstimation.  This should be some reasonable
+    /// multiple of the underlying filesystem block size.
+    ///
+    /// An input shorter than a block has nothing to sample, so it's read and
+    /// compressed in full, as with any input too small to be worth sampling.
+    pub fn with_block_size(block_size: u64) -> Self {
+        Self {
+            config: Config {
+                block_size,
+                adaptive_block_size: false,
+                ..Config::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// The configured block size, before any adaptive growth.
+    pub fn get_block_size(&self) -> u64 {
+        self.config.block_size
+    }
+
+    /// The configured margin of error.
+    pub fn get_error_margin(&self) -> f32 {
+        self.config.error_margin
+    }
+
+    /// The configured confidence level.
+    pub fn get_confidence(&self) -> Confidence {
+        self.config.confidence
+    }
+
+    /// Use a given block size for compresstimation.  This should be some reasonable
+    /// multiple of the underlying filesystem block size.
+    ///
+    /// An input shorter than a block has nothing to sample, so it's read and
+    /// compressed in full, as with any input too small to be worth sampling.
+    ///
+    /// This turns off `adaptive_block_size`.
+    pub fn set_block_size(&mut self, block_size: u64) -> &mut Self {
+        self.config.block_size = block_size;
+        self.config.adaptive_block_size = false;
+        self
+    }
+
+    /// Use a given block size for compresstimation, as with `set_block_size`.
+    #[deprecated(note = "use `set_block_size`")]
+    pub fn block_size(&mut self, block_size: u64) -> &mut Self {
+        self.set_block_size(block_size)
+    }
+
+    /// Grow the block size for large inputs, so there are fewer seeks and each
+    /// sample captures more context.  On by default, unless the block size was
+    /// given with `set_block_size` or `with_block_size`.
+    ///
+    /// The block size grows in powers of two to keep inputs at least 65536
+    /// blocks long, up to 1MiB blocks: 4KiB blocks serve anything up to 512MiB,
+    /// 16KiB up to 2GiB, and 1MiB from 64GiB.  It never shrinks below the
+    /// configured size.  With about the same number of blocks sampled, bigger
+    /// blocks mean more bytes read - around 100MiB rather than 400KiB for a huge
+    /// input with the default margin of error.
+    pub fn adaptive_block_size(&mut self, adaptive: bool) -> &mut Self {
+        self.config.adaptive_block_size = adaptive;
+        self
+    }
+
+    /// Set the margin of error for the compressibility check.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the error margin is not between 0 and 1.
+    pub fn set_error_margin(&mut self, margin: f32) -> &mut Self {
+        assert!(margin > 0.0 && margin < 1.0);
+        self.config.error_margin = margin;
+        self
+    }
+
+    /// Set the margin of error, as with `set_error_margin`.
+    #[deprecated(note = "use `set_error_margin`")]
+    pub fn error_margin(&mut self, margin: f32) -> &mut Self {
+        self.set_error_margin(margin)
+    }
+
+    /// Set the confidence level of the compressibility check.
+    pub fn set_confidence(&mut self, confidence: Confidence) -> &mut Self {
+        self.config.confidence = confidence;
+        self
+    }
+
+    /// Set the confidence level, as with `set_confidence`.
+    #[deprecated(note = "use `set_confidence`")]
+    pub fn confidence_level(&mut self, confidence: Confidence) -> &mut Self {
+        self.set_confidence(confidence)
+    }
+
+    /// Choose where samples are taken from: evenly spaced, the default, at
+    /// random, or weighted towards a region.
+    ///
+    /// A fixed stride can alias with data laid out in a period which divides
+    /// it, such as fixed-size records with a compressible header, sampling the
+    /// same part of every period and badly skewing the estimate.  Random
+    /// sampling avoids that at the cost of a little more variance, and of
+    /// reads which are no longer evenly spaced.  Blocks are still whole blocks
+    /// from the start of the input, read in ascending order.
+    ///
+    /// `SampleStrategy::Region` steers sampling towards a part of the input
+    /// known to matter more, such as recently appended data, while still
+    /// covering the rest lightly.  The usual number of samples is shared out
+    /// in proportion to each part's blocks, with each block in the region
+    /// counting `weight` times: a region of an eighth of the input with a
+    /// weight of 7 gets half of them.  At least one goes to the rest, if
+    /// there's any and more than one sample, and neither part takes more
+    /// samples than it has blocks, handing the excess to the other.  The
+    /// estimate is deliberately biased the same way, with every byte in the
+    /// region counting `weight` times as much as one outside it; a weight of
+    /// 1 samples about as `Uniform` does, and 0 leaves the region out.
+    /// The region is in bytes from the start of what's sampled, after any
+    /// trimmed head or prefix dictionary, and is clipped to the input.  Inputs
+    /// small enough to read in full are read in full regardless.
+    pub fn sample_strategy(&mut self, strategy: SampleStrategy) -> &mut Self {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// Round each sampled offset down to a multiple of the block size, relative
+    /// to the start of the stream rather than the position sampling began at.
+    ///
+    /// When the block size matches (or is a multiple of) the filesystem block
+    /// size, this avoids each sampled read straddling two physical blocks.  The
+    /// cost is a slight bias in which bytes get sampled, since blocks are no
+    /// longer evenly spaced from the starting position.  Off by default.
+    pub fn align_samples(&mut self, align: bool) -> &mut Self {
+        self.config.align_samples = align;
+        self
+    }
+
+    /// Sample whole lines of newline-delimited text, so partial lines don't
+    /// skew the estimate and the blocks seen by hooks like
+    /// `compresstimate_len_hashed` can be grepped.
+    ///
+    /// Each sampled block starts after its first newline and is extended past
+    /// its end to the next one, found by reading up to one more block.  That
+    /// doubles the I/O of sampling, though the second read is usually
+    /// sequential.  Blocks without a newline, and lines longer than a block,
+    /// are cut at a block boundary as usual.  Off by default.
+    pub fn align_lines(&mut self, align: bool) -> &mut Self {
+        self.config.align_lines = align;
+        self
+    }
+
+    /// Always read and compress the full length of the input rather than
+    /// sampling it, making estimates directly comparable with `base_truth`
+    /// while keeping the same API.  Off by default.
+    pub fn force_exhaustive(&mut self, exhaustive: bool) -> &mut Self {
+        self.config.force_exhaustive = exhaustive;
+        self
+    }
+
+    /// Set the filesystem's minimum allocation unit, used to round up
+    /// `Estimate::estimated_allocated_len`.  For many small files this
+    /// overhead can matter more than how well their contents compress.
+    ///
+    /// Defaults to 1, i.e. no rounding.  Only affects detailed estimates.
+    pub fn min_allocation_unit(&mut self, unit: u64) -> &mut Self {
+        self.config.min_allocation_unit = unit;
+        self
+    }
+
+    /// Count `bytes` of container around each object, such as an archive
+    /// format's per-entry header and footer, towards
+    /// `Estimate::estimated_allocated_len`.
+    ///
+    /// It's added to the extrapolated compressed size before rounding to the
+    /// allocation unit, once per estimate, so once per file.  The ratio and
+    /// `extrapolated_compressed` still describe the content alone; the cost
+    /// of storing it, overhead included, is `Estimate::stored_ratio`, which
+    /// for many small objects may show there's little to save.  Defaults to
+    /// 0.  Only affects detailed estimates.
+    pub fn container_overhead(&mut self, bytes: u64) -> &mut Self {
+        self.config.container_overhead = bytes;
+        self
+    }
+
+    /// Stop sampling an input once its first `exit.blocks` sampled blocks each
+    /// compress to at least `exit.threshold` of their size on their own,
+    /// returning the estimate from those, which will be around 1.0.  This saves
+    /// most of the work of sampling media and archives.  Off by default.
+    ///
+    /// Only a streak from the first sampled block counts: once one compresses
+    /// better, the rest are sampled as normal.  While the streak lasts each
+    /// block is compressed twice, which is cheap with lz4 but starts a process
+    /// per block with an external codec.  Inputs small enough to be read in
+    /// full are unaffected.
+    pub fn early_exit(&mut self, exit: Option<EarlyExit>) -> &mut Self {
+        self.config.early_exit = exit;
+        self
+    }
+
+    /// Set the compressor to estimate with.  Defaults to lz4 level 1.
+    pub fn codec(&mut self, codec: Codec) -> &mut Self {
+        self.config.codec = codec;
+        self
+    }
+
+    /// Limit the codec's window to at most 2^`log` bytes, bounding the memory
+    /// compressing takes, or `None` for no limit, the default.
+    ///
+    /// zstd's window options are lowered to fit, and if it has none but its
+    /// level implies a larger window, one is added, down to zstd's minimum
+    /// of 2^10.  lz4's 64KiB window is fixed, and other external commands
+    /// are run as given, since their options aren't understood.  A smaller
+    /// window finds fewer long-distance matches, so on data with repeats
+    /// further apart than it the estimate rises, but it stays an estimate of
+    /// what compressing within the limit would achieve, the same as
+    /// `base_truth` would find.  Windows in `compresstimate_window_sweep`
+    /// above the limit are clamped too.  `effective_window_log` says what's
+    /// actually used.
+    pub fn max_window_log(&mut self, log: Option<u32>) -> &mut Self {
+        self.config.max_window_log = log;
+        self
+    }
+
+    /// The log2 of the window the configured codec compresses with, after any
+    /// `max_window_log` limit, or `None` if it's an external command other
+    /// than zstd whose window isn't known.
+    pub fn effective_window_log(&self) -> Option<u32> {
+        self.clamped(&self.config.codec).window_log()
+    }
+
+    /// Leave the first `head` and last `tail` bytes of each input out of
+    /// estimates, so only the body between them is sampled - for container
+    /// formats whose fixed headers and footers would otherwise bias the result.
+    ///
+    /// The resulting `Estimate` describes the body alone, so its
+    /// `total_original` excludes the trimmed bytes.  An input no longer than
+    /// `head` and `tail` together has no body, and estimates as empty, with a
+    /// ratio of 1.  `base_truth` and `compresstimate_appended` ignore this.
+    pub fn with_trim(&mut self, head: u64, tail: u64) -> &mut Self {
+        self.config.trim_head = head;
+        self.config.trim_tail = tail;
+        self
+    }
+
+    /// Sample only the parts of each file which have data written to them,
+    /// spreading samples over them in proportion to their size, rather than
+    /// over the whole logical length.
+    ///
+    /// On thin-provisioned volumes and sparse files much of the logical size
+    /// may be holes, which cost nothing to store and read back as zeros, so
+    /// sampling them flatters the estimate.  This asks the filesystem for the
+    /// file's extents with `FIEMAP`, leaving out holes and preallocated
+    /// extents never written to, and the resulting `Estimate` describes only
+    /// that data: `total_original` is how much of it there is.  Samples are
+    /// whole blocks, so one small extent still reads a block.  It only
+    /// applies to files opened by path, like `compresstimate_file`, on Linux
+    /// filesystems supporting `FIEMAP`; elsewhere, or on other platforms,
+    /// the logical range is sampled as usual.
+    pub fn sample_extents(&mut self, extents: bool) -> &mut Self {
+        self.config.sample_extents = extents;
+        self
+    }
+
+    /// Compress in independent chunks of `chunk_size` bytes, starting a fresh
+    /// stream for each, or as one stream if it's zero, the default.
+    ///
+    /// This models storage which compresses fixed-size records on their own,
+    /// like ZFS and Btrfs, where redundancy between records goes unused and a
+    /// continuous stream would overstate the savings.  The ratio is then the
+    /// sum of every chunk's compressed size over their original size.  So
+    /// samples are made of whole chunks, the block size is rounded up to a
+    /// multiple of the chunk size, and chunks start from each sample's
+    /// offset, so use `align_samples` too to line them up with the records
+    /// themselves.  Each chunk is primed with any dictionary.
+    pub fn with_chunk_size(&mut self, chunk_size: u64) -> &mut Self {
+        self.config.chunk_size = chunk_size;
+        self
+    }
+
+    /// Leave runs of at least 16 `byte`s out of what's compressed, for inputs
+    /// padded with a filler which would otherwise flatter the estimate.
+    ///
+    /// This changes what the ratio represents: it's the compressibility of
+    /// the content alone, the compressed size of what's left over its own
+    /// size, and `sampled_original` counts only what's left.  The estimate is
+    /// still extrapolated over the whole input, as if it were all content.
+    /// Runs are found within each block, so one straddling two blocks is only
+    /// stripped where at least 16 of it fall in the same block.  Padding is
+    /// stripped before any transform, and hooks like
+    /// `compresstimate_len_hashed` see blocks as they were read.  Turn it off
+    /// again with `reconfigure`.
+    pub fn with_strip_padding(&mut self, byte: u8) -> &mut Self {
+        self.config.strip_padding = Some(byte);
+        self
+    }
+
+    /// Open files with `O_DIRECT`, so sampled reads bypass the page cache
+    /// rather than evicting hotter data from it.
+    ///
+    /// Direct I/O needs buffers, file offsets and read lengths aligned to the
+    /// device's logical block size.  Buffers are aligned to 4096 bytes, which
+    /// covers any device, and samples are aligned as with `align_samples`, so
+    /// the block size and any head trim must be multiples of 4096 - this is
+    /// checked by `validate` and `reconfigure`, and reads fail with
+    /// `EINVAL` otherwise.  Only files opened by path, such as with
+    /// `compresstimate_file`, are affected, and some filesystems, like older
+    /// tmpfs, refuse `O_DIRECT` entirely.
+    #[cfg(target_os = "linux")]
+    pub fn with_direct_io(&mut self, direct: bool) -> &mut Self {
+        self.config.direct_io = direct;
+        self
+    }
+
+    /// Read every block twice, failing with `CompresstimatorError::ReadMismatch`
+    /// if the two reads differ, to catch unstable reads from failing storage
+    /// rather than quietly estimating garbage.
+    ///
+    /// This doubles the reads.  The second is likely to come from the page
+    /// cache rather than the device unless paired with `with_direct_io`, so
+    /// on its own it mostly catches errors between the cache and us.  Blocks
+    /// are compared before `visit`-style hooks and transforms see them.
+    pub fn verify_reads(&mut self, verify: bool) -> &mut Self {
+        self.config.verify_reads = verify;
+        self
+    }
+
+    /// Prime every estimate with `dictionary`, as if the data were to be
+    /// compressed with it, or clear it with an empty `Vec`.
+    ///
+    /// lz4 can only make use of the last 64KiB.  Note that when sampling, the
+    /// first few sampled blocks benefit most, since later ones push the
+    /// dictionary out of the window - for a large file that's a reasonable
+    /// approximation, for a small one read in full it's exactly how it would
+    /// be compressed.  See `dictionary_from_samples` for building one from a
+    /// set of sample files.
+    ///
+    /// This is the dictionary as a fixed, external input shared across every
+    /// file estimated, as an archive storing a corpus against one dictionary
+    /// would use it - nothing is trained from the data being estimated.  Every
+    /// lz4 encoder the estimator builds is primed with it, including those
+    /// compressing single blocks and the ones behind `base_truth`.  External
+    /// codecs don't take one, and estimate as if without it.
+    pub fn dictionary(&mut self, dictionary: Vec<u8>) -> &mut Self {
+        self.dictionary = if dictionary.is_empty() {
+            None
+        } else {
+            Some(dictionary.into())
+        };
+        self
+    }
+
+    /// Use the first `prefix_len` bytes of each input as the dictionary for
+    /// sampling the rest, approximating the context the whole file would give
+    /// later blocks without training anything.  Zero, the default, turns it off.
+    ///
+    /// The prefix is an extra sequential read before sampling starts, and
+    /// isn't itself sampled: the estimate covers the remainder and is
+    /// extrapolated to the whole input.  It replaces any dictionary from
+    /// `dictionary`, and as with one, lz4 only makes use of the last
+    /// 64KiB and external codecs ignore it.  Inputs no longer than the prefix
+    /// are estimated as normal, as are those found to be once it's read, by
+    /// methods like `compresstimate_file_up_to` which tolerate a short input.
+    /// With direct I/O the prefix must be a multiple of 4096 bytes, like the
+    /// block size.
+    pub fn with_self_prefix_dictionary(&mut self, prefix_len: u64) -> &mut Self {
+        self.config.prefix_dictionary = prefix_len;
+        self
+    }
+
+    /// Run-length encode each block after any transform and before
+    /// compressing it, to estimate an RLE-then-compress pipeline, as suits
+    /// bitmaps and other images with long runs of the same byte.
+    ///
+    /// This changes what the ratio means: it's the compressed size of the
+    /// run-length encoded blocks against the size of the original ones.
+    /// Blocks are PackBits-encoded, as TIFF does, each on its own, so runs
+    /// don't carry across blocks.  Where runs are short or rare this costs a
+    /// byte in 128 before compression, so it's no help on other data.  As
+    /// with a transform, `base_truth` compresses the data as it is.
+    pub fn with_rle_preanalysis(&mut self, rle: bool) -> &mut Self {
+        self.config.rle_preanalysis = rle;
+        self
+    }
+
+    /// Apply `transform` to each block before compressing it, such as
+    /// delta-encoding or transposing numeric data, to estimate how well the
+    /// data would compress after that preprocessing.
+    ///
+    /// This changes what the ratio means: it's the compressed size of the
+    /// transformed blocks against the size of the original ones, so the
+    /// transform should preserve length.  It's applied to each block on its
+    /// own, a block at a time even when reading in full, so it can't carry
+    /// state across blocks.  `base_truth` compresses the data as it is, and
+    /// `visit`-style hooks like `compresstimate_len_hashed` see it untouched.
+    pub fn with_transform(&mut self, transform: Box<BlockTransform>) -> &mut Self {
+        self.transform = Some(Transform::Custom(transform.into()));
+        self
+    }
+
+    /// Byte-shuffle each block as arrays of `element_size`-byte values before
+    /// compressing it, as Blosc and HDF5's shuffle filter do, to estimate
+    /// shuffle-then-compress storage of numeric data like `f32` or `i64`.
+    ///
+    /// The first byte of every element is gathered together, then the
+    /// second, and so on, which puts the slowly-changing high bytes of nearby
+    /// values next to each other.  Any bytes left over after the last whole
+    /// element stay where they are.  This is a transform like any other, so
+    /// it replaces one from `with_transform`, and elements line up with the
+    /// data as long as the block size and any trimming are multiples of
+    /// `element_size`.
+    pub fn with_shuffle(&mut self, element_size: usize) -> &mut Self {
+        self.transform = Some(Transform::Shuffle(element_size));
+        self
+    }
+
+    /// The current configuration.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Check the configuration as `reconfigure` would, without estimating
+    /// anything - worthwhile before a long batch, since not every setter
+    /// validates what it's given.
+    pub fn validate(&self) -> Result<(), CompresstimatorError> {
+        self.config.validate()
+    }
+
+    /// Replace the whole configuration at once, provided it's valid.  On error
+    /// the existing configuration is left untouched, so there's no risk of a
+    /// partially-applied update.
+    ///
+    /// This takes `&mut self`, so it can't race with estimates running on the
+    /// same instance.  Services sharing an estimator across threads should hold
+    /// it behind a `RwLock`, or since a `Compresstimator` is cheap to clone,
+    /// swap in a reconfigured copy.  Estimates already running keep the
+    /// configuration they started with either way.
+    pub fn reconfigure(&mut self, config: Config) -> Result<(), CompresstimatorError> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Exhaustively compress the stream and return the achieved ratio.
+    pub fn base_truth<R: Read>(&self, input: R) -> io::Result<f32> {
+        self.base_truth_hashed(input, &mut io::sink())
+    }
+
+    /// Exhaustively compress the stream as with `base_truth`, additionally
+    /// writing every byte read to `hasher`.
+    ///
+    /// Any `Write` will do, which covers most digest implementations - a
+    /// `std::hash::Hasher` needs a small adapter.
+    pub fn base_truth_hashed<R: Read, H: Write>(
+        &self,
+        input: R,
+        hasher: &mut H,
+    ) -> io::Result<f32> {
+        let mut encoder = self.compressor(&self.config.codec)?;
+        let mut input = Tee::new(input, hasher);
+        let mut buf = vec![0; FULL_READ_CHUNK];
+        let mut written = 0;
+
+        // Feed the encoder in the same sized chunks as the full-read path of
+        // compresstimate, so the two give identical results.
+        loop {
+            let n = read_full(&mut input, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            encoder.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+
+        let compressed = encoder.finish()?;
+        Ok((compressed as f32 / written as f32).min(1.0))
+    }
+
+    /// Exhaustively compress the stream as with `base_truth`, but only the
+    /// first `chunk_len` bytes or so before returning, so a cooperative
+    /// scheduler can run other work in between.
+    ///
+    /// Each call, this one included, reads in the same 64KiB pieces as
+    /// `base_truth` until it has read at least `chunk_len` bytes, so chunks
+    /// are rounded up to a multiple of 64KiB, and a `chunk_len` of zero reads
+    /// one piece.  Pass the returned state to `BaseTruthState::resume` for
+    /// the next chunk until it's `Done`, with the same ratio `base_truth`
+    /// would give.  A blocking `input` still blocks within each chunk.
+    pub fn base_truth_chunked<R: Read>(
+        &self,
+        input: R,
+        chunk_len: u64,
+    ) -> io::Result<BaseTruthProgress<R>> {
+        BaseTruthState {
+            input,
+            encoder: self.compressor(&self.config.codec)?,
+            buf: vec![0; FULL_READ_CHUNK],
+            chunk_len: chunk_len.max(1),
+            written: 0,
+        }
+        .resume()
+    }
+
+    /// Compress the stream as with `base_truth`, but give up once the running
+    /// ratio exceeds `abort_if_above`, returning `None`.
+    ///
+    /// The input is read in the same 64KiB pieces as `base_truth`, so a
+    /// stream which isn't abandoned gives the same ratio.  At least 1MiB is
+    /// always read before giving up, so small amounts of leading
+    /// incompressible data (headers, indexes) don't trigger it, and after that
+    /// the check is made after every piece.  Note this is a heuristic: a file
+    /// which starts badly could still have come in under the threshold by the
+    /// end.
+    pub fn base_truth_until<R: Read>(
+        &self,
+        mut input: R,
+        abort_if_above: f32,
+    ) -> io::Result<Option<f32>> {
+        let mut encoder = self.compressor(&self.config.codec)?;
+        let mut buf = vec![0; FULL_READ_CHUNK];
+        let mut written = 0;
+
+        loop {
+            let n = read_full(&mut input, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            encoder.write_all(&buf[..n])?;
+            written += n as u64;
+
+            if written >= BASE_TRUTH_MIN_READ
+                && encoder.written() as f32 > abort_if_above * written as f32
+            {
+                return Ok(None);
+            }
+        }
+
+        let compressed = encoder.finish()?;
+        Ok(Some((compressed as f32 / written as f32).min(1.0)))
+    }
+
+    /// Compresstimate the seekable stream `input` from the current position to the
+    /// end, leaving it back at that position afterwards.
+    ///
+    /// This function determines the length of the stream by seeking to the end,
+    /// failing with `CompresstimatorError::InvalidStreamLength` if that reports
+    /// a position before the current one.  The position is restored even if
+    /// estimating fails, though not if seeking itself does.
+    ///
+    /// Positions are only ever set through `Seek`, so buffered readers like
+    /// `BufReader` stay consistent: their `stream_position` accounts for what
+    /// they've buffered, and seeking discards the buffer, so a `BufReader`
+    /// will refill it on the next read rather than returning stale data.
+    pub fn compresstimate<P: Read + Seek>(&self, mut input: P) -> io::Result<f32> {
+        // In future consider stream_len()
+        // https://github.com/rust-lang/rust/issues/59359
+        let position = input.stream_position()?;
+        let end = input.seek(SeekFrom::End(0))?;
+        input.seek(SeekFrom::Start(position))?;
+
+        if end < position {
+            return Err(CompresstimatorError::InvalidStreamLength { position, end }.into());
+        }
+
+        let result = self.compresstimate_len(&mut input, end - position);
+        let restored = input.seek(SeekFrom::Start(position));
+        let ratio = result?;
+        restored?;
+        Ok(ratio)
+    }
+
+    /// Compresstimate up to `len` bytes from the seekable `input` stream,
+    /// returning an estimated compression ratio using the configured codec (lz4
+    /// level 1 by default).
+    pub fn compresstimate_len<P: Read + Seek>(&self, input: P, len: u64) -> io::Result<f32> {
+        self.compresstimate_len_hashed(input, len, &mut io::sink())
+    }
+
+    /// Compresstimate the `len` bytes of memory starting at `ptr`, for callers
+    /// which already have the data mapped and don't want it copied.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads of `len` bytes, and the memory must not be
+    /// modified for the duration of the call.  It may be null if `len` is zero.
+    pub unsafe fn compresstimate_raw(&self, ptr: *const u8, len: usize) -> io::Result<f32> {
+        let data = if len == 0 {
+            &[][..]
+        } else if ptr.is_null() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "null pointer"));
+        } else {
+            std::slice::from_raw_parts(ptr, len)
+        };
+
+        self.compresstimate_len(io::Cursor::new(data), len as u64)
+    }
+
+    /// Compresstimate a set of discrete `records` as if they were concatenated
+    /// in order and compressed together, as a columnar store compresses a row
+    /// group.
+    ///
+    /// Record boundaries are flattened: records are fed through one encoder
+    /// back to back with nothing between them, so matches can span records.
+    /// When there are many, they're sampled as a stream would be, in runs of
+    /// consecutive records a block long, starting at even intervals.  The
+    /// last record of each run is cut off at the end of the block, so no more
+    /// than a block is read per sample however large the records are.  Each
+    /// record, or the part of one sampled, is stripped of padding,
+    /// transformed and run-length encoded as a block would be.
+    pub fn compresstimate_records(&self, records: &[&[u8]]) -> io::Result<f32> {
+        let total: u64 = records.iter().map(|r| r.len() as u64).sum();
+        let blocks = total / self.config.block_size;
+        let samples = sample_size(blocks, self.config.error_margin, self.config.confidence) as u64;
+
+        let mut encoder = self.compressor(&self.config.codec)?;
+        let mut written = 0;
+        // Each record, or the part of it sampled, is prepared as a block would be
+        let mut buf = (vec![], vec![]);
+        let mut write = |encoder: &mut Compressor<'_>, record: &[u8]| -> io::Result<()> {
+            let (scratch, packed) = &mut buf;
+            scratch.clear();
+            scratch.extend_from_slice(record);
+            let (kept, prepared) = self.prepare_in_place(scratch, packed);
+            encoder.write_all(prepared)?;
+            written += kept as u64;
+            Ok(())
+        };
+
+        if self.config.force_exhaustive || samples == 0 || blocks < samples * 4 {
+            for record in records {
+                write(&mut encoder, record)?;
+            }
+        } else {
+            let step = (records.len() / samples as usize).max(1);
+            for start in (0..records.len()).step_by(step).take(samples as usize) {
+                let mut run = 0;
+                for record in records[start..].iter().take(step) {
+                    if run >= self.config.block_size {
+                        break;
+                    }
+                    let take = (self.config.block_size - run).min(record.len() as u64);
+                    write(&mut encoder, &record[..take as usize])?;
+                    run += take;
+                }
+            }
+        }
+
+        let compressed = encoder.finish()?;
+        Ok(Estimate::new(written, compressed, written, 1, 0).ratio())
+    }
+
+    /// Compresstimate `records` as with `compresstimate_records`, both as given
+    /// and sorted by `key`, to see how much a sorted storage layout - such as
+    /// clustering rows by a column - would gain.
+    ///
+    /// All the records are sorted, not just the sampled ones, since sampling a
+    /// sorted sample would leave neighbours further apart than they really
+    /// are.  Only references are moved, but `key` is called once per record,
+    /// so for many records keep it cheap.  The sort is stable.
+    pub fn compresstimate_records_sorted<K, F>(
+        &self,
+        records: &[&[u8]],
+        mut key: F,
+    ) -> io::Result<SortComparison>
+    where
+        K: Ord,
+        F: FnMut(&[u8]) -> K,
+    {
+        let mut sorted = records.to_vec();
+        sorted.sort_by_cached_key(|record| key(record));
+
+        Ok(SortComparison {
+            as_is: self.compresstimate_records(records)?,
+            sorted: self.compresstimate_records(&sorted)?,
+        })
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with `compresstimate_len`,
+    /// additionally writing every byte read to `hasher`.
+    ///
+    /// Note that when the stream is sampled, the hash only covers the sampled
+    /// blocks, in the order they were read.  It is only a hash of the full
+    /// content if the stream was small enough to read in its entirety - use
+    /// `base_truth_hashed` if you always need that.
+    pub fn compresstimate_len_hashed<P: Read + Seek, H: Write>(
+        &self,
+        input: P,
+        len: u64,
+        hasher: &mut H,
+    ) -> io::Result<f32> {
+        self.estimate(input, len, &mut |_, block| hasher.write_all(block))
+            .map(|e| e.ratio())
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with `compresstimate_len`,
+    /// additionally writing the compressed output to `sink`.
+    ///
+    /// This is exactly what the ratio was measured from: with lz4, a single
+    /// frame decompressing to the sampled blocks in the order they were read,
+    /// after any transform, or all of the input if it was read in full, or a
+    /// frame per chunk with `with_chunk_size`.  A dictionary is written
+    /// through ahead of them, since the frame depends on it, though it isn't
+    /// counted.  External codecs send whatever their command outputs.  This
+    /// saves compressing a second time to keep what was compressed.
+    pub fn compresstimate_into<P: Read + Seek, W: Write>(
+        &self,
+        input: P,
+        len: u64,
+        mut sink: W,
+    ) -> io::Result<f32> {
+        let plan = Plan {
+            trim: true,
+            sink: Some(Sink(&mut sink)),
+            ..Plan::default()
+        };
+        let estimate = self.estimate_planned(input, len, plan, &mut |_, _| Ok(()))?;
+        sink.flush()?;
+        Ok(estimate.ratio())
+    }
+
+    /// Compresstimate the seekable `input` stream from a block at each of
+    /// `offsets`, from the start of the stream, rather than spreading samples
+    /// out evenly, for callers which know which regions are representative.
+    ///
+    /// Blocks are compressed together in the order given, with any codec,
+    /// dictionary and transform configured, but the block size is used as it
+    /// is, without adapting it or trimming the input.  A block at the end may
+    /// be short; an offset at or past the end fails with `InvalidInput`.  The
+    /// position is restored afterwards, as with `compresstimate`.  With no
+    /// offsets nothing is sampled, which counts as incompressible.
+    pub fn compresstimate_at_offsets<P: Read + Seek>(
+        &self,
+        mut input: P,
+        offsets: &[u64],
+    ) -> io::Result<f32> {
+        let position = input.stream_position()?;
+        let end = input.seek(SeekFrom::End(0))?;
+
+        if let Some(&offset) = offsets.iter().find(|&&offset| offset >= end) {
+            input.seek(SeekFrom::Start(position))?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is past the end of the input at {}", offset, end),
+            ));
+        }
+
+        input.seek(SeekFrom::Start(0))?;
+        // Offsets near the end can leave a short block
+        let plan = Plan {
+            offsets: Some(offsets),
+            tolerate_short: true,
+            ..Plan::default()
+        };
+        let result = self.estimate_inner(&mut input, end, plan, &mut |_, _| Ok(()));
+        let restored = input.seek(SeekFrom::Start(position));
+        let estimate = result?;
+        restored?;
+        Ok(estimate.ratio())
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with `compresstimate_len`,
+    /// returning the sampled and extrapolated sizes rather than just the ratio.
+    pub fn compresstimate_len_detailed<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<Estimate> {
+        self.estimate(input, len, &mut |_, _| Ok(()))
+    }
+
+    /// Update a `previous` estimate of `input` now that it's grown to `len`
+    /// bytes, sampling only the appended region rather than starting over.
+    ///
+    /// This assumes the first `previous.total_original` bytes are unchanged,
+    /// as with an append-only log - nothing before them is read again.  The
+    /// new region is sampled at the same density as the old one, so the
+    /// combined counts weight each region by its length.  `input` is read
+    /// from its start, not its current position.
+    ///
+    /// Returns an error if `len` is less than the previous length.
+    pub fn compresstimate_appended<P: Read + Seek>(
+        &self,
+        mut input: P,
+        previous: &Estimate,
+        len: u64,
+    ) -> io::Result<Estimate> {
+        if len < previous.total_original {
+            return Err(CompresstimatorError::InputShrank {
+                previous: previous.total_original,
+                len,
+            }
+            .into());
+        }
+
+        let appended = len - previous.total_original;
+        let samples = match previous.sampled_original {
+            0 => None,
+            sampled => {
+                let density = sampled as f64 / previous.total_original as f64;
+                let blocks = appended / self.config.block_size;
+                Some(((blocks as f64 * density).ceil() as u64).min(blocks))
+            }
+        };
+
+        input.seek(SeekFrom::Start(previous.total_original))?;
+        let plan = Plan {
+            samples,
+            ..Plan::default()
+        };
+        let tail = self.estimate_planned(input, appended, plan, &mut |_, _| Ok(()))?;
+
+        Ok(Estimate::new(
+            previous.sampled_original + tail.sampled_original,
+            previous.sampled_compressed + tail.sampled_compressed,
+            len,
+            self.config.min_allocation_unit,
+            self.config.container_overhead,
+        ))
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with
+    /// `compresstimate_len_detailed`, also hashing each block read to see how
+    /// many are duplicates.
+    ///
+    /// The dedup estimate is block-granular and approximate: only whole blocks
+    /// at the configured block size are compared, at their sampled offsets, so
+    /// duplicates which aren't block-aligned are missed.  When sampling rather
+    /// than reading everything, a duplicate is only seen if both copies were
+    /// sampled, so this tends to underestimate large inputs.
+    pub fn compresstimate_dedup<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<DedupEstimate> {
+        let mut seen = HashSet::new();
+        let mut blocks = 0u64;
+        let mut duplicates = 0u64;
+
+        let sampled = self.estimate(input, len, &mut |_, block| {
+            let mut hasher = DefaultHasher::new();
+            block.hash(&mut hasher);
+            blocks += 1;
+            if !seen.insert(hasher.finish()) {
+                duplicates += 1;
+            }
+            Ok(())
+        })?;
+
+        Ok(DedupEstimate {
+            sampled,
+            duplicate_fraction: match blocks {
+                0 => 0.0,
+                _ => duplicates as f32 / blocks as f32,
+            },
+        })
+    }
+
+    /// Compresstimate up to `len` bytes from `input`, also testing whether the
+    /// sampled bytes look random, to tell encrypted data apart from data
+    /// which is merely incompressible.
+    ///
+    /// Anything compressing below `INCOMPRESSIBLE_RATIO` is `Compressible`.
+    /// Otherwise it's `Random` if the chi-square statistic of the sampled
+    /// bytes is within `RANDOM_CHI_SQUARE`, and `Incompressible` if not.  The
+    /// statistics are taken over the blocks as read, before any padding is
+    /// stripped or transform applied.  The test grows more sensitive with the
+    /// amount sampled, so small samples of compressed formats may pass for
+    /// random, while it rejects about one in a thousand truly random inputs.
+    pub fn compresstimate_randomness<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<RandomnessEstimate> {
+        let mut counts = [0u64; 256];
+        let sampled = self.estimate(input, len, &mut |_, block| {
+            block.iter().for_each(|&b| counts[b as usize] += 1);
+            Ok(())
+        })?;
+
+        let chi_square = stats::chi_square(&counts);
+        let class = if !sampled.likely_incompressible() {
+            DataClass::Compressible
+        } else if chi_square <= RANDOM_CHI_SQUARE {
+            DataClass::Random
+        } else {
+            DataClass::Incompressible
+        };
+
+        Ok(RandomnessEstimate {
+            sampled,
+            entropy: stats::entropy(&counts),
+            chi_square,
+            class,
+        })
+    }
+
+    /// Whether the extrapolated compressed size of up to `len` bytes from
+    /// `input` comes in below `budget` bytes, as would be found by
+    /// `compresstimate_len_detailed`.
+    ///
+    /// This stops reading as soon as the answer is certain: straight away if
+    /// `len` is itself below the budget, since estimates never exceed the
+    /// original size, or once the compressed output so far already
+    /// extrapolates to more than the budget.
+    pub fn fits_under<P: Read + Seek>(&self, input: P, len: u64, budget: u64) -> io::Result<bool> {
+        if len < budget {
+            return Ok(true);
+        }
+
+        let plan = Plan {
+            stop_above: Some(budget),
+            trim: true,
+            ..Plan::default()
+        };
+        self.estimate_planned(input, len, plan, &mut |_, _| Ok(()))
+            .map(|e| e.extrapolated_compressed < budget)
+    }
+
+    /// Compresstimate up to `len` bytes from `input` at each of the given lz4
+    /// `levels`, returning a ratio for each in the same order.
+    ///
+    /// The input is only read once, with each sampled block fed to an encoder
+    /// per level, so this is much cheaper on I/O than estimating repeatedly.
+    /// Levels above 2 select lz4's high compression mode, up to a maximum of 16.
+    /// Blocks are stripped of padding, transformed and run-length encoded
+    /// first as configured, as they are for `compresstimate_len`, and the same
+    /// goes for `compresstimate_codecs` and `compresstimate_window_sweep`.
+    pub fn compresstimate_levels<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        levels: &[u32],
+    ) -> io::Result<Vec<f32>> {
+        let mut encoders = levels
+            .iter()
+            .map(|&level| self.compressor(&Codec::Lz4 { level }))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut buf = Default::default();
+        let estimate = self.estimate(input, len, &mut |_, block| {
+            let block = self.prepare(block, &mut buf);
+            encoders.iter_mut().try_for_each(|e| e.write_all(block))
+        })?;
+
+        encoders
+            .into_iter()
+            .map(|encoder| {
+                let compressed = encoder.finish()?;
+                Ok(Estimate::new(estimate.sampled_original, compressed, len, 1, 0).ratio())
+            })
+            .collect()
+    }
+
+    /// Compresstimate up to `len` bytes from `input` with each of `codecs`,
+    /// returning the estimate and compression time for each in the same order,
+    /// to weigh ratio against throughput.
+    ///
+    /// As with `compresstimate_levels`, the input is only read once, with every
+    /// sampled block fed to an encoder per codec.  The time is just that spent
+    /// in each codec's writes and finish, so it leaves out I/O and sampling,
+    /// but external codecs run in their own processes alongside each other and
+    /// only part of their time shows up here, when a pipe is full or the
+    /// output is awaited.  Treat them as indicative, and time a real run
+    /// before committing to an SLO.
+    pub fn compresstimate_codecs<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        codecs: &[Codec],
+    ) -> io::Result<Vec<CodecEstimate>> {
+        let mut encoders = codecs
+            .iter()
+            .map(|codec| Ok((self.compressor(codec)?, Duration::default())))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut buf = Default::default();
+        let estimate = self.estimate(input, len, &mut |_, block| {
+            let block = self.prepare(block, &mut buf);
+            encoders.iter_mut().try_for_each(|(e, time)| {
+                let start = Instant::now();
+                e.write_all(block)?;
+                *time += start.elapsed();
+                Ok(())
+            })
+        })?;
+
+        encoders
+            .into_iter()
+            .map(|(encoder, time)| {
+                let start = Instant::now();
+                let compressed = encoder.finish()?;
+                Ok(CodecEstimate {
+                    estimate: Estimate::new(
+                        estimate.sampled_original,
+                        compressed,
+                        len,
+                        self.config.min_allocation_unit,
+                        self.config.container_overhead,
+                    ),
+                    compress_time: time + start.elapsed(),
+                })
+            })
+            .collect()
+    }
+
+    /// Decide whether to compress up to `len` bytes from `input` or store them
+    /// raw, with the default `RecommendPolicy`: compress if it's estimated to
+    /// come to less than 0.9 of its size.
+    pub fn recommend<P: Read + Seek>(&self, input: P, len: u64) -> io::Result<StorageAction> {
+        self.recommend_with(input, len, &RecommendPolicy::default())
+    }
+
+    /// Decide whether to compress up to `len` bytes from `input` or store them
+    /// raw, with custom thresholds and codec in `policy`.
+    pub fn recommend_with<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        policy: &RecommendPolicy,
+    ) -> io::Result<StorageAction> {
+        if len < policy.min_len {
+            return Ok(StorageAction::StoreRaw {
+                reason: RawReason::TooSmall,
+            });
+        }
+
+        let codec = policy.codec.as_ref().unwrap_or(&self.config.codec);
+        let estimated_ratio = if *codec == self.config.codec {
+            self.compresstimate_len(input, len)?
+        } else {
+            let mut est = self.clone();
+            est.config.codec = codec.clone();
+            est.compresstimate_len(input, len)?
+        };
+
+        Ok(if estimated_ratio < policy.max_ratio {
+            StorageAction::Compress {
+                codec: codec.clone(),
+                estimated_ratio,
+            }
+        } else {
+            StorageAction::StoreRaw {
+                reason: RawReason::Incompressible { estimated_ratio },
+            }
+        })
+    }
+
+    /// The highest of the given lz4 `levels` which is expected to compress all
+    /// `len` bytes of `input` within `budget`.
+    ///
+    /// Each level's time to compress the samples is measured as with
+    /// `compresstimate_codecs`, and extrapolated linearly to the whole input
+    /// by how much of it was sampled.  That assumes compression time scales
+    /// with the amount of data rather than its content, which holds well
+    /// enough for lz4, but it leaves out reading the input and timings are
+    /// noisy on small samples, so leave some headroom.  Fails with `TimedOut`
+    /// if no level fits, and `InvalidInput` if there are none to choose from.
+    pub fn best_level_within<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        budget: Duration,
+        levels: &[u32],
+    ) -> io::Result<u32> {
+        if levels.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no levels to choose from",
+            ));
+        }
+
+        let codecs: Vec<Codec> = levels.iter().map(|&level| Codec::Lz4 { level }).collect();
+        let results = self.compresstimate_codecs(input, len, &codecs)?;
+
+        levels
+            .iter()
+            .zip(results)
+            .filter(|(_, result)| {
+                let estimate = result.estimate;
+                let scale = match estimate.sampled_original {
+                    0 => 1.0,
+                    sampled => estimate.total_original as f64 / sampled as f64,
+                };
+                result.compress_time.as_secs_f64() * scale <= budget.as_secs_f64()
+            })
+            .map(|(&level, _)| level)
+            .max()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no level compresses within {:?}", budget),
+                )
+            })
+    }
+
+    /// Compresstimate up to `len` bytes from `input` with the configured codec
+    /// at each of the given `windows`, returning a ratio for each in the same
+    /// order, to see whether a larger window - such as zstd's long-distance
+    /// matching - is worth it.
+    ///
+    /// The codec must be an `ExternalCommandCodec` with a `{window}`
+    /// placeholder in its arguments, which is replaced by each window as given,
+    /// in whatever units the program expects; lz4's window is fixed.  The
+    /// input is read once, with a process per window.  Bear in mind that
+    /// sampling brings distant blocks together, hiding exactly the matches a
+    /// larger window would find, so force exhaustive reads for a fair sweep.
+    pub fn compresstimate_window_sweep<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        windows: &[u32],
+    ) -> io::Result<Vec<f32>> {
+        let mut encoders = windows
+            .iter()
+            .map(|&window| self.compressor(&self.config.codec.with_window(window)?))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // The codec as configured has a placeholder for an argument, so don't
+        // run it - lz4 is cheap enough to sample with alongside
+        let mut sampler = self.clone();
+        sampler.config.codec = Codec::default();
+
+        let mut buf = Default::default();
+        let estimate = sampler.estimate(input, len, &mut |_, block| {
+            let block = self.prepare(block, &mut buf);
+            encoders.iter_mut().try_for_each(|e| e.write_all(block))
+        })?;
+
+        encoders
+            .into_iter()
+            .map(|encoder| {
+                let compressed = encoder.finish()?;
+                Ok(Estimate::new(estimate.sampled_original, compressed, len, 1, 0).ratio())
+            })
+            .collect()
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with
+    /// `compresstimate_len_detailed`, additionally compressing the first block
+    /// on its own to see how well that alone would have done.
+    pub fn compare_first_block<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<FirstBlockComparison> {
+        let mut first = None;
+        let sampled = self.estimate(input, len, &mut |_, block| {
+            if first.is_none() {
+                first = Some(self.compress_ratio(block)?);
+            }
+            Ok(())
+        })?;
+
+        Ok(FirstBlockComparison {
+            sampled,
+            first_block: first.unwrap_or(1.0),
+        })
+    }
+
+    /// How the estimate of up to `len` bytes from `input` converges with the
+    /// number of blocks sampled, as the ratio from sampling 1, 2, 4, 8...
+    /// blocks, up to the number an estimate would sample, which comes last
+    /// with its ratio.
+    ///
+    /// Each count is a separate estimate with its samples spread over the
+    /// whole input, so this reads around twice as much as one estimate.  If
+    /// the input would be read in full, that's the only point.  Ratios which
+    /// settle well before the end suggest a lower confidence or wider margin
+    /// would do.
+    pub fn compresstimate_convergence<P: Read + Seek>(
+        &self,
+        mut input: P,
+        len: u64,
+    ) -> io::Result<Vec<(u64, f32)>> {
+        let sampling = self.sampling(len);
+        let start = input.stream_position()?;
+        let mut curve = vec![];
+
+        let mut samples = 1;
+        while !sampling.full_read && samples < sampling.blocks {
+            let plan = Plan {
+                samples: Some(samples),
+                trim: true,
+                ..Plan::default()
+            };
+            input.seek(SeekFrom::Start(start))?;
+            let estimate = self.estimate_planned(&mut input, len, plan, &mut |_, _| Ok(()))?;
+            curve.push((samples, estimate.ratio()));
+            samples *= 2;
+        }
+
+        input.seek(SeekFrom::Start(start))?;
+        let estimate = self.estimate(&mut input, len, &mut |_, _| Ok(()))?;
+        curve.push((sampling.blocks, estimate.ratio()));
+        Ok(curve)
+    }
+
+    /// Compresstimate up to `len` bytes from `input` as with
+    /// `compresstimate_len`, sending `(bytes_processed, running_ratio)`
+    /// updates to `updates` as it goes, for showing an estimate converge.
+    ///
+    /// Updates carry the bytes read so far, and are sent whenever the codec
+    /// emits more output after a sampled block or, reading in full, each
+    /// 64KiB read - for lz4, that's every 64KiB it's given, as it fills a
+    /// frame block.  The running ratio is what's been emitted over what's
+    /// been given, so it runs low by whatever the codec still holds, and
+    /// external codecs may emit little until they finish.  Once done, a
+    /// final update with the bytes sampled and the estimated ratio is sent,
+    /// and that one is authoritative, matching the ratio returned.  Nothing
+    /// final is sent if estimating fails, and a dropped receiver doesn't stop
+    /// the estimate.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use std::sync::mpsc;
+    /// use compresstimator::Compresstimator;
+    ///
+    /// let (tx, rx) = mpsc::channel();
+    /// let data = vec![0; 1024 * 1024];
+    /// let ratio = Compresstimator::default()
+    ///     .compresstimate_updates(Cursor::new(&data), data.len() as u64, &tx)
+    ///     .expect("estimate");
+    /// drop(tx);
+    /// assert_eq!(rx.iter().last(), Some((1024 * 1024, ratio)));
+    /// ```
+    pub fn compresstimate_updates<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        updates: &Sender<(u64, f32)>,
+    ) -> io::Result<f32> {
+        let plan = Plan {
+            trim: true,
+            updates: Some(updates),
+            ..Plan::default()
+        };
+        let estimate = self.estimate_planned(input, len, plan, &mut |_, _| Ok(()))?;
+        let _ = updates.send((estimate.sampled_original, estimate.ratio()));
+        Ok(estimate.ratio())
+    }
+
+    /// Compresstimate up to `len` bytes from `input`, returning the given
+    /// `percentiles` (0 to 100) of the ratios of each block compressed on its
+    /// own, in the same order.
+    ///
+    /// Where the overall estimate gives the mean, this shows the spread -
+    /// a mostly compressible file with dense incompressible regions will have a
+    /// high 90th percentile.  Compressing each block separately costs extra
+    /// CPU, and with an external codec, a process per block.
+    pub fn compresstimate_percentiles<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        percentiles: &[f32],
+    ) -> io::Result<Vec<f32>> {
+        let (_, mut ratios) = self.block_ratios(input, len)?;
+        ratios.sort_by(f32::total_cmp);
+
+        Ok(percentiles
+            .iter()
+            .map(|&p| stats::percentile(&ratios, p))
+            .collect())
+    }
+
+    /// Compresstimate up to `len` bytes from `input`, returning a confidence
+    /// interval for its stored size, as the lowest and highest number of
+    /// compressed bytes.
+    ///
+    /// Each sampled block is also compressed on its own, and the spread of
+    /// their ratios gives a standard error, corrected for how much of the
+    /// input was sampled.  The interval is that many standard errors either
+    /// side of `extrapolated_compressed`, as the configured confidence level
+    /// calls for, clamped to between nothing and the original size.  When the
+    /// input is read in full there's nothing left to guess at, and the
+    /// interval is just that point.  Per-block ratios miss matches between
+    /// blocks, so this is an indication of the sampling error rather than a
+    /// guarantee.
+    pub fn compresstimate_stored_interval<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<(u64, u64)> {
+        let (estimate, ratios) = self.block_ratios(input, len)?;
+        let point = estimate.extrapolated_compressed;
+        let sampling = self.sampling(len);
+        if sampling.covered(&estimate) {
+            return Ok((point, point));
+        }
+
+        let population = estimate.total_original.div_ceil(sampling.block_size);
+        let error = stats::standard_error(&ratios, population);
+        let margin =
+            f64::from(f32::from(self.config.confidence) * error) * estimate.total_original as f64;
+        let margin = margin.round() as u64;
+
+        Ok((
+            point.saturating_sub(margin),
+            point.saturating_add(margin).min(estimate.total_original),
+        ))
+    }
+
+    /// Compresstimate up to `len` bytes from `input`, scoring how trustworthy
+    /// the estimate is from 0 to 1 by how many blocks were sampled and how
+    /// much their ratios varied.
+    ///
+    /// An input read in full scores 1, since nothing was guessed at, and one
+    /// with nothing sampled 0.  Otherwise each sampled block is also
+    /// compressed on its own, and with `n` of them, `h` the half-width of the
+    /// confidence interval for their mean ratio, as from
+    /// `compresstimate_stored_interval`, and `m` the configured margin of
+    /// error, the score is:
+    ///
+    /// ```text
+    /// m / (m + h) * n / (n + 1)
+    /// ```
+    ///
+    /// So uniform data sampled from plenty of blocks scores near 1, an
+    /// interval as wide as the margin halves the score, and few samples
+    /// lower it however alike they were.  One or two samples can't show
+    /// variance, and score at most 0.5 and 0.67.  A low score suggests
+    /// sampling more or reading the input in full with `base_truth`.
+    pub fn compresstimate_with_quality<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+    ) -> io::Result<QualityEstimate> {
+        let (sampled, ratios) = self.block_ratios(input, len)?;
+        let samples = ratios.len() as u64;
+        let sampling = self.sampling(len);
+        let full_read = sampling.covered(&sampled);
+
+        let quality = if full_read {
+            1.0
+        } else if samples == 0 {
+            0.0
+        } else {
+            let population = sampled.total_original.div_ceil(sampling.block_size);
+            let error = stats::standard_error(&ratios, population);
+            let half_width = f32::from(self.config.confidence) * error;
+            let margin = self.config.error_margin;
+            let n = samples as f32;
+            margin / (margin + half_width) * n / (n + 1.0)
+        };
+
+        Ok(QualityEstimate {
+            sampled,
+            quality,
+            samples,
+            full_read,
+        })
+    }
+
+    /// Compresstimate up to `len` bytes from `input`, also estimating how
+    /// noisy the result is by bootstrapping: resampling the per-block ratios
+    /// `resamples` times with replacement, and taking the standard deviation
+    /// of the resampled means.
+    ///
+    /// Each sampled block is also compressed on its own to give those ratios,
+    /// but nothing more is read, so this shows whether sampling more would
+    /// be worthwhile at no extra I/O.  The standard error shrinks with the
+    /// square root of the number of samples, so halving it takes four times
+    /// as many.  Unlike `compresstimate_stored_interval`, it assumes nothing
+    /// about the spread, though it knows nothing of the unsampled remainder
+    /// either, and isn't corrected for how much of the input was sampled.  An
+    /// input read in full has no sampling error, giving 0.  Resampling is
+    /// seeded from the sampled sizes, so the same input gives the same
+    /// result.  A thousand or so resamples is usually plenty.
+    pub fn compresstimate_bootstrap<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        resamples: usize,
+    ) -> io::Result<BootstrapEstimate> {
+        let (sampled, ratios) = self.block_ratios(input, len)?;
+        let full_read = self.sampling(len).covered(&sampled);
+
+        let standard_error = if full_read {
+            0.0
+        } else {
+            let seed = sampled.sampled_original ^ sampled.sampled_compressed.rotate_left(32);
+            stats::bootstrap_standard_error(&ratios, resamples, seed)
+        };
+
+        Ok(BootstrapEstimate {
+            sampled,
+            standard_error,
+        })
+    }
+
+    /// Estimate as normal, also collecting the ratio of each block compressed
+    /// on its own, in the order they were read.
+    fn block_ratios<P: Read + Seek>(&self, input: P, len: u64) -> io::Result<(Estimate, Vec<f32>)> {
+        let mut ratios = vec![];
+        let estimate = self.estimate(input, len, &mut |_, block| {
+            ratios.push(self.compress_ratio(block)?);
+            Ok(())
+        })?;
+
+        Ok((estimate, ratios))
+    }
+
+    fn estimate<P: Read + Seek>(
+        &self,
+        input: P,
+        len: u64,
+        visit: &mut dyn FnMut(u64, &[u8]) -> io::Result<()>,
+    ) -> io::Result<Estimate> {
+        let plan = Plan {
+            trim: true,
+            ..Plan::default()
+        };
+        self.estimate_planned(input, len, plan, visit)
+    }
+
+    /// Estimate `file` as with `estimate`, batching samples into vectored reads
+    /// where the platform allows.
+    fn estimate_file(
+        &self,
+        file: &File,
+        len: u64,
+        visit: &mut dyn FnMut(u64, &[u8]) -> io::Result<()>,
+    ) -> io::Result<Estimate> {
+        let plan = Plan {
+            trim: true,
+            file: Some(file),
+            batch: true,
+            ..Plan::default()
+        };
+        self.estimate_planned(file, len, plan, visit)
+    }
+
+    /// Estimate as with `estimate`, following `plan`.
+    fn estimate_planned<P: Read + Seek>(
+        &self,
+        mut input: P,
+        mut len: u64,
+        plan: Plan<'_>,
+        visit: &mut dyn FnMut(u64, &[u8]) -> io::Result<()>,
+    ) -> io::Result<Estimate> {
+        if plan.trim {
+            let (head, tail) = (self.config.trim_head, self.config.trim_tail);
+            len = len.saturating_sub(head.saturating_add(tail));
+
+            if len > 0 && head > 0 {
+                let start = input.stream_position()?;
+                input.seek(SeekFrom::Start(start + head))?;
+            }
+        }
+
+        let prefix_len = self.config.prefix_dictionary;
+        let whole_len = len;
+        let mut prefix = None;
+        if plan.trim && prefix_len > 0 && len > prefix_len {
+            let mut buf = AlignedBuf::new(prefix_len as usize, self.config.direct_io);
+            let n = read_full(&mut input, &mut buf)?;
+            plan.charge(n as u64)?;
+            if n == buf.len() {
+                len -= prefix_len;
+                debug!("using the first {} bytes as a dictionary", prefix_len);
+                prefix = Some(buf);
+            } else if plan.tolerate_short {
+                // No longer than the prefix after all, so estimated as normal
+                debug!("input ended {} bytes into the prefix", n);
+                input.seek(SeekFrom::Current(-(n as i64)))?;
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "input ended before the prefix dictionary",
+                ));
+            }
+        }
+        let plan = plan.primed(prefix.as_deref());
+
+        // This estimate's own block size
+        let mut est = self.clone();
+        let block_size = self.planned_block_size(len);
+        if block_size != self.config.block_size {
+            debug!("{} bytes: growing blocks to {}", len, block_size);
+            est.config.block_size = block_size;
+        }
+
+        let start = Instant::now();
+        let result = est.estimate_inner(input, len, plan, visit).map(|e| {
+            if whole_len == len {
+                e
+            } else {
+                // Extrapolate over the prefix too
+                Estimate::new(
+                    e.sampled_original,
+                    e.sampled_compressed,
+                    whole_len,
+                    self.config.min_allocation_unit,
+                    self.config.container_overhead,
+                )
+            }
+        });
+
+        match &result {
+            Ok(e) => debug!(
+                "estimated {} bytes from {}: ratio {:.3} in {:.2?}",
+                len,
+                e.sampled_original,
+                e.ratio(),
+                start.elapsed()
+            ),
+            Err(e) => debug!("estimating {} bytes failed: {}", len, e),
+        }
+
+        result
+    }
+
+    /// Estimate all of `input`, reading it forwards only, for streams which
+    /// can't seek and whose length may not be known.
+    ///
+    /// Every block is kept until there are four times the samples needed of
+    /// an endless input, then every other one is dropped and only every other
+    /// block after that is kept, and so on, leaving an evenly spaced sample of
+    /// however long the stream turns out to be.  Short streams are kept in
+    /// full, as `reads_in_full` would.  Given `len_hint`, the samples needed
+    /// for that length are kept from the start at the stride which spreads
+    /// them over it, carrying on as before if the stream turns out longer.
+    fn estimate_stream<R: Read>(
+        &self,
+        mut input: R,
+        len_hint: Option<u64>,
+    ) -> io::Result<Estimate> {
+        let block_size = self.config.block_size as usize;
+        let blocks = len_hint.map_or(u64::MAX, |len| len / self.config.block_size);
+        let samples =
+            sample_size(blocks, self.config.error_margin, self.config.confidence).max(1.0) as usize;
+
+        let mut kept: Vec<Vec<u8>> = vec![];
+        let mut stride = match len_hint {
+            Some(len) if !self.reads_in_full(len, self.config.block_size, samples as u64) => {
+                (blocks / samples as u64).max(1)
+            }
+            _ => 1,
+        };
+        let mut total = 0;
+        let mut buf = vec![0; block_size];
+
+        for index in 0.. {
+            let n = read_full(&mut input, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+
+            if index % stride == 0 {
+                kept.push(buf[..n].to_vec());
+                if kept.len() > samples * 4 {
+                    kept = kept.into_iter().step_by(2).collect();
+                    stride *= 2;
+                }
+            }
+        }
+
+        debug!(
+            "streamed {} bytes, keeping every {} of {} byte blocks",
+            total, stride, block_size
+        );
+
+        let mut encoder = self.compressor(&self.config.codec)?;
+        let mut sampled = 0;
+        let mut packed = vec![];
+        for block in &mut kept {
+            let (kept, block) = self.prepare_in_place(block, &mut packed);
+            encoder.write_all(block)?;
+            sampled += kept as u64;
+        }
+        let compressed = encoder.finish()?;
+
+        Ok(Estimate::new(
+            sampled,
+            compressed,
+            total,
+            self.config.min_allocation_unit,
+            self.config.container_overhead,
+        ))
+    }
+
+    /// The block size to sample `len` bytes with: grown for large inputs if
+    /// `adaptive_block_size` is on, and then to whole chunks if compressing
+    /// in chunks, so every sample is made of them.
+    fn planned_block_size(&self, len: u64) -> u64 {
+        let block_size = if self.config.adaptive_block_size {
+            adaptive_block_size(len, self.config.block_size)
+        } else {
+            self.config.block_size
+        };
+
+        match self.config.chunk_size {
+            0 => block_size,
+            chunk => block_size.div_ceil(chunk) * chunk,
+        }
+    }
+
+    /// Whether to read all `len` bytes of an input rather than sample it, if
+    /// it's to have `samples` blocks of `block_size` sampled.
+    fn reads_in_full(&self, len: u64, block_size: u64, samples: u64) -> bool {
+        // If we're going to be randomly sampling a big chunk of the file anyway,
+        // we might as well read in the lot.
+        self.config.force_exhaustive || samples == 0 || len < samples * block_size * 4
+    }
+
+    /// Estimate `len` bytes of `input`, calling `visit` with the offset and
+    /// contents of each block as it's compressed.
+    fn estimate_inner<P: Read + Seek>(
+        &self,
+        mut input: P,
+        len: u64,
+        mut plan: Plan<'_>,
+        visit: &mut dyn FnMut(u64, &[u8]) -> io::Result<()>,
+    ) -> io::Result<Estimate> {
+        let sink = plan.sink.take().map(|Sink(sink)| sink);
+        let dictionary = plan.dictionary.or(self.dictionary.as_deref());
+        let mut encoder = ChunkedCompressor::new(
+            self.clamped(&self.config.codec),
+            dictionary,
+            sink,
+            self.config.chunk_size,
+        )?;
+        plan.reported = encoder.written();
+
+        let start = input.stream_position()?;
+        let extents = match plan.file {
+            Some(file) if self.config.sample_extents && plan.offsets.is_none() => {
+                sys::extents(file, start, start + len)?
+            }
+            _ => None,
+        };
+        // The bytes worth sampling: all of them, or just what's been written
+        let total = extents
+            .as_ref()
+            .map_or(len, |extents| extents.iter().map(|&(_, len)| len).sum());
+
+        let blocks = total / self.config.block_size;
+        let samples = plan
+            .offsets
+            .map(|offsets| offsets.len() as u64)
+            .or(plan.samples)
+            .unwrap_or_else(|| {
+                sample_size(blocks, self.config.error_margin, self.config.confidence) as u64
+            });
+        let mut written = 0;
+
+        debug!(
+            "{} bytes: {} blocks of {}, {} samples needed",
+            len, blocks, self.config.block_size, samples
+        );
+
+        let planned_offsets = plan.offsets.is_some() || extents.is_some();
+        if !planned_offsets && self.reads_in_full(len, self.config.block_size, samples) {
+            debug!("reading all {} bytes", len);
+
+            let block_size = self.config.block_size as usize;
+            let chunk = block_size * (FULL_READ_CHUNK / block_size).max(1);
+            // No bigger than needed, in case of a huge block on a small input,
+            // unless direct I/O needs every read the same aligned size
+            let direct = self.config.direct_io;
+            let size = if direct {
+                chunk
+            } else {
+                len.min(chunk as u64) as usize
+            };
+            let mut buf = AlignedBuf::new(size, direct);
+            let verify_size = if self.config.verify_reads { size } else { 0 };
+            let mut verify = AlignedBuf::new(verify_size, direct);
+            // Less what's been stripped of padding, this is `written`
+            let mut read = 0;
+            let mut packed = vec![];
+
+            while read < len {
+                let want = if direct {
+                    size
+                } else {
+                    size.min((len - read) as usize)
+                };
+                let n = read_full(&mut input, &mut buf[..want])?.min((len - read) as usize);
+                if n == 0 {
+                    break;
+                }
+
+                let offset = start + read;
+                plan.charge(n as u64)?;
+                if self.config.verify_reads {
+                    plan.charge(n as u64)?;
+                    verify_read(&mut input, offset, &buf[..n], &mut verify[..want])?;
+                    input.seek(SeekFrom::Start(offset + n as u64))?;
+                }
+                read += n as u64;
+                // Blocks which only need transforming go to the encoder together
+                let whole = self.config.strip_padding.is_none() && !self.config.rle_preanalysis;
+                for (i, block) in buf[..n].chunks_mut(block_size).enumerate() {
+                    visit(offset + (i * block_size) as u64, block)?;
+                    if whole {
+                        self.transform(block);
+                    } else {
+                        let (kept, block) = self.prepare_in_place(block, &mut packed);
+                        encoder.write_all(block)?;
+                        written += kept as u64;
+                    }
+                }
+                if whole {
+                    encoder.write_all(&buf[..n])?;
+                    written += n as u64;
+                }
+
+                plan.update(read, written, encoder.written());
+                if plan.stopping(encoder.written(), len, len) {
+                    debug!("stopping after {} bytes, over the limit", written);
+                    return Ok(Estimate::new(
+                        written,
+                        encoder.written(),
+                        len,
+                        self.config.min_allocation_unit,
+                        self.config.container_overhead,
+                    ));
+                }
+            }
+        } else {
+            let block_size = self.config.block_size as usize;
+            let direct = self.config.direct_io;
+            let mut buf = AlignedBuf::new(block_size, direct);
+            let next_size = if self.config.align_lines {
+                block_size
+            } else {
+                0
+            };
+            let mut next = AlignedBuf::new(next_size, direct);
+            let verify_size = if self.config.verify_reads {
+                block_size
+            } else {
+                0
+            };
+            let mut verify = AlignedBuf::new(verify_size, direct);
+            let mut line = Vec::new();
+            let mut packed = vec![];
+            let planned = samples.min(blocks) * self.config.block_size;
+            let end = start + len;
+            // Sampled blocks so far which were all incompressible
+            let mut streak = self.config.early_exit.map(|_| 0);
+
+            // Line alignment reads on from each block, so needs the position
+            let vectored = plan
+                .file
+                .filter(|_| plan.batch && sys::VECTORED_READS && !self.config.align_lines);
+            let (batch_size, skip_size) = match vectored {
+                Some(_) => (
+                    block_size * max_batch_blocks(self.config.block_size),
+                    MAX_BATCH_SKIP,
+                ),
+                None => (0, 0),
+            };
+            let mut batch_buf = AlignedBuf::new(batch_size, direct);
+            let mut skip = AlignedBuf::new(skip_size, direct);
+
+            let offsets: Vec<u64> = match (plan.offsets, &extents) {
+                (Some(offsets), _) => offsets.to_vec(),
+                (None, Some(extents)) => {
+                    debug!("{} of {} bytes in {} extents", total, len, extents.len());
+                    self.extent_offsets(extents, total, samples)
+                }
+                (None, None) => self.sample_offsets(start, blocks, samples).collect(),
+            };
+            let mut rest = &offsets[..];
+            // Unlike `written`, this counts stripped padding, and whatever line
+            // alignment skips or reads on
+            let mut read = 0;
+
+            'sampling: while !rest.is_empty() {
+                let (batch, tail) = match vectored {
+                    Some(_) => rest.split_at(batch_len(rest, self.config.block_size)),
+                    None => rest.split_at(1),
+                };
+                rest = tail;
+
+                // If the file ended early, go back over it a block at a time
+                let batched = match vectored {
+                    Some(file) => {
+                        let batch_buf = &mut batch_buf[..batch.len() * block_size];
+                        read_batch(file, batch, batch_buf, &mut skip)?
+                    }
+                    None => false,
+                };
+
+                for (i, &offset) in batch.iter().enumerate() {
+                    let block = if batched {
+                        &mut batch_buf[i * block_size..(i + 1) * block_size]
+                    } else {
+                        // Growing or truncated inputs can be shorter than we
+                        // were told, so take what's there if that's allowed
+                        input.seek(SeekFrom::Start(offset))?;
+                        let n = if plan.tolerate_short {
+                            read_full(&mut input, &mut buf)?
+                        } else {
+                            input.read_exact(&mut buf)?;
+                            block_size
+                        };
+                        if n == 0 {
+                            debug!("input ended at {}, before sampling finished", offset);
+                            break 'sampling;
+                        }
+                        &mut buf[..n]
+                    };
+                    read += block.len() as u64;
+                    plan.charge(block.len() as u64)?;
+
+                    // This leaves the position after the block, as line
+                    // alignment needs
+                    if self.config.verify_reads {
+                        plan.charge(block.len() as u64)?;
+                        verify_read(&mut input, offset, block, &mut verify)?;
+                    }
+
+                    let first = if self.config.align_lines {
+                        block.iter().position(|&b| b == b'\n')
+                    } else {
+                        None
+                    };
+                    let (offset, sample) = match first {
+                        Some(first) => {
+                            line.clear();
+                            line.extend_from_slice(&block[first + 1..]);
+                            let avail = end.saturating_sub(offset + block_size as u64);
+                            let n = read_full(&mut input, &mut next)?.min(avail as usize);
+                            read += n as u64;
+                            plan.charge(n as u64)?;
+                            let eol = next[..n].iter().position(|&b| b == b'\n');
+                            line.extend_from_slice(&next[..eol.map_or(n, |eol| eol + 1)]);
+                            (offset + first as u64 + 1, &mut line[..])
+                        }
+                        _ => (offset, block),
+                    };
+
+                    visit(offset, sample)?;
+                    let (kept, prepared) = self.prepare_in_place(sample, &mut packed);
+                    encoder.write_all(prepared)?;
+                    written += kept as u64;
+                    let sample = &sample[..kept];
+
+                    if let (Some(exit), Some(count)) = (self.config.early_exit, streak) {
+                        let codec = self.clamped(&self.config.codec);
+                        let mut alone = Compressor::new(&codec, dictionary, None)?;
+                        alone.write_all(self.pack(sample, &mut packed))?;
+                        let ratio = alone.finish()? as f32 / sample.len() as f32;
+
+                        streak = Some(count + 1).filter(|_| ratio >= exit.threshold);
+                        if streak >= Some(exit.blocks) {
+                            debug!("stopping after {} incompressible blocks", count + 1);
+                            break 'sampling;
+                        }
+                    }
+
+                    plan.update(read, written, encoder.written());
+                    if plan.stopping(encoder.written(), planned, total) {
+                        debug!("stopping after sampling {} bytes, over the limit", written);
+                        return Ok(Estimate::new(
+                            written,
+                            encoder.written(),
+                            total,
+                            self.config.min_allocation_unit,
+                            self.config.container_overhead,
+                        ));
+                    }
+                }
+            }
+        }
+
+        let compressed = encoder.finish()?;
+        Ok(Estimate::new(
+            written,
+            compressed,
+            total,
+            self.config.min_allocation_unit,
+            self.config.container_overhead,
+        ))
+    }
+
+    /// The offsets to sample `samples` blocks from `extents`, totalling `total`
+    /// bytes, spread over them in proportion to their length, or every block
+    /// of them if that's few enough to read in full.
+    ///
+    /// Samples are placed as `sample_offsets` would over the extents laid end
+    /// to end, then mapped back to where they are in the file, moving any
+    /// which would run off the end of their extent back within it.
+    fn extent_offsets(&self, extents: &[(u64, u64)], total: u64, samples: u64) -> Vec<u64> {
+        let block_size = self.config.block_size;
+        if self.reads_in_full(total, block_size, samples) {
+            return extents
+                .iter()
+                .flat_map(|&(offset, len)| (offset..offset + len).step_by(block_size as usize))
+                .collect();
+        }
+
+        let mut extents = extents.iter().peekable();
+        let mut before = 0;
+        self.sample_offsets(0, total / block_size, samples)
+            .filter_map(|virtual_offset| {
+                while let Some(&&(_, len)) = extents.peek() {
+                    if virtual_offset < before + len {
+                        break;
+                    }
+                    before += len;
+                    extents.next();
+                }
+
+                let &&(offset, len) = extents.peek()?;
+                let within = virtual_offset - before;
+                Some(if len >= block_size && within + block_size > len {
+                    offset + len - block_size
+                } else {
+                    offset + within
+                })
+            })
+            .collect()
+    }
+
+    /// How an input of `len` bytes would be read by `compresstimate_len` and
+    /// friends: whether it's sampled, how many blocks, and how far apart.
+    ///
+    /// This describes the plan after trimming and any adaptive block size, but
+    /// not where an estimate stops early, such as with `early_exit`.
+    pub fn sampling(&self, len: u64) -> Sampling {
+        let mut len =
+            len.saturating_sub(self.config.trim_head.saturating_add(self.config.trim_tail));
+        if self.config.prefix_dictionary > 0 && len > self.config.prefix_dictionary {
+            len -= self.config.prefix_dictionary;
+        }
+        let block_size = self.planned_block_size(len);
+
+        let blocks = len / block_size;
+        let samples = sample_size(blocks, self.config.error_margin, self.config.confidence) as u64;
+
+        if self.reads_in_full(len, block_size, samples) {
+            Sampling {
+                block_size,
+                full_read: true,
+                blocks: len.div_ceil(block_size),
+                stride: block_size,
+            }
+        } else {
+            let samples = samples.min(blocks);
+            Sampling {
+                block_size,
+                full_read: false,
+                blocks: samples,
+                stride: block_size * (blocks / samples.max(1)),
+            }
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        open(path, self.config.direct_io)
+    }
+
+    fn compressor(&self, codec: &Codec) -> io::Result<Compressor<'static>> {
+        Compressor::new(&self.clamped(codec), self.dictionary.as_deref(), None)
+    }
+
+    /// `codec` with its window cut down to `max_window_log`, if there is one.
+    fn clamped(&self, codec: &Codec) -> Codec {
+        match self.config.max_window_log {
+            Some(max) => codec.clamp_window(max),
+            None => codec.clone(),
+        }
+    }
+
+    /// Apply the transform, if any, to `block`.
+    fn transform(&self, block: &mut [u8]) {
+        match &self.transform {
+            Some(Transform::Shuffle(element_size)) => shuffle(block, *element_size),
+            Some(Transform::Custom(transform)) => transform(block),
+            None => (),
+        }
+    }
+
+    /// Compress `data` on its own, returning the ratio achieved.
+    fn compress_ratio(&self, data: &[u8]) -> io::Result<f32> {
+        let compressed = self.compressed_len(data)?;
+        Ok((compressed as f32 / data.len() as f32).min(1.0))
+    }
+
+    /// `block` run-length encoded into `packed` if `with_rle_preanalysis` is
+    /// on, or as it is if not.
+    fn pack<'b>(&self, block: &'b [u8], packed: &'b mut Vec<u8>) -> &'b [u8] {
+        if self.config.rle_preanalysis {
+            pack_bits(block, packed);
+            packed
+        } else {
+            block
+        }
+    }
+
+    /// Prepare `block` in place for the encoder: stripped of padding,
+    /// transformed and run-length encoded into `packed` as configured.
+    /// Returns how much of the block was kept, which is what it counts
+    /// towards the sample, and what to compress.
+    fn prepare_in_place<'b>(
+        &self,
+        block: &'b mut [u8],
+        packed: &'b mut Vec<u8>,
+    ) -> (usize, &'b [u8]) {
+        let kept = match self.config.strip_padding {
+            Some(byte) => strip_padding(block, byte),
+            None => block.len(),
+        };
+        let block = &mut block[..kept];
+        self.transform(block);
+        (kept, self.pack(block, packed))
+    }
+
+    /// A copy of `block` as the estimate's own encoder sees it, as with
+    /// `prepare_in_place`, for `visit` hooks compressing blocks with encoders
+    /// of their own, using `buf` for scratch space.
+    fn prepare<'b>(&self, block: &[u8], buf: &'b mut (Vec<u8>, Vec<u8>)) -> &'b [u8] {
+        let (scratch, packed) = buf;
+        scratch.clear();
+        scratch.extend_from_slice(block);
+        self.prepare_in_place(scratch, packed).1
+    }
+
+    /// Compress `data` on its own, returning the compressed size.
+    fn compressed_len(&self, data: &[u8]) -> io::Result<u64> {
+        let mut encoder = self.compressor(&self.config.codec)?;
+        let mut packed = vec![];
+        match &self.transform {
+            Some(_) => {
+                let mut data = data.to_vec();
+                self.transform(&mut data);
+                encoder.write_all(self.pack(&data, &mut packed))?;
+            }
+            None => encoder.write_all(self.pack(data, &mut packed))?,
+        }
+        encoder.finish()
+    }
+
+    /// The offsets of `samples` blocks spread over `blocks` blocks from `start`.
+    ///
+    /// The stride is always at least a block, so samples never overlap and no
+    /// bytes are counted twice - if more samples are asked for than there are
+    /// blocks, every block is sampled once.  In practice `estimate_inner` reads
+    /// the whole input well before this point, since it only samples when the
+    /// stride would be at least four blocks.
+    ///
+    /// With `SampleStrategy::Random` the blocks are picked at random instead,
+    /// at offsets which are whole blocks from `start`.
+    fn sample_offsets(&self, start: u64, blocks: u64, samples: u64) -> impl Iterator<Item = u64> {
+        let block_size = self.config.block_size;
+        let samples = samples.min(blocks);
+        let step = block_size * (blocks / samples.max(1));
+        let align = self.config.align_samples || self.config.direct_io;
+
+        let offsets: Vec<u64> = match self.config.strategy {
+            SampleStrategy::Uniform => (0..samples).map(|i| start + step * i).collect(),
+            SampleStrategy::Random { seed } => random_blocks(blocks, samples, seed)
+                .into_iter()
+                .map(|block| start + block * block_size)
+                .collect(),
+            SampleStrategy::Region {
+                offset,
+                len,
+                weight,
+            } => {
+                let region = offset / block_size..offset.saturating_add(len).div_ceil(block_size);
+                region_blocks(blocks, samples, region, weight)
+                    .into_iter()
+                    .map(|block| start + block * block_size)
+                    .collect()
+            }
+        };
+
+        offsets.into_iter().map(move |offset| {
+            if !align {
+                offset
+            } else if offset - offset % block_size < start {
+                // Rounding down would take us before the start, so round up
+                offset + block_size - offset % block_size
+            } else {
+                offset - offset % block_size
+            }
+        })
+    }
+
+    /// Compresstimate the first `len` bytes of the file located at `path`.
+    ///
+    /// If the file is shorter than `len`, this may fail with an
+    /// `UnexpectedEof` error; `compresstimate_file_up_to` doesn't.
+    pub fn compresstimate_file_len<P: AsRef<Path>>(&self, path: P, len: u64) -> io::Result<f32> {
+        let file = self.open(path.as_ref())?;
+        self.estimate_file(&file, len, &mut |_, _| Ok(()))
+            .map(|e| e.ratio())
+    }
+
+    /// Compresstimate at most the first `max_len` bytes of the file located at
+    /// `path`, or the whole file if it's shorter.
+    ///
+    /// Unlike `compresstimate_file_len`, the length is clamped to the file's
+    /// size up front, so a short file is estimated as it is rather than
+    /// extrapolated out to `max_len`.  Useful to cap the work done per file
+    /// across a batch of mixed sizes.
+    ///
+    /// A file truncated or rewritten after it's been sized doesn't fail either:
+    /// only what's there is read, and the ratio of that extrapolated to the
+    /// length it had.
+    pub fn compresstimate_file_up_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+        max_len: u64,
+    ) -> io::Result<f32> {
+        let mut file = self.open(path.as_ref())?;
+        let len = file_len(&mut file)?.min(max_len);
+        let plan = Plan {
+            trim: true,
+            file: Some(&file),
+            batch: true,
+            tolerate_short: true,
+            ..Plan::default()
+        };
+        self.estimate_planned(&file, len, plan, &mut |_, _| Ok(()))
+            .map(|e| e.ratio())
+    }
+
+    /// Compresstimate the file located at `path`.
+    ///
+    /// On Linux, block devices are sized with `BLKGETSIZE64`, so whole volumes
+    /// can be estimated.  On Linux, Android, the BSDs and illumos, samples
+    /// close together are fetched with a single `preadv` to cut down on
+    /// syscalls - up to 32 blocks or 2MiB at a time, reading through gaps of
+    /// up to 64KiB each and 256KiB in all.  Sampling whole lines with
+    /// `align_lines` reads each block separately.
+    pub fn compresstimate_file<P: AsRef<Path>>(&self, path: P) -> io::Result<f32> {
+        self.compresstimate_file_detailed(path).map(|e| e.ratio())
+    }
+
+    /// Compresstimate each of `paths` as with `compresstimate_file`, using up to
+    /// `jobs` threads, or one per CPU if `jobs` is 0.
+    ///
+    /// Every path gets its own result, so one failing doesn't stop the rest.
+    /// A path given more than once is only estimated once.
+    ///
+    /// The threads are this call's own, started for it and joined before it
+    /// returns, never a global pool, so it won't queue behind or hold up an
+    /// application's other parallel work beyond the CPU it uses.  To estimate
+    /// on an existing pool instead, such as rayon's, call `compresstimate_file`
+    /// from its tasks: a `Compresstimator` is `Send` and `Sync`, so one can be
+    /// shared between them by reference.
+    pub fn compresstimate_files_map(
+        &self,
+        paths: &[PathBuf],
+        jobs: usize,
+    ) -> HashMap<PathBuf, io::Result<f32>> {
+        let mut unique: Vec<&PathBuf> = paths.iter().collect();
+        unique.sort();
+        unique.dedup();
+
+        let jobs = match jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        }
+        .min(unique.len());
+
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        while let Some(path) = unique.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            results.push(((*path).clone(), self.compresstimate_file(path)));
+                        }
+                        results
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("estimating thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Compresstimate the file located at `path`, retrying the whole operation
+    /// on transient errors according to `policy`.
+    pub fn compresstimate_file_retry<P: AsRef<Path>>(
+        &self,
+        path: P,
+        policy: &RetryPolicy,
+    ) -> io::Result<f32> {
+        policy.run(|| self.compresstimate_file(path.as_ref()))
+    }
+
+    /// Compresstimate the file located at `path`, charging what's read to
+    /// `budget`, or return `None` if the budget runs out first.
+    ///
+    /// A file which exhausts the budget part way through is abandoned, not
+    /// estimated from what was read, so one can't masquerade as a complete
+    /// estimate.  Every byte read is charged, including a prefix dictionary,
+    /// the second read of each block with `verify_reads`, and what
+    /// `align_lines` reads on past a block.  The budget is checked after each
+    /// read, so it can be overrun by one read: a block when sampling, or
+    /// 64KiB when reading a file in full.  Samples aren't batched into
+    /// vectored reads, whose gaps would go uncharged.
+    pub fn compresstimate_file_budgeted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        budget: &ByteBudget,
+    ) -> io::Result<Option<f32>> {
+        if budget.is_exhausted() {
+            return Ok(None);
+        }
+
+        let mut file = self.open(path.as_ref())?;
+        let len = file_len(&mut file)?;
+
+        // Batched reads would read through gaps the budget never hears of
+        let plan = Plan {
+            trim: true,
+            file: Some(&file),
+            budget: Some(budget),
+            ..Plan::default()
+        };
+        let result = self.estimate_planned(&file, len, plan, &mut |_, _| Ok(()));
+
+        match result {
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<BudgetExhausted>()) => {
+                debug!("{}: byte budget exhausted", path.as_ref().display());
+                Ok(None)
+            }
+            result => result.map(|e| Some(e.ratio())),
+        }
+    }
+
+    /// Compresstimate the file located at `path` as with
+    /// `compresstimate_file_detailed`, first asking `classify` how, given the
+    /// path and its length.
+    ///
+    /// This keeps per-file-type policy in one place: the classifier can skip a
+    /// file, leave it to the estimator's configuration, or supply a `Config`
+    /// of its own, which is validated as by `reconfigure`.  The dictionary is
+    /// used either way.
+    pub fn compresstimate_file_classified<P, F>(
+        &self,
+        path: P,
+        classify: F,
+    ) -> io::Result<ClassifiedEstimate>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path, u64) -> SampleParams,
+    {
+        let path = path.as_ref();
+        let mut file = self.open(path)?;
+        let len = file_len(&mut file)?;
+
+        let estimate = match classify(path, len) {
+            SampleParams::Skip => {
+                debug!("{}: skipped by classifier", path.display());
+                return Ok(ClassifiedEstimate::Skipped);
+            }
+            SampleParams::Default => self.estimate_file(&file, len, &mut |_, _| Ok(()))?,
+            SampleParams::With(config) => {
+                let mut est = self.clone();
+                est.reconfigure(config)?;
+                est.estimate_file(&file, len, &mut |_, _| Ok(()))?
+            }
+        };
+
+        Ok(ClassifiedEstimate::Estimated(estimate))
+    }
+
+    /// Estimate the corpus-wide ratio of the tree under `path` from a random
+    /// `fraction` (0 to 1) of its files, for trees far too large to estimate
+    /// file by file.
+    ///
+    /// Whether a file is chosen depends only on its path and `seed`, so runs
+    /// with the same seed see the same files.  Each is estimated as with
+    /// `compresstimate_file_detailed`, and combined weighted by size.  Check
+    /// `files_sampled` before trusting the ratio: a small tree, or a small
+    /// fraction, may not pick enough files to say much.  Files are chosen and
+    /// estimated as the walk finds them, so only one directory's listing is
+    /// held at a time.  Symlinks to directories aren't followed.
+    pub fn compresstimate_tree_sampled<P: AsRef<Path>>(
+        &self,
+        path: P,
+        fraction: f64,
+        seed: u64,
+    ) -> io::Result<TreeEstimate> {
+        let (mut found, mut failed, mut dirs_failed) = (0, 0, 0);
+        let mut estimates = vec![];
+        tree::visit(path.as_ref(), &mut dirs_failed, &mut |file| {
+            found += 1;
+            if !tree::selected(&file, fraction, seed) {
+                return;
+            }
+
+            match self.compresstimate_file_detailed(&file) {
+                Ok(estimate) => estimates.push(estimate),
+                Err(e) => {
+                    debug!("{}: {}", file.display(), e);
+                    failed += 1;
+                }
+            }
+        })?;
+
+        Ok(TreeEstimate {
+            files_found: found,
+            files_sampled: estimates.len() as u64,
+            files_failed: failed,
+            dirs_failed,
+            total_original: estimates.iter().map(|e| e.total_original).sum(),
+            ratio: aggregate_ratio(&estimates, Weighting::ByOriginal),
+        })
+    }
+
+    /// Estimate the files under `new` which are new or changed since `old`,
+    /// such as two snapshots, to plan the storage of an incremental backup.
+    ///
+    /// Files are matched by their path relative to each root, and count as
+    /// changed if their size or modification time differs - contents aren't
+    /// compared, so this is cheap but can be fooled by a preserved mtime.
+    /// Deleted files are ignored.  Each is estimated as with
+    /// `compresstimate_file_detailed`.
+    pub fn compresstimate_tree_diff<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        old: P,
+        new: Q,
+    ) -> io::Result<TreeDiffEstimate> {
+        let mut failed = 0;
+        let files = tree::changed(old.as_ref(), new.as_ref(), &mut failed)?;
+
+        let mut estimates = vec![];
+        for file in &files {
+            match self.compresstimate_file_detailed(file) {
+                Ok(estimate) => estimates.push(estimate),
+                Err(e) => {
+                    debug!("{}: {}", file.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+
+        Ok(TreeDiffEstimate {
+            files_changed: estimates.len() as u64,
+            files_failed: failed,
+            changed_bytes: estimates.iter().map(|e| e.total_original).sum(),
+            ratio: aggregate_ratio(&estimates, Weighting::ByOriginal),
+        })
+    }
+
+    /// A compressibility sketch of up to `len` bytes from `input`: the ratios
+    /// of `windows` windows compressed on their own, at fixed fractions of
+    /// the way through, for comparing files for near-duplicates.
+    ///
+    /// Each window is a block long, and the `i`th of `n` starts `i / (n - 1)`
+    /// of the way from the start to the last whole block, so the first is at
+    /// the start and the last at the end, with a lone window at the start.
+    /// The offsets depend only on the length, so sketches of files of
+    /// similar size compare windows from corresponding places, and similar
+    /// content gives similar ratios, which can be compared with a distance
+    /// such as the mean absolute difference.  Windows overlap when there are
+    /// more than fit, along with anything shorter than a block, which is one
+    /// short window repeated; nothing at all is all 1.  The block size is
+    /// used as it is, without adapting it or trimming the input.
+    pub fn compresstimate_sketch<P: Read + Seek>(
+        &self,
+        mut input: P,
+        len: u64,
+        windows: usize,
+    ) -> io::Result<Vec<f32>> {
+        let start = input.stream_position()?;
+        let block_size = self.config.block_size.min(len);
+        let last = (len - block_size) as u128;
+        let mut buf = vec![0; block_size as usize];
+
+        (0..windows)
+            .map(|i| {
+                let offset = match windows {
+                    1 => 0,
+                    n => (i as u128 * last / (n as u128 - 1)) as u64,
+                };
+                input.seek(SeekFrom::Start(start + offset))?;
+                let n = read_full(&mut input, &mut buf)?;
+                self.compress_ratio(&buf[..n])
+            })
+            .collect()
+    }
+
+    /// How similar the files at `a` and `b` look to the compressor, from 0.0
+    /// for nothing in common to 1.0 for the same content.
+    ///
+    /// This is one minus the normalized compression distance: how much
+    /// smaller the two compress together than the larger of them does alone.
+    /// It's an approximation from the sampled blocks of each rather than the
+    /// whole files, which are interleaved rather than concatenated.  Where the
+    /// codec's window is known, blocks are kept to at most half of it, so each
+    /// block and its counterpart fall within the window together.  It works
+    /// best with files laid out alike, where the same sample offsets cover the
+    /// same sort of content.  Two empty files are the same.
+    pub fn similarity<A: AsRef<Path>, B: AsRef<Path>>(&self, a: A, b: B) -> io::Result<f32> {
+        let sample = |path: &Path| -> io::Result<Vec<Vec<u8>>> {
+            let mut file = self.open(path)?;
+            let len = file_len(&mut file)?;
+            let mut est = self.clone();
+            if let Some(log) = self.effective_window_log() {
+                est.config.block_size = self.planned_block_size(len).min(1 << (log - 1));
+                est.config.adaptive_block_size = false;
+            }
+            let (mut blocks, mut buf) = (vec![], Default::default());
+            est.estimate_file(&file, len, &mut |_, block| {
+                blocks.push(self.prepare(block, &mut buf).to_vec());
+                Ok(())
+            })?;
+            Ok(blocks)
+        };
+        let (a, b) = (sample(a.as_ref())?, sample(b.as_ref())?);
+
+        let compressed = |blocks: &mut dyn Iterator<Item = &Vec<u8>>| -> io::Result<u64> {
+            let mut encoder = self.compressor(&self.config.codec)?;
+            for block in blocks {
+                encoder.write_all(block)?;
+            }
+            encoder.finish()
+        };
+        let alone_a = compressed(&mut a.iter())?;
+        let alone_b = compressed(&mut b.iter())?;
+
+        let longest = a.len().max(b.len());
+        let together =
+            compressed(&mut (0..longest).flat_map(|i| a.get(i).into_iter().chain(b.get(i))))?;
+
+        let (min, max) = (alone_a.min(alone_b), alone_a.max(alone_b));
+        if a.iter().chain(&b).all(|block| block.is_empty()) || max == 0 {
+            return Ok(1.0);
+        }
+
+        let distance = together.saturating_sub(min) as f32 / max as f32;
+        Ok((1.0 - distance).clamp(0.0, 1.0))
+    }
+
+    /// Compresstimate what the file located at `path` decompresses to, if it's
+    /// gzip, zstd, xz, bzip2 or lz4, or the file itself if it's none of them.
+    ///
+    /// The format is recognised by its magic bytes.  lz4 is decompressed
+    /// in-process, and the others by running `gzip -dc` and so on, which must
+    /// be in `PATH`.  Since a decompressed stream can't seek and its length
+    /// isn't known up front, it's sampled as it's read forwards, keeping an
+    /// evenly spaced selection of between two and four times as many blocks
+    /// as a sampled estimate would take, thinning them out as the stream goes
+    /// on.  That means decompressing the whole file and holding a few MiB of
+    /// samples, and ignores trimming and the options which depend on knowing
+    /// the length.
+    pub fn compresstimate_decompressed<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> io::Result<DecompressedEstimate> {
+        let mut file = self.open(path.as_ref())?;
+        let mut magic = [0; InputFormat::MAGIC_LEN];
+        let n = read_full(&mut file, &mut magic)?;
+        let format = InputFormat::detect(&magic[..n]);
+        debug!("{}: {} input", path.as_ref().display(), format);
+
+        let estimate = if format == InputFormat::Plain {
+            let len = file_len(&mut file)?;
+            file.seek(SeekFrom::Start(0))?;
+            self.estimate_file(&file, len, &mut |_, _| Ok(()))?
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            self.estimate_stream(decompress::decompressed(format, file)?, None)?
+        };
+
+        Ok(DecompressedEstimate { format, estimate })
+    }
+
+    /// Compresstimate the file located at `path` as with
+    /// `compresstimate_with_quality`, falling back to compressing all of it
+    /// with `base_truth` if the estimate's quality is below `min_quality`.
+    ///
+    /// Files read in full have a quality of 1 and never need the second
+    /// pass.  The exhaustive pass reads through the page cache whether or
+    /// not direct I/O is enabled, and like `base_truth` ignores any trim.
+    pub fn compresstimate_or_exhaustive<P: AsRef<Path>>(
+        &self,
+        path: P,
+        min_quality: f32,
+    ) -> io::Result<FallbackEstimate> {
+        let path = path.as_ref();
+        let mut file = self.open(path)?;
+        let len = file_len(&mut file)?;
+        let sampled = self.compresstimate_with_quality(&file, len)?;
+        if sampled.quality >= min_quality {
+            return Ok(FallbackEstimate::Sampled(sampled));
+        }
+
+        debug!(
+            "{}: quality {:.2} below {:.2}, compressing it all",
+            path.display(),
+            sampled.quality,
+            min_quality
+        );
+        let ratio = self.base_truth(open(path, false)?)?;
+        Ok(FallbackEstimate::Exhaustive { sampled, ratio })
+    }
+
+    /// Compresstimate the data from an iterator of `chunks`, such as a
+    /// chunk-oriented reader, returning an estimated compression ratio.
+    ///
+    /// Chunks are read as one stream, forwards only, so blocks are taken
+    /// regardless of where one chunk ends and the next begins, and chunks
+    /// can be any size, even empty.  Every chunk is still pulled from the
+    /// iterator, since there's no skipping ahead.  Without `len_hint`, the
+    /// sample is thinned as the stream grows, keeping every block at first,
+    /// then every other one, and so on; with it, blocks are taken at the
+    /// stride which spreads the samples needed over that length, as if it
+    /// could seek.  A hint which turns out too short still gives an even
+    /// sample, just a larger one than needed.  The first error from the
+    /// iterator fails the estimate.
+    pub fn compresstimate_chunks<I>(&self, chunks: I, len_hint: Option<u64>) -> io::Result<f32>
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>>,
+    {
+        self.estimate_stream(ChunkReader::new(chunks), len_hint)
+            .map(|e| e.ratio())
+    }
+
+    /// Compresstimate the file located at `path`, returning the sampled and
+    /// extrapolated sizes rather than just the ratio.
+    pub fn compresstimate_file_detailed<P: AsRef<Path>>(&self, path: P) -> io::Result<Estimate> {
+        let mut file = self.open(path.as_ref())?;
+        let len = file_len(&mut file)?;
+        self.estimate_file(&file, len, &mut |_, _| Ok(()))
+    }
+}
+
+/// A xorshift32 generator starting from `seed`, for test data that's the same
+/// every run.
+#[cfg(test)]
+fn xorshift(mut x: u32) -> impl FnMut() -> u32 {
+    move || {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        x
+    }
+}
+
+/// `len` bytes of incompressible noise from `seed`.
+#[cfg(test)]
+fn noise(seed: u32, len: usize) -> Vec<u8> {
+    let mut next = xorshift(seed);
+    (0..len).map(|_| next() as u8).collect()
+}
+
+/// `lines` lines of log-like text from `seed`, which compresses about as well
+/// as source code.
+#[cfg(test)]
+fn log_text(seed: u32, lines: usize) -> Vec<u8> {
+    let mut next = xorshift(seed);
+    (0..lines)
+        .flat_map(|i| {
+            let x = next();
+            format!("{} sensor {} reads {}\n", i, x % 8, x % 1000).into_bytes()
+        })
+        .collect()
+}
+
+/// 16MiB of 4KiB blocks from `seed`, each either noise or `filler` over and
+/// over, shuffled together in about equal measure.
+#[cfg(test)]
+fn mixed_blocks(seed: u32, filler: &[u8]) -> Vec<u8> {
+    let mut next = xorshift(seed);
+    let mut mixed = Vec::new();
+    for _ in 0..4096 {
+        if next() % 2 == 0 {
+            mixed.extend((0..4096).map(|_| next() as u8));
+        } else {
+            mixed.extend(filler.iter().cycle().take(4096));
+        }
+    }
+    mixed
+}
+
+#[test]
+fn amazing_test_suite() {
+    let est = Compresstimator::default();
+
+    assert!(est.compresstimate_file("Cargo.lock").expect("Cargo.lock") < 1.0);
+
+    let empty = vec![];
+    assert!(
+        est.compresstimate(std::io::Cursor::new(empty))
+            .expect("empty should work")
+            == 1.0
+    );
+
+    if std::path::PathBuf::from("/dev/urandom").exists() {
+        assert!(
+            est.compresstimate_file_len("/dev/urandom", 1024 * 1024)
+                .expect("/dev/urandom")
+                >= 1.0
+        );
+    }
+}
+
+#[test]
+fn base_truth_until() {
+    let est = Compresstimator::default();
+
+    let zeros = vec![0; 4 * 1024 * 1024];
+    let ratio = est
+        .base_truth_until(&zeros[..], 0.5)
+        .expect("zeros should work")
+        .expect("zeros should compress below 0.5");
+    assert!(ratio < 0.5);
+    assert_eq!(ratio, est.base_truth(&zeros[..]).expect("base truth"));
+
+    // Noise gives up as soon as the minimum's been read
+    let noise = noise(0xab07, 8 * 1024 * 1024);
+    let mut rest = &noise[..];
+    assert!(est
+        .base_truth_until(&mut rest, 0.5)
+        .expect("noise")
+        .is_none());
+    assert_eq!(noise.len() - rest.len(), BASE_TRUTH_MIN_READ as usize);
+
+    if std::path::PathBuf::from("/dev/urandom").exists() {
+        let random = File::open("/dev/urandom").expect("/dev/urandom");
+        assert!(est
+            .base_truth_until(random.take(8 * 1024 * 1024), 0.5)
+            .expect("/dev/urandom")
+            .is_none());
+    }
+}
+
+#[test]
+fn base_truth_in_chunks() {
+    let data: Vec<u8> = (0..1_000_000u32)
+        .map(|i| (i % 251 * (i % 7)) as u8)
+        .collect();
+    let est = Compresstimator::default();
+    let whole = est.base_truth(&data[..]).expect("base_truth");
+
+    let mut progress = est.base_truth_chunked(&data[..], 200_000).expect("start");
+    let mut calls = 1;
+    let ratio = loop {
+        match progress {
+            BaseTruthProgress::Pending(state) => {
+                // Rounded up to four 64KiB reads a call
+                assert_eq!(state.bytes_read(), (calls * 4 * 65536).min(1_000_000));
+                progress = state.resume().expect("resume");
+                calls += 1;
+            }
+            BaseTruthProgress::Done(ratio) => break ratio,
+        }
+    };
+    // The end is only found by the call after the last bytes are read
+    assert_eq!(calls, 5);
+    assert_eq!(ratio, whole);
+
+    match est.base_truth_chunked(&[][..], 0).expect("empty") {
+        BaseTruthProgress::Done(ratio) => assert_eq!(ratio, 1.0),
+        pending => panic!("{:?}", pending),
+    }
+}
+
+#[test]
+fn hashed_sees_what_was_read() {
+    let est = Compresstimator::default();
+    let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let mut seen = vec![];
+    est.base_truth_hashed(&data[..], &mut seen)
+        .expect("base_truth_hashed");
+    assert_eq!(seen, data);
+
+    let mut seen = vec![];
+    est.compresstimate_len_hashed(std::io::Cursor::new(&data), data.len() as u64, &mut seen)
+        .expect("compresstimate_len_hashed");
+    assert!(!seen.is_empty());
+    assert!(seen.len() <= data.len());
+}
+
+#[test]
+fn aligned_sample_offsets() {
+    let mut est = Compresstimator::with_block_size(4096);
+    est.align_samples(true);
+
+    for start in [0, 1, 1000, 4095, 4096, 12345] {
+        let offsets: Vec<u64> = est.sample_offsets(start, 1000, 30).collect();
+        assert_eq!(offsets.len(), 30);
+        assert!(offsets.iter().all(|o| o % 4096 == 0 && *o >= start));
+        assert!(offsets.windows(2).all(|w| w[1] >= w[0] + 4096));
+    }
+
+    let data: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut cursor = std::io::Cursor::new(&data);
+    cursor.set_position(1000);
+    assert!(est.compresstimate(cursor).expect("aligned compresstimate") < 1.0);
+}
+
+#[test]
+fn detailed_estimate() {
+    let est = Compresstimator::default();
+    let data: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let detail = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("detailed");
+    assert_eq!(detail.total_original, data.len() as u64);
+    assert!(detail.sampled_original < detail.total_original);
+    assert!(detail.sampled_compressed < detail.sampled_original);
+    assert!(detail.extrapolated_compressed < detail.total_original);
+
+    let empty = est
+        .compresstimate_len_detailed(std::io::Cursor::new(vec![]), 0)
+        .expect("empty");
+    assert_eq!(empty.ratio(), 1.0);
+    assert_eq!(empty.extrapolated_compressed, 0);
+}
+
+#[test]
+fn forced_exhaustive() {
+    let mut est = Compresstimator::default();
+    est.force_exhaustive(true);
+    let data: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let detail = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("detailed");
+    assert_eq!(detail.sampled_original, detail.total_original);
+    assert_eq!(
+        detail.ratio(),
+        est.base_truth(&data[..]).expect("base_truth")
+    );
+}
+
+#[test]
+fn retry_policy() {
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_millis(1),
+    };
+
+    let mut calls = 0;
+    let result = policy.run(|| {
+        calls += 1;
+        if calls < 3 {
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        } else {
+            Ok(calls)
+        }
+    });
+    assert_eq!(result.expect("third attempt succeeds"), 3);
+
+    let mut calls = 0;
+    let result: io::Result<()> = policy.run(|| {
+        calls += 1;
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+
+    let mut calls = 0;
+    let result: io::Result<()> = policy.run(|| {
+        calls += 1;
+        Err(io::Error::from(io::ErrorKind::Interrupted))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn first_block_comparison() {
+    let est = Compresstimator::default();
+
+    // A compressible start followed by noise: the first block is optimistic
+    let mut data = vec![0; 4096];
+    data.extend(noise(1, 8 * 1024 * 1024));
+
+    let cmp = est
+        .compare_first_block(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("compare_first_block");
+    assert!(cmp.first_block < 0.1);
+    assert!(cmp.sampled.ratio() > 0.9);
+    assert!(cmp.divergence() > 0.8);
+}
+
+#[test]
+fn compression_levels() {
+    let est = Compresstimator::default();
+    let text = log_text(0x1e7e1, 400_000);
+
+    let ratios = est
+        .compresstimate_levels(std::io::Cursor::new(&text), text.len() as u64, &[1, 9])
+        .expect("compresstimate_levels");
+    assert_eq!(ratios.len(), 2);
+    assert!(ratios[1] <= ratios[0]);
+    assert_eq!(
+        ratios[0],
+        est.compresstimate_len(std::io::Cursor::new(&text), text.len() as u64)
+            .expect("compresstimate_len")
+    );
+}
+
+#[test]
+fn allocation_unit() {
+    let mut est = Compresstimator::default();
+    est.min_allocation_unit(4096);
+
+    let detail = est
+        .compresstimate_len_detailed(std::io::Cursor::new(vec![0; 1000]), 1000)
+        .expect("detailed");
+    assert!(detail.extrapolated_compressed < 100);
+    assert_eq!(detail.estimated_allocated_len, 4096);
+}
+
+#[test]
+fn container_overhead() {
+    let mut est = Compresstimator::default();
+    est.container_overhead(512);
+
+    let detail = est
+        .compresstimate_len_detailed(std::io::Cursor::new(vec![0; 1000]), 1000)
+        .expect("detailed");
+    assert!(detail.ratio() < 0.1);
+    assert_eq!(
+        detail.estimated_allocated_len,
+        detail.extrapolated_compressed + 512
+    );
+    assert!(detail.stored_ratio() > 0.5);
+
+    // Added before rounding up
+    est.min_allocation_unit(4096);
+    let detail = est
+        .compresstimate_len_detailed(std::io::Cursor::new(vec![0; 4000]), 4000)
+        .expect("detailed");
+    assert_eq!(detail.estimated_allocated_len, 4096);
+    assert!((detail.stored_ratio() - 4096.0 / 4000.0).abs() < 1e-9);
+}
+
+#[test]
+fn shareable() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Compresstimator>();
+    assert_send_sync::<Estimate>();
+}
+
+#[test]
+fn window_limit() {
+    let mut est = Compresstimator::default();
+    assert_eq!(est.effective_window_log(), Some(16));
+    est.max_window_log(Some(12));
+    assert_eq!(est.effective_window_log(), Some(16));
+
+    est.codec(Codec::from_param_str("zstd:level=19,long=31").expect("zstd"))
+        .max_window_log(Some(24));
+    assert_eq!(est.effective_window_log(), Some(24));
+    est.max_window_log(None);
+    assert_eq!(est.effective_window_log(), Some(31));
+}
+
+#[test]
+fn reconfigure() {
+    let mut est = Compresstimator::default();
+
+    let config = Config {
+        block_size: 8192,
+        confidence: Confidence::C99,
+        ..Config::default()
+    };
+    est.reconfigure(config.clone()).expect("valid config");
+    assert_eq!(*est.config(), config);
+
+    let bad = Config {
+        block_size: 65536,
+        error_margin: 1.5,
+        ..Config::default()
+    };
+    assert_eq!(
+        est.reconfigure(bad),
+        Err(CompresstimatorError::InvalidErrorMargin(1.5))
+    );
+    assert_eq!(*est.config(), config);
+}
+
+#[test]
+fn short_stream_end() {
+    // A stream which claims to end before wherever it currently is
+    struct Liar(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Liar {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for Liar {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(_) => Ok(10),
+                pos => self.0.seek(pos),
+            }
+        }
+    }
+
+    let mut liar = Liar(std::io::Cursor::new(vec![0; 100]));
+    liar.seek(SeekFrom::Start(50)).expect("seek");
+
+    let err = Compresstimator::default()
+        .compresstimate(liar)
+        .expect_err("should fail");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.into_inner()
+            .and_then(|e| e.downcast::<CompresstimatorError>().ok())
+            .map(|e| *e),
+        Some(CompresstimatorError::InvalidStreamLength {
+            position: 50,
+            end: 10
+        })
+    );
+}
+
+#[test]
+fn samples_never_overlap() {
+    let est = Compresstimator::with_block_size(4096);
+
+    for (blocks, samples) in [(10, 10), (10, 30), (7, 3), (1, 5), (0, 5), (5, 0)] {
+        let offsets: Vec<u64> = est.sample_offsets(0, blocks, samples).collect();
+        assert!(offsets.len() as u64 <= blocks.min(samples));
+        assert!(offsets.windows(2).all(|w| w[1] >= w[0] + 4096));
+        assert!(offsets.iter().all(|o| o + 4096 <= blocks * 4096));
+    }
+}
+
+#[test]
+fn external_codec() {
+    let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut est = Compresstimator::default();
+
+    est.codec(Codec::External(ExternalCommandCodec::new(
+        "compresstimator-no-such-program",
+        &["-c"],
+    )));
+    let err = est.base_truth(&data[..]).expect_err("missing program");
+    assert!(err
+        .get_ref()
+        .is_some_and(|e| e.is::<CompresstimatorError>()));
+
+    if std::process::Command::new("gzip")
+        .arg("--version")
+        .output()
+        .is_ok()
+    {
+        est.codec(Codec::External(ExternalCommandCodec::new("gzip", &["-c"])));
+        let ratio = est
+            .compresstimate_len(std::io::Cursor::new(&data), data.len() as u64)
+            .expect("gzip");
+        assert!(ratio < 0.1);
+    }
+}
+
+#[test]
+fn raw_memory() {
+    let est = Compresstimator::default();
+    let data = vec![0u8; 64 * 1024];
+
+    let ratio = unsafe { est.compresstimate_raw(data.as_ptr(), data.len()) };
+    assert!(ratio.expect("raw") < 0.1);
+
+    let empty = unsafe { est.compresstimate_raw(std::ptr::null(), 0) };
+    assert_eq!(empty.expect("empty"), 1.0);
+
+    let null = unsafe { est.compresstimate_raw(std::ptr::null(), 10) };
+    assert!(null.is_err());
+}
+
+#[test]
+fn block_percentiles() {
+    let est = Compresstimator::default();
+
+    // Mostly zeros, with a pocket of noise a tenth of the size
+    let mut data = vec![0; 9 * 1024 * 1024];
+    data.extend(noise(1, 1024 * 1024));
+
+    let p = est
+        .compresstimate_percentiles(
+            std::io::Cursor::new(&data),
+            data.len() as u64,
+            &[50.0, 99.0],
+        )
+        .expect("percentiles");
+    assert!(p[0] < 0.1);
+    assert!(p[1] > 0.9);
+}
+
+#[test]
+fn appended_estimate() {
+    let est = Compresstimator::default();
+
+    // 32MiB of zeros, then 32MiB of noise appended
+    let mut data = vec![0; 32 * 1024 * 1024];
+    let before = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("before");
+
+    data.extend(noise(1, 32 * 1024 * 1024));
+
+    let after = est
+        .compresstimate_appended(std::io::Cursor::new(&data), &before, data.len() as u64)
+        .expect("after");
+    assert_eq!(after.total_original, data.len() as u64);
+    assert!(after.sampled_original < 2 * before.sampled_original + 4096);
+    assert!((after.ratio() - 0.5).abs() < 0.05);
+
+    let err = est
+        .compresstimate_appended(std::io::Cursor::new(&data), &after, 1024)
+        .expect_err("shrunk");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn dedup_estimate() {
+    let est = Compresstimator::default();
+
+    // Eight distinct blocks, repeated four times over
+    let data: Vec<u8> = (0..32).flat_map(|n| noise(n % 8 + 1, 4096)).collect();
+
+    let dedup = est
+        .compresstimate_dedup(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("dedup");
+    assert_eq!(dedup.duplicate_fraction, 0.75);
+    assert!(dedup.combined_ratio() < dedup.sampled.ratio());
+}
+
+#[test]
+fn shared_dictionary() {
+    // A small record which, on its own, lz4 can do little with
+    let record = br#"{"timestamp":"2019-06-01T12:00:00Z","level":"info","service":"ingest","message":"accepted batch","batch_id":4471}"#;
+    let dictionary: Vec<u8> = (0..64)
+        .flat_map(|i| {
+            format!(
+                r#"{{"timestamp":"2019-05-{:02}T0{}:30:00Z","level":"info","service":"ingest","message":"accepted batch","batch_id":{}}}"#,
+                i % 28 + 1,
+                i % 10,
+                1000 + i * 7
+            )
+            .into_bytes()
+        })
+        .collect();
+
+    let mut est = Compresstimator::default();
+    let alone = est
+        .compresstimate(std::io::Cursor::new(&record[..]))
+        .expect("alone");
+
+    est.dictionary(dictionary);
+    let primed = est
+        .compresstimate(std::io::Cursor::new(&record[..]))
+        .expect("primed");
+
+    assert!(alone > 0.9, "alone: {}", alone);
+    assert!(primed < alone / 2.0, "primed: {} vs {}", primed, alone);
+}
+
+#[test]
+fn block_larger_than_input() {
+    let est = Compresstimator::with_block_size(1024 * 1024 * 1024);
+    let text = log_text(0xb10c, 10_000);
+    let path = std::env::temp_dir().join(format!("compresstimator-larger-{}", std::process::id()));
+    std::fs::write(&path, &text).expect("write");
+
+    let ratio = est.compresstimate_file_len(&path, text.len() as u64);
+    std::fs::remove_file(&path).expect("cleanup");
+    let ratio = ratio.expect("estimate");
+    let actual = est.base_truth(io::Cursor::new(&text)).expect("base truth");
+    assert!((ratio - actual).abs() < 0.01, "{} vs {}", ratio, actual);
+}
+
+#[test]
+fn validate() {
+    let mut est = Compresstimator::default();
+    assert_eq!(est.validate(), Ok(()));
+
+    est.set_block_size(0);
+    assert_eq!(est.validate(), Err(CompresstimatorError::ZeroBlockSize));
+
+    est.set_block_size(4096);
+    est.codec(Codec::Lz4 { level: 17 });
+    assert!(matches!(
+        est.validate(),
+        Err(CompresstimatorError::Codec { .. })
+    ));
+
+    est.codec(Codec::External(ExternalCommandCodec::new("", &["-c"])));
+    assert!(est.validate().is_err());
+}
+
+#[test]
+fn trimmed_estimate() {
+    // Incompressible header and footer around a compressible body
+    let mut data = noise(1, 256 * 1024);
+    data.extend(vec![0; 1024 * 1024]);
+    data.extend(noise(2, 256 * 1024));
+
+    let mut est = Compresstimator::default();
+    let whole = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("whole");
+
+    est.with_trim(256 * 1024, 256 * 1024);
+    let body = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), data.len() as u64)
+        .expect("body");
+    assert_eq!(body.total_original, 1024 * 1024);
+    assert!(body.ratio() < 0.1);
+    assert!(whole.ratio() > 0.15);
+
+    let short = est
+        .compresstimate_len_detailed(std::io::Cursor::new(&data), 1024)
+        .expect("short");
+    assert_eq!(short.total_original, 0);
+    assert_eq!(short.ratio(), 1.0);
+}
+
+#[test]
+fn classified_files() {
+    let est = Compresstimator::default();
+    let classify = |path: &Path, len: u64| match path.extension().and_then(|e| e.to_str()) {
+        Some("lock") => SampleParams::Skip,
+        Some("rs") if len > 1024 => {
+            SampleParams::With(Compresstimator::thorough().config().clone())
+        }
+        _ => SampleParams::Default,
+    };
+
+    let dir =
+        std::env::temp_dir().join(format!("compresstimator-classified-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("dir");
+    let (lock, source) = (dir.join("deps.lock"), dir.join("main.rs"));
+    std::fs::write(&lock, log_text(0x10c, 1000)).expect("lock");
+    std::fs::write(&source, log_text(0x5ec, 1000)).expect("source");
+
+    let skipped = est.compresstimate_file_classified(&lock, classify);
+    let estimated = est.compresstimate_file_classified(&source, classify);
+    let invalid = |_: &Path, _| {
+        SampleParams::With(Config {
+            block_size: 0,
+            ..Config::default()
+        })
+    };
+    let invalid = est.compresstimate_file_classified(&lock, invalid);
+    std::fs::remove_dir_all(&dir).expect("cleanup");
+
+    assert_eq!(skipped.expect("lock"), ClassifiedEstimate::Skipped);
+    assert!(matches!(
+        estimated,
+        Ok(ClassifiedEstimate::Estimated(e)) if e.ratio() < 1.0
+    ));
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn records() {
+    let est = Compresstimator::default();
+
+    let rows: Vec<Vec<u8>> = (0..100_000)
+        .map(|i| format!("{{\"id\":{},\"status\":\"ok\"}}", i).into_bytes())
+        .collect();
+    let records: Vec<&[u8]> = rows.iter().map(|r| &r[..]).collect();
+
+    let ratio = est.compresstimate_records(&records).expect("records");
+    let actual = est.base_truth(&rows.concat()[..]).expect("base truth");
+    assert!((ratio - actual).abs() < 0.1, "{} vs {}", ratio, actual);
+
+    assert_eq!(est.compresstimate_records(&[]).expect("empty"), 1.0);
+
+    // Fewer records than samples, but plenty of data
+    let big = vec![0; 16 * 1024 * 1024];
+    assert!(
+        est.compresstimate_records(&[&big[..], &big[..]])
+            .expect("big")
+            < 0.1
+    );
+
+    // Records far larger than a block are only sampled a block at a time
+    let mut large = vec![0; 4096];
+    large.extend(noise(0x4ec0_14d5, 1 << 20));
+    assert!(
+        est.compresstimate_records(&vec![&large[..]; 2000])
+            .expect("large")
+            < 0.1
+    );
+
+    // Records are prepared as blocks are
+    let padded: Vec<Vec<u8>> = rows.iter().map(|r| [&r[..], &[0; 64]].concat()).collect();
+    let padded: Vec<&[u8]> = padded.iter().map(|r| &r[..]).collect();
+    let mut stripped = Compresstimator::default();
+    stripped.with_strip_padding(0);
+    let unpadded = stripped.compresstimate_records(&padded).expect("stripped");
+    assert!((unpadded - ratio).abs() < 0.05, "{} vs {}", unpadded, ratio);
+    assert!(est.compresstimate_records(&padded).expect("padded") < unpadded - 0.05);
+
+    let mut transformed = Compresstimator::default();
+    transformed.with_transform(Box::new(|record: &mut [u8]| record.fill(b'x')));
+    assert!(
+        transformed
+            .compresstimate_records(&records)
+            .expect("transformed")
+            < 0.1
+    );
+
+    let mut packed = Compresstimator::default();
+    packed.with_rle_preanalysis(true);
+    let big = [&big[..], &big[..]];
+    assert!(
+        packed.compresstimate_records(&big).expect("packed")
+            < est.compresstimate_records(&big).expect("unpacked")
+    );
+}
+
+#[test]
+fn budget_fits() {
+    let est = Compresstimator::default();
+    let zeros = vec![0; 8 * 1024 * 1024];
+    let len = zeros.len() as u64;
+
+    assert!(est
+        .fits_under(std::io::Cursor::new(&zeros), len, len / 10)
+        .expect("zeros"));
+    assert!(est
+        .fits_under(std::io::Cursor::new(&zeros), len, len + 1)
+        .expect("short"));
+
+    let noise = noise(1, 8 * 1024 * 1024);
+    assert!(!est
+        .fits_under(std::io::Cursor::new(&noise), len, len / 2)
+        .expect("noise"));
+}
+
+#[test]
+fn sampled_tree() {
+    let est = Compresstimator::default();
+
+    let all = est.compresstimate_tree_sampled("src", 1.0, 0).expect("all");
+    assert!(all.files_found > 10);
+    assert_eq!(all.files_sampled + all.files_failed, all.files_found);
+    assert_eq!(all.dirs_failed, 0);
+    assert!(all.ratio < 1.0);
+
+    let some = est
+        .compresstimate_tree_sampled("src", 0.5, 42)
+        .expect("some");
+    assert!(some.files_sampled < some.files_found);
+    assert_eq!(
+        est.compresstimate_tree_sampled("src", 0.5, 42)
+            .expect("again"),
+        some
+    );
+
+    assert!(est.compresstimate_tree_sampled("nope", 1.0, 0).is_err());
+}
+
+#[test]
+fn transformed_blocks() {
+    // A random walk, which delta encoding turns into a handful of step sizes
+    let (mut next, mut level) = (xorshift(1), 0u8);
+    let data: Vec<u8> = (0..1024 * 1024)
+        .map(|_| {
+            level = level.wrapping_add((next() % 4) as u8);
+            level
+        })
+        .collect();
+
+    let mut est = Compresstimator::default();
+    let plain = est
+        .compresstimate(std::io::Cursor::new(&data))
+        .expect("plain");
+
+    est.with_transform(Box::new(|block: &mut [u8]| {
+        for i in (1..block.len()).rev() {
+            block[i] = block[i].wrapping_sub(block[i - 1]);
+        }
+    }));
+    let delta = est
+        .compresstimate(std::io::Cursor::new(&data))
+        .expect("delta");
+
+    assert!(delta < plain, "{} vs {}", delta, plain);
+    assert_eq!(est.base_truth(&data[..]).expect("base truth"), plain);
+
+    // Encoders for other levels and codecs see transformed blocks too
+    let levels = est
+        .compresstimate_levels(std::io::Cursor::new(&data), data.len() as u64, &[1])
+        .expect("levels");
+    assert_eq!(levels, [delta]);
+    est.with_shuffle(4);
+    let shuffled = est
+        .compresstimate(std::io::Cursor::new(&data))
+        .expect("shuffled");
+    let codecs = est
+        .compresstimate_codecs(
+            std::io::Cursor::new(&data),
+            data.len() as u64,
+            &[Codec::default()],
+        )
+        .expect("codecs");
+    assert_eq!(codecs[0].ratio(), shuffled);
+
+    // Replaced transforms are freed, along with whatever they hold
+    let held = Arc::new(());
+    let holder = Arc::clone(&held);
+    est.with_transform(Box::new(move |_: &mut [u8]| {
+        let _ = &holder;
+    }));
+    let copy = est.clone();
+    est.with_shuffle(4);
+    drop(copy);
+    assert_eq!(Arc::strong_count(&held), 1);
+}
+
+#[test]
+fn window_sweep() {
+    let data = vec![0u8; 256 * 1024];
+    let mut est = Compresstimator::default();
+    assert!(est
+        .compresstimate_window_sweep(std::io::Cursor::new(&data), data.len() as u64, &[1])
+        .is_err());
+
+    // A stand-in compressor which outputs the first `window` bytes it's
+    // given, and fails without a window
+    est.codec(Codec::External(ExternalCommandCodec::new(
+        "sh",
+        &["-c", "head -c {window} && cat >/dev/null"],
+    )));
+    est.force_exhaustive(true);
+    let ratios = est
+        .compresstimate_window_sweep(
+            std::io::Cursor::new(&data),
+            data.len() as u64,
+            &[64 * 1024, 128 * 1024],
+        )
+        .expect("sweep");
+    assert_eq!(ratios, vec![0.25, 0.5]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn direct_io() {
+    let mut est = Compresstimator::default();
+    est.with_direct_io(true);
+    assert_eq!(est.validate(), Ok(()));
+
+    let path = std::env::temp_dir().join(format!("compresstimator-direct-{}", std::process::id()));
+    std::fs::write(&path, log_text(0xd1, 100_000)).expect("write");
+    let expected = Compresstimator::default().compresstimate_file(&path);
+    let direct = est.compresstimate_file(&path);
+    std::fs::remove_file(&path).expect("cleanup");
+
+    let expected = expected.expect("buffered");
+    match direct {
+        Ok(ratio) => assert_eq!(ratio, expected),
+        // Not every filesystem supports it
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+        Err(e) => panic!("direct: {}", e),
+    }
+
+    est.set_block_size(1000);
+    assert!(matches!(
+        est.validate(),
+        Err(CompresstimatorError::DirectIoAlignment { .. })
+    ));
+}
+
+#[test]
+fn line_aligned() {
+    let mut text = String::new();
+    // Enough to be sampled rather than read in full
+    for i in 0..200_000 {
+        text.push_str(&format!("record {} of the log, status ok\n", i));
+    }
+
+    let mut est = Compresstimator::default();
+    est.align_lines(true);
+
+    let mut samples = Vec::new();
+    est.compresstimate_len_hashed(
+        io::Cursor::new(text.as_bytes()),
+        text.len() as u64,
+        &mut samples,
+    )
+    .expect("estimate");
+    let sampled = String::from_utf8(samples).expect("utf-8");
+    assert!(!sampled.is_empty() && sampled.len() < text.len() / 2);
+    assert!(sampled
+        .lines()
+        .all(|l| l.starts_with("record ") && l.ends_with(" status ok")));
+
+    let whole = Compresstimator::default()
+        .base_truth(text.as_bytes())
+        .expect("base truth");
+    let ratio = est
+        .compresstimate_len(io::Cursor::new(text.as_bytes()), text.len() as u64)
+        .expect("estimate");
+    assert!((ratio - whole).abs() < 0.1);
+}
+
+#[test]
+fn incompressible() {
+    let noise = noise(0x9e37_79b9, 1 << 20);
+
+    let est = Compresstimator::default();
+    let random = est
+        .compresstimate_len_detailed(io::Cursor::new(&noise), noise.len() as u64)
+        .expect("estimate");
+    assert!(random.likely_incompressible());
+
+    let zeros = vec![0; 1 << 20];
+    let zeros = est
+        .compresstimate_len_detailed(io::Cursor::new(&zeros), zeros.len() as u64)
+        .expect("estimate");
+    assert!(!zeros.likely_incompressible());
+}
+
+#[test]
+fn byte_budget() {
+    let est = Compresstimator::default();
+    let text = log_text(0xb7d, 1000);
+    let len = text.len() as u64;
+    let path = std::env::temp_dir().join(format!("compresstimator-budget-{}", std::process::id()));
+    std::fs::write(&path, &text).expect("write");
+
+    let budget = ByteBudget::new(len * 2 + 1);
+    let estimate = || est.compresstimate_file_budgeted(&path, &budget);
+    let fitting = [estimate(), estimate()];
+    let remaining = budget.remaining();
+    let over = estimate();
+    let exhausted = budget.is_exhausted();
+    let after = estimate();
+
+    // Verifying reads the file twice, and both are charged
+    let mut verified = Compresstimator::default();
+    verified.verify_reads(true);
+    let twice = ByteBudget::new(len * 2 + 1);
+    let verified = verified.compresstimate_file_budgeted(&path, &twice);
+    std::fs::remove_file(&path).expect("cleanup");
+
+    for ratio in fitting {
+        assert!(ratio.expect("estimate").is_some());
+    }
+    assert_eq!(remaining, 1);
+
+    // Not enough for another, which leaves nothing for any after it
+    assert_eq!(over.expect("estimate"), None);
+    assert!(exhausted);
+    assert_eq!(after.expect("estimate"), None);
+
+    assert!(verified.expect("verified").is_some());
+    assert_eq!(twice.remaining(), 1);
+}
+
+#[test]
+fn basis_points() {
+    let estimate = |sampled_original, sampled_compressed| {
+        Estimate::new(sampled_original, sampled_compressed, sampled_original, 1, 0)
+    };
+    assert_eq!(estimate(100_000, 42_310).ratio_bp(), 4231);
+    assert_eq!(estimate(100_000, 42_315).ratio_bp(), 4232);
+    assert_eq!(estimate(100_000, 42_314).ratio_bp(), 4231);
+    assert_eq!(estimate(100, 0).ratio_bp(), 0);
+    assert_eq!(estimate(100, 120).ratio_bp(), 10_000);
+    assert_eq!(estimate(0, 0).ratio_bp(), 10_000);
+}
+
+#[test]
+fn tree_diff() {
+    use std::fs;
+
+    let root = std::env::temp_dir().join(format!("compresstimator-diff-{}", std::process::id()));
+    let (old, new) = (root.join("old"), root.join("new"));
+    fs::create_dir_all(&old).expect("old");
+    fs::create_dir_all(new.join("sub")).expect("new");
+
+    let text = log_text(0xd1ff, 1000);
+    for dir in [&old, &new] {
+        fs::write(dir.join("same"), &text).expect("same");
+        fs::write(dir.join("edited"), &text).expect("edited");
+    }
+    let mtime = fs::metadata(old.join("same"))
+        .and_then(|m| m.modified())
+        .expect("mtime");
+    File::options()
+        .write(true)
+        .open(new.join("same"))
+        .and_then(|f| f.set_modified(mtime))
+        .expect("set mtime");
+    fs::write(new.join("edited"), [&text[..], b"more"].concat()).expect("edit");
+    fs::write(new.join("sub").join("added"), vec![0; 100_000]).expect("added");
+
+    let est = Compresstimator::default();
+    let diff = est.compresstimate_tree_diff(&old, &new);
+    let unchanged = est.compresstimate_tree_diff(&old, &old);
+    fs::remove_dir_all(&root).expect("cleanup");
+
+    let diff = diff.expect("diff");
+    assert_eq!(diff.files_changed, 2);
+    assert_eq!(diff.files_failed, 0);
+    assert_eq!(diff.changed_bytes, text.len() as u64 + 4 + 100_000);
+    assert!(diff.ratio < 0.5);
+
+    assert_eq!(unchanged.expect("unchanged").files_changed, 0);
+}
+
+#[test]
+fn adaptive_blocks() {
+    const MIB: u64 = 1024 * 1024;
+    assert_eq!(adaptive_block_size(0, 4096), 4096);
+    assert_eq!(adaptive_block_size(512 * MIB, 4096), 8192);
+    assert_eq!(adaptive_block_size(512 * MIB - 1, 4096), 4096);
+    assert_eq!(adaptive_block_size(2048 * MIB, 4096), 32768);
+    assert_eq!(adaptive_block_size(2048 * MIB, 65536), 65536);
+    assert_eq!(adaptive_block_size(1 << 50, 4096), MIB);
+
+    let mut est = Compresstimator::default();
+    assert!(est.config().adaptive_block_size);
+    est.set_block_size(8192);
+    assert!(!est.config().adaptive_block_size);
+    assert!(
+        !Compresstimator::with_block_size(8192)
+            .config()
+            .adaptive_block_size
+    );
+}
+
+#[test]
+fn early_exit() {
+    let noise = noise(0x1234_5678, 8 << 20);
+    let zeros = vec![0; 8 << 20];
+
+    let mut est = Compresstimator::default();
+    est.early_exit(Some(EarlyExit::default()));
+
+    let random = est
+        .compresstimate_len_detailed(io::Cursor::new(&noise), noise.len() as u64)
+        .expect("random");
+    assert_eq!(random.sampled_original, 8 * 4096);
+    assert!(random.ratio() >= 0.98);
+
+    let normal = Compresstimator::default()
+        .compresstimate_len_detailed(io::Cursor::new(&zeros), zeros.len() as u64)
+        .expect("zeros");
+    assert_eq!(
+        est.compresstimate_len_detailed(io::Cursor::new(&zeros), zeros.len() as u64)
+            .expect("zeros"),
+        normal
+    );
+}
+
+#[test]
+fn batched_reads() {
+    assert_eq!(batch_len(&[0, 4096, 8192], 4096), 3);
+    assert_eq!(batch_len(&[0, 8192, 16384 + MAX_BATCH_GAP + 1], 4096), 2);
+    let spread: Vec<u64> = (0..100).map(|i| i * 40_960).collect();
+    // Gaps of 36KiB each, so limited by the total skipped
+    assert_eq!(batch_len(&spread, 4096), 8);
+    let adjacent: Vec<u64> = (0..100).map(|i| i * 4096).collect();
+    assert_eq!(batch_len(&adjacent, 4096), MAX_BATCH_BLOCKS);
+    // Big blocks are batched fewer at a time
+    let adjacent: Vec<u64> = (0..100).map(|i| i << 20).collect();
+    assert_eq!(batch_len(&adjacent, 1 << 20), 2);
+    let adjacent: Vec<u64> = (0..100).map(|i| i << 23).collect();
+    assert_eq!(batch_len(&adjacent, 8 << 20), 1);
+
+    // Sampled, but with samples close enough to batch
+    let mut next = xorshift(0xdead_beef);
+    let data: Vec<u8> = (0..2 << 20)
+        .map(|i| {
+            let x = next();
+            if i % 3 == 0 {
+                x as u8
+            } else {
+                b'a' + (x % 4) as u8
+            }
+        })
+        .collect();
+    let path = std::env::temp_dir().join(format!("compresstimator-batch-{}", std::process::id()));
+    std::fs::write(&path, &data).expect("write");
+
+    let est = Compresstimator::default();
+    let from_file = est.compresstimate_file_detailed(&path);
+    std::fs::remove_file(&path).expect("cleanup");
+
+    let expected = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), data.len() as u64)
+        .expect("cursor");
+    assert!(expected.sampled_original < data.len() as u64);
+    assert_eq!(from_file.expect("file"), expected);
+}
+
+#[test]
+fn codec_comparison() {
+    let est = Compresstimator::default();
+    let text = log_text(0xc0dec, 400_000);
+    let codecs = [Codec::Lz4 { level: 1 }, Codec::Lz4 { level: 12 }];
+
+    let results = est
+        .compresstimate_codecs(io::Cursor::new(&text), text.len() as u64, &codecs)
+        .expect("compresstimate_codecs");
+    assert_eq!(results.len(), 2);
+    assert!(results[1].ratio() <= results[0].ratio());
+    assert!(results.iter().all(|r| r.compress_time > Duration::ZERO));
+    assert!(results[0].throughput() > 0.0);
+    assert_eq!(
+        results[0].estimate,
+        est.compresstimate_len_detailed(io::Cursor::new(&text), text.len() as u64)
+            .expect("compresstimate_len_detailed")
+    );
+}
+
+#[test]
+fn sampling_plan() {
+    let est = Compresstimator::default();
+
+    let small = est.sampling(100_000);
+    assert!(small.full_read);
+    assert_eq!((small.blocks, small.stride), (25, 4096));
+
+    let data = vec![7; 8 << 20];
+    let plan = est.sampling(data.len() as u64);
+    assert!(!plan.full_read);
+    assert!(plan.stride > plan.block_size);
+    let estimate = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), data.len() as u64)
+        .expect("estimate");
+    assert_eq!(estimate.sampled_original, plan.blocks * plan.block_size);
+}
+
+#[test]
+fn buffered_position() {
+    let data = log_text(0xbf, 10_000);
+    let mut reader = io::BufReader::new(io::Cursor::new(&data));
+
+    // Leave the rest of a buffer's worth behind
+    let mut head = [0; 100];
+    reader.read_exact(&mut head).expect("read");
+
+    let est = Compresstimator::default();
+    let ratio = est.compresstimate(&mut reader).expect("estimate");
+    assert_eq!(
+        ratio,
+        est.compresstimate(io::Cursor::new(&data[100..]))
+            .expect("remainder")
+    );
+
+    assert_eq!(reader.stream_position().expect("position"), 100);
+    let mut next = [0; 100];
+    reader.read_exact(&mut next).expect("read");
+    assert_eq!(next[..], data[100..200]);
+}
+
+#[test]
+fn self_prefix_dictionary() {
+    // Distinct noisy records, each repeated through the file
+    let records: Vec<Vec<u8>> = (0..64).map(|i| noise(0x0bad_cafe + i, 512)).collect();
+    let data: Vec<u8> = (0..16 << 20 >> 9)
+        .flat_map(|i| records[(i * 7) % records.len()].clone())
+        .collect();
+
+    let mut est = Compresstimator::default();
+    let plain = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), data.len() as u64)
+        .expect("plain");
+
+    est.with_self_prefix_dictionary(32 * 1024);
+    let primed = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), data.len() as u64)
+        .expect("primed");
+    assert_eq!(primed.total_original, data.len() as u64);
+    assert!(primed.ratio() < plain.ratio() / 2.0);
+
+    // Too short to have a prefix, so estimated as normal
+    let short = est
+        .compresstimate_len_detailed(io::Cursor::new(&data[..1000]), 1000)
+        .expect("short");
+    assert_eq!(
+        short,
+        Compresstimator::default()
+            .compresstimate_len_detailed(io::Cursor::new(&data[..1000]), 1000)
+            .expect("short")
+    );
+
+    // Or, if it's allowed, when it turns out shorter than its length
+    let stale = 1 << 20;
+    let truncated = |est: &Compresstimator, tolerate_short| {
+        let plan = Plan {
+            trim: true,
+            tolerate_short,
+            ..Plan::default()
+        };
+        est.estimate_planned(io::Cursor::new(&data[..1000]), stale, plan, &mut |_, _| {
+            Ok(())
+        })
+    };
+    assert_eq!(
+        truncated(&est, true).expect("tolerant"),
+        truncated(&Compresstimator::default(), true).expect("tolerant")
+    );
+    assert_eq!(
+        truncated(&est, false).expect_err("strict").kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn files_map() {
+    let dir = std::env::temp_dir().join(format!("compresstimator-map-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("dir");
+    let paths: Vec<PathBuf> = ["text", "noise", "nope", "text"]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+    std::fs::write(&paths[0], log_text(0x3a9, 10_000)).expect("text");
+    std::fs::write(&paths[1], noise(0x3a9, 100_000)).expect("noise");
+
+    let est = Compresstimator::default();
+    let expected = est.compresstimate_file(&paths[0]);
+    let mapped: Vec<_> = [0, 1, 3]
+        .iter()
+        .map(|&jobs| est.compresstimate_files_map(&paths, jobs))
+        .collect();
+    std::fs::remove_dir_all(&dir).expect("cleanup");
+
+    let expected = expected.expect("text");
+    for results in mapped {
+        assert_eq!(results.len(), 3);
+        assert_eq!(*results[&paths[0]].as_ref().expect("text"), expected);
+        assert!(results[&paths[1]].is_ok());
+        assert!(results[&paths[2]].is_err());
+    }
+
+    assert!(est.compresstimate_files_map(&[], 4).is_empty());
+}
+
+#[test]
+fn sorted_records() {
+    // Rows of a few kinds, interleaved by a shuffle
+    let mut next = xorshift(0x5eed);
+    let rows: Vec<Vec<u8>> = (0..50_000)
+        .map(|i| {
+            let kind = next() % 16;
+            format!(
+                "{:02}|kind {} payload {}|{}\n",
+                kind,
+                kind,
+                "x".repeat(kind as usize),
+                i % 10
+            )
+            .into_bytes()
+        })
+        .collect();
+    let records: Vec<&[u8]> = rows.iter().map(|r| &r[..]).collect();
+
+    let est = Compresstimator::default();
+    let cmp = est
+        .compresstimate_records_sorted(&records, |r| r[..2].to_vec())
+        .expect("sorted");
+    assert_eq!(
+        cmp.as_is,
+        est.compresstimate_records(&records).expect("as is")
+    );
+    assert!(cmp.sorted < cmp.as_is);
+    assert!(cmp.gain() > 0.0);
+}
+
+#[test]
+fn defaults() {
+    let mut est = Compresstimator::default();
+    assert_eq!(est.get_block_size(), Compresstimator::DEFAULT_BLOCK_SIZE);
+    assert_eq!(
+        est.get_error_margin(),
+        Compresstimator::DEFAULT_ERROR_MARGIN
+    );
+    assert_eq!(est.get_confidence(), Compresstimator::DEFAULT_CONFIDENCE);
+
+    est.set_block_size(8192);
+    est.set_error_margin(0.05);
+    est.set_confidence(Confidence::C99);
+    assert_eq!(est.get_block_size(), 8192);
+    assert_eq!(est.get_error_margin(), 0.05);
+    assert_eq!(est.get_confidence(), Confidence::C99);
+
+    // The old setter names still work
+    #[allow(deprecated)]
+    {
+        est.block_size(16384).error_margin(0.2);
+        est.confidence_level(Confidence::C90);
+    }
+    assert_eq!(est.get_block_size(), 16384);
+    assert_eq!(est.get_error_margin(), 0.2);
+    assert_eq!(est.get_confidence(), Confidence::C90);
+}
+
+#[test]
+fn short_input() {
+    let text = log_text(0xfeed, 400_000);
+    let est = Compresstimator::default();
+    let actual = est
+        .compresstimate_len(io::Cursor::new(&text), text.len() as u64)
+        .expect("actual");
+
+    // As if truncated since its length was taken
+    let stale = text.len() as u64 * 2;
+    let tolerant = Plan {
+        tolerate_short: true,
+        ..Plan::default()
+    };
+    let detail = est
+        .estimate_planned(io::Cursor::new(&text), stale, tolerant, &mut |_, _| Ok(()))
+        .expect("stale");
+    assert_eq!(detail.total_original, stale);
+    assert!(detail.sampled_original < est.sampling(stale).blocks * 4096);
+    assert!(
+        (detail.ratio() - actual).abs() < 0.1,
+        "{:?} vs {}",
+        detail,
+        actual
+    );
+
+    // The strict methods still fail
+    let strict = est.compresstimate_len(io::Cursor::new(&text), stale);
+    assert_eq!(
+        strict.expect_err("strict").kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+
+    let path = std::env::temp_dir().join(format!("compresstimator-short-{}", std::process::id()));
+    std::fs::write(&path, &text).expect("write");
+    let strict = est.compresstimate_file_len(&path, stale);
+    let file = File::open(&path).expect("open");
+    let plan = Plan {
+        file: Some(&file),
+        batch: true,
+        tolerate_short: true,
+        ..Plan::default()
+    };
+    let from_file = est.estimate_planned(&file, stale, plan, &mut |_, _| Ok(()));
+    std::fs::remove_file(&path).expect("cleanup");
+    assert_eq!(
+        strict.expect_err("strict file").kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+    assert_eq!(from_file.expect("file").ratio(), detail.ratio());
+}
+
+#[test]
+fn random_sampling() {
+    let mut est = Compresstimator::default();
+    let len = 8 << 20;
+    let stride = est.sampling(len as u64).stride as usize;
+
+    // A compressible block at the start of every stride, noise otherwise
+    let mut next = xorshift(0xa11a5);
+    let data: Vec<u8> = (0..len)
+        .map(|i| {
+            let x = next();
+            if i % stride < 4096 {
+                0
+            } else {
+                x as u8
+            }
+        })
+        .collect();
+    let actual = est.base_truth(&data[..]).expect("base truth");
+
+    let uniform = est.compresstimate(io::Cursor::new(&data)).expect("uniform");
+    assert!(actual - uniform > 0.5, "{} vs {}", uniform, actual);
+
+    est.sample_strategy(SampleStrategy::Random { seed: 1 });
+    let random = est.compresstimate(io::Cursor::new(&data)).expect("random");
+    assert!((random - actual).abs() < 0.1, "{} vs {}", random, actual);
+    assert_eq!(
+        random,
+        est.compresstimate(io::Cursor::new(&data)).expect("again")
+    );
+
+    let picked = random_blocks(1000, 100, 7);
+    assert_eq!(picked.len(), 100);
+    assert!(picked.windows(2).all(|w| w[0] < w[1]));
+    assert!(picked.iter().all(|&b| b < 1000));
+    assert_eq!(random_blocks(10, 10, 7), (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn file_up_to() {
+    let est = Compresstimator::default();
+    let text = log_text(0x0b70, 10_000);
+    let len = text.len() as u64;
+    let path = std::env::temp_dir().join(format!("compresstimator-up-to-{}", std::process::id()));
+    std::fs::write(&path, &text).expect("write");
+
+    let clamped = est.compresstimate_file_up_to(&path, len * 10);
+    let whole = est.compresstimate_file(&path);
+    let capped = est.compresstimate_file_up_to(&path, len / 2);
+    let prefix = est.compresstimate_file_len(&path, len / 2);
+    std::fs::remove_file(&path).expect("cleanup");
+
+    assert_eq!(clamped.expect("clamped"), whole.expect("whole"));
+    assert_eq!(capped.expect("capped"), prefix.expect("len"));
+    assert!(est
+        .compresstimate_file_up_to("does/not/exist", 1024)
+        .is_err());
+}
+
+#[test]
+fn shuffled_blocks() {
+    let mut block = *b"abcABC123xy";
+    shuffle(&mut block, 3);
+    assert_eq!(&block, b"aA1bB2cC3xy");
+
+    // A smooth signal, whose high bytes barely change from one value to the
+    // next
+    let data: Vec<u8> = (0..256 * 1024)
+        .flat_map(|i| (1000.0 + (i as f32 / 5000.0).sin()).to_le_bytes())
+        .collect();
+
+    let mut est = Compresstimator::default();
+    let plain = est
+        .compresstimate(std::io::Cursor::new(&data))
+        .expect("plain");
+
+    est.with_shuffle(4);
+    let shuffled = est
+        .compresstimate(std::io::Cursor::new(&data))
+        .expect("shuffled");
+
+    assert!(shuffled < plain * 0.9, "{} vs {}", shuffled, plain);
+}
+
+#[test]
+fn similarity() {
+    let dir = std::env::temp_dir().join(format!("compresstimator-similar-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create dir");
+
+    let original = noise(0x5eed, 4 << 20);
+    let mut edited = original.clone();
+    edited[1 << 20..(1 << 20) + 4096].fill(0);
+    let unrelated = noise(0x5eed_5eed, 4 << 20);
+
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, data).expect("write");
+        path
+    };
+    let original = write("original", &original);
+    let edited = write("edited", &edited);
+    let unrelated = write("unrelated", &unrelated);
+    let empty = write("empty", &[]);
+
+    let est = Compresstimator::default();
+    let same = est.similarity(&original, &original);
+    let close = est.similarity(&original, &edited);
+    let far = est.similarity(&original, &unrelated);
+    let nothing = est.similarity(&empty, &empty);
+    let missing = est.similarity(&original, dir.join("missing"));
+    // Blocks bigger than lz4's window are cut down to fit pairs of them in it
+    let big = Compresstimator::with_block_size(128 * 1024).similarity(&original, &original);
+    std::fs::remove_dir_all(&dir).expect("remove dir");
+
+    assert!(same.expect("same") > 0.95);
+    assert!(close.expect("close") > 0.9);
+    assert!(far.expect("far") < 0.05);
+    assert_eq!(nothing.expect("empty"), 1.0);
+    assert!(missing.is_err());
+    assert!(big.expect("big blocks") > 0.95);
+}
+
+#[test]
+fn decompressed_input() {
+    let dir = std::env::temp_dir().join(format!("compresstimator-packed-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create dir");
+
+    let mut next = xorshift(0xdec0);
+    let text: Vec<u8> = (0..200_000)
+        .flat_map(|i| {
+            let x = next();
+            format!("{} record {} value {}\n", i, x % 1000, x % 7).into_bytes()
+        })
+        .collect();
+
+    let plain = dir.join("plain");
+    std::fs::write(&plain, &text).expect("write plain");
+
+    let packed = dir.join("packed.lz4");
+    let mut encoder = lz4::EncoderBuilder::new()
+        .build(File::create(&packed).expect("create lz4"))
+        .expect("encoder");
+    encoder.write_all(&text).expect("write lz4");
+    encoder.finish().1.expect("finish lz4");
+
+    let gzipped = dir.join("packed.gz");
+    let gzip = std::process::Command::new("gzip")
+        .arg("-k")
+        .arg(&plain)
+        .status()
+        .ok()
+        .filter(|status| status.success())
+        .map(|_| std::fs::rename(dir.join("plain.gz"), &gzipped).expect("rename"));
+
+    let est = Compresstimator::default();
+    let actual = est.base_truth(&text[..]).expect("base truth");
+    let results: Vec<_> = [&plain, &packed, &gzipped]
+        .iter()
+        .map(|path| est.compresstimate_decompressed(path))
+        .collect();
+    std::fs::remove_dir_all(&dir).expect("remove dir");
+
+    let expected = [InputFormat::Plain, InputFormat::Lz4, InputFormat::Gzip];
+    for (result, format) in results.into_iter().zip(expected) {
+        if format == InputFormat::Gzip && gzip.is_none() {
+            continue;
+        }
+        let result = result.expect("estimate");
+        assert_eq!(result.format, format);
+        assert_eq!(result.estimate.total_original, text.len() as u64);
+        let ratio = result.estimate.ratio();
+        assert!(
+            (ratio - actual).abs() < 0.05,
+            "{}: {} vs {}",
+            format,
+            ratio,
+            actual
+        );
+    }
+
+    assert_eq!(InputFormat::detect(b"BZh91AY"), InputFormat::Bzip2);
+    assert_eq!(InputFormat::detect(b"BZh0"), InputFormat::Plain);
+    assert_eq!(InputFormat::detect(b"BZhello"), InputFormat::Plain);
+    assert_eq!(InputFormat::detect(b"\x1f"), InputFormat::Plain);
+}
+
+#[test]
+fn verified_reads() {
+    /// Reads which come back different every time
+    struct Unstable(io::Cursor<Vec<u8>>, u8);
+
+    impl Read for Unstable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            buf[..n].iter_mut().for_each(|b| *b ^= self.1);
+            self.1 = self.1.wrapping_add(1);
+            Ok(n)
+        }
+    }
+
+    impl Seek for Unstable {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    let data = log_text(0x7e21f, 10_000);
+    let path =
+        std::env::temp_dir().join(format!("compresstimator-verified-{}", std::process::id()));
+    std::fs::write(&path, &data).expect("write");
+    let mut est = Compresstimator::default();
+    est.verify_reads(true);
+    let verified = est.compresstimate_file(&path);
+    let unverified = Compresstimator::default().compresstimate_file(&path);
+    std::fs::remove_file(&path).expect("cleanup");
+    assert_eq!(verified.expect("verified file"), unverified.expect("file"));
+
+    // Both reading in full and sampling
+    for len in [64 * 1024, 16 << 20] {
+        let data: Vec<u8> = data.iter().copied().cycle().take(len).collect();
+        let expected = Compresstimator::default()
+            .compresstimate(io::Cursor::new(&data))
+            .expect("unverified");
+        assert_eq!(
+            est.compresstimate(io::Cursor::new(&data)).expect("stable"),
+            expected
+        );
+
+        let err = est
+            .compresstimate(Unstable(io::Cursor::new(data), 0))
+            .expect_err("unstable");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn compressed_into() {
+    let data = log_text(0x1270, 10_000);
+    let est = Compresstimator::default();
+
+    for len in [data.len(), 16 << 20] {
+        let data: Vec<u8> = data.iter().copied().cycle().take(len).collect();
+        let mut sink = vec![];
+        let ratio = est
+            .compresstimate_into(io::Cursor::new(&data), len as u64, &mut sink)
+            .expect("estimate");
+        assert_eq!(
+            ratio,
+            est.compresstimate(io::Cursor::new(&data)).expect("plain")
+        );
+
+        let mut samples = vec![];
+        lz4::Decoder::new(&sink[..])
+            .expect("decoder")
+            .read_to_end(&mut samples)
+            .expect("decompress");
+        let sampled = est
+            .compresstimate_len_detailed(io::Cursor::new(&data), len as u64)
+            .expect("detailed")
+            .sampled_original;
+        assert_eq!(samples.len() as u64, sampled);
+        assert!((sink.len() as f32 / sampled as f32 - ratio).abs() < 0.01);
+        assert_eq!(samples[..4096], data[..4096]);
+    }
+
+    // External output arrives as it's produced
+    let mut est = Compresstimator::default();
+    est.codec(Codec::External(ExternalCommandCodec::new(
+        "cat",
+        &[] as &[&str],
+    )));
+    let mut sink = vec![];
+    est.compresstimate_into(io::Cursor::new(&data), data.len() as u64, &mut sink)
+        .expect("external");
+    assert_eq!(sink, data);
+}
+
+#[test]
+fn stripped_padding() {
+    let mut block = *b"ab\0\0cd\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0ef\0";
+    let kept = strip_padding(&mut block, 0);
+    assert_eq!(&block[..kept], b"ab\0\0cdef\0");
+    assert_eq!(strip_padding(&mut [0; 64], 0), 0);
+
+    // Random records, each padded out to 8KiB with zeros
+    let data: Vec<u8> = (0..2048)
+        .flat_map(|i| {
+            let mut record = noise(0x9add + i, 3000);
+            record.resize(8192, 0);
+            record
+        })
+        .collect();
+
+    let mut est = Compresstimator::default();
+    let padded = est.compresstimate(io::Cursor::new(&data)).expect("padded");
+    assert!(padded < 0.5, "{}", padded);
+
+    est.with_strip_padding(0);
+    let content = est.compresstimate(io::Cursor::new(&data)).expect("content");
+    assert!(content > 0.98, "{}", content);
+
+    est.force_exhaustive(true);
+    let full = est
+        .compresstimate_len_detailed(io::Cursor::new(&data[..1 << 20]), 1 << 20)
+        .expect("full read");
+    assert_eq!(full.sampled_original, 128 * 3000);
+    assert!(full.ratio() > 0.98);
+}
+
+#[test]
+fn level_within_budget() {
+    let data: Vec<u8> = log_text(0x1e7e12, 10_000)
+        .into_iter()
+        .cycle()
+        .take(8 << 20)
+        .collect();
+    let len = data.len() as u64;
+    let est = Compresstimator::default();
+    let levels = [1, 4, 9, 12];
+
+    let best = |budget| est.best_level_within(io::Cursor::new(&data), len, budget, &levels);
+    assert_eq!(best(Duration::from_secs(3600)).expect("generous"), 12);
+    assert_eq!(
+        best(Duration::ZERO).expect_err("impossible").kind(),
+        io::ErrorKind::TimedOut
+    );
+    assert_eq!(
+        est.best_level_within(io::Cursor::new(&data), len, Duration::MAX, &[])
+            .expect_err("no levels")
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn stored_interval() {
+    let est = Compresstimator::default();
+
+    // Small enough to read in full
+    let text = b"nothing to sample here\n".repeat(1000);
+    let (low, high) = est
+        .compresstimate_stored_interval(io::Cursor::new(&text), text.len() as u64)
+        .expect("read in full");
+    assert_eq!(low, high);
+
+    // 64KiB stretches, each either noise or drawn from a four letter alphabet
+    let mut next = xorshift(0xc1);
+    let mut data = Vec::with_capacity(16 << 20);
+    for _ in 0..256 {
+        let mask = if next() % 2 == 0 { 0x03 } else { 0xff };
+        data.extend((0..64 * 1024).map(|_| next() as u8 & mask));
+    }
+    let len = data.len() as u64;
+    let (low, high) = est
+        .compresstimate_stored_interval(io::Cursor::new(&data), len)
+        .expect("sampled");
+    let point = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), len)
+        .expect("detailed")
+        .extrapolated_compressed;
+    let actual = (est.base_truth(&data[..]).expect("base truth") * len as f32) as u64;
+
+    assert!(low < point && point < high, "{} {} {}", low, point, high);
+    assert!(
+        low <= actual && actual <= high,
+        "{} {} {}",
+        low,
+        actual,
+        high
+    );
+    assert!(high - low < len / 4);
+}
+
+#[test]
+fn chunked_compression() {
+    // 32KiB of noise over and over, which only compresses across chunks
+    let pattern = noise(0xc4, 32 * 1024);
+    let data = pattern.repeat(32);
+
+    let mut est = Compresstimator::default();
+    let continuous = est
+        .compresstimate(io::Cursor::new(&data))
+        .expect("continuous");
+    assert!(continuous < 0.5, "{}", continuous);
+
+    est.with_chunk_size(32 * 1024);
+    let chunked = est.compresstimate(io::Cursor::new(&data)).expect("chunked");
+    assert!(chunked > 0.99, "{}", chunked);
+
+    // Samples are whole chunks
+    est.set_block_size(4096).align_samples(true);
+    let sampling = est.sampling(1 << 30);
+    assert!(!sampling.full_read);
+    assert_eq!(sampling.block_size % (32 * 1024), 0);
+
+    let big = pattern.repeat(1024);
+    let sampled = est.compresstimate(io::Cursor::new(&big)).expect("sampled");
+    assert!(sampled > 0.99, "{}", sampled);
+
+    // Every chunk's output goes to the sink
+    let mut sink = vec![];
+    est.compresstimate_into(io::Cursor::new(&data), data.len() as u64, &mut sink)
+        .expect("into");
+    let compressed = est
+        .compresstimate_len_detailed(io::Cursor::new(&data), data.len() as u64)
+        .expect("detailed")
+        .sampled_compressed;
+    assert_eq!(sink.len() as u64, compressed);
+}
+
+#[test]
+fn recommendations() {
+    let est = Compresstimator::default();
+    let text = log_text(0x4ec, 10_000);
+    let len = text.len() as u64;
+
+    match est.recommend(io::Cursor::new(&text), len).expect("text") {
+        StorageAction::Compress {
+            codec,
+            estimated_ratio,
+        } => {
+            assert_eq!(codec, Codec::default());
+            assert!(estimated_ratio < 0.9);
+        }
+        action => panic!("{:?}", action),
+    }
+
+    let noise = noise(0x4ec, 256 * 1024);
+    assert_eq!(
+        est.recommend(io::Cursor::new(&noise), noise.len() as u64)
+            .expect("noise"),
+        StorageAction::StoreRaw {
+            reason: RawReason::Incompressible {
+                estimated_ratio: 1.0
+            }
+        }
+    );
+
+    let policy = RecommendPolicy {
+        max_ratio: 0.1,
+        min_len: 0,
+        codec: Some(Codec::Lz4 { level: 9 }),
+    };
+    assert!(matches!(
+        est.recommend_with(io::Cursor::new(&text), len, &policy),
+        Ok(StorageAction::StoreRaw {
+            reason: RawReason::Incompressible { .. }
+        })
+    ));
+
+    let policy = RecommendPolicy {
+        max_ratio: 0.9,
+        ..policy
+    };
+    assert!(matches!(
+        est.recommend_with(io::Cursor::new(&text), len, &policy),
+        Ok(StorageAction::Compress {
+            codec: Codec::Lz4 { level: 9 },
+            ..
+        })
+    ));
+
+    let policy = RecommendPolicy {
+        min_len: len + 1,
+        ..RecommendPolicy::default()
+    };
+    assert_eq!(
+        est.recommend_with(io::Cursor::new(&text), len, &policy)
+            .expect("small"),
+        StorageAction::StoreRaw {
+            reason: RawReason::TooSmall
+        }
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn sampled_extents() {
+    let path = std::env::temp_dir().join(format!("compresstimator-thin-{}", std::process::id()));
+
+    // Four 1MiB stretches of noise, with 63MiB holes between them
+    let noise = noise(0x7f1, 1 << 20);
+    let file = File::create(&path).expect("create");
+    for i in 0..4u64 {
+        use std::os::unix::fs::FileExt;
+        file.write_all_at(&noise, i * (64 << 20)).expect("write");
+    }
+    file.set_len(256 << 20).expect("extend");
+    drop(file);
+
+    let mut est = Compresstimator::default();
+    let logical = est.compresstimate_file_detailed(&path);
+    est.sample_extents(true);
+    let mapped = est.compresstimate_file_detailed(&path);
+    let extents = sys::extents(&File::open(&path).expect("open"), 0, 256 << 20);
+    std::fs::remove_file(&path).expect("remove");
+
+    let logical = logical.expect("logical");
+    assert_eq!(logical.total_original, 256 << 20);
+    assert!(logical.ratio() < 0.2, "{}", logical.ratio());
+
+    // Filesystems without FIEMAP fall back to sampling the logical range
+    match extents.expect("extents") {
+        Some(extents) => {
+            assert_eq!(extents.iter().map(|&(_, len)| len).sum::<u64>(), 4 << 20);
+            let mapped = mapped.expect("mapped");
+            assert_eq!(mapped.total_original, 4 << 20);
+            assert_eq!(mapped.ratio(), 1.0);
+        }
+        None => assert_eq!(mapped.expect("mapped"), logical),
+    }
+}
+
+#[test]
+fn explicit_offsets() {
+    // Noise in the first half, zeros in the second
+    let mut data = noise(0x51ed, 1 << 20);
+    data.resize(2 << 20, 0);
+
+    let est = Compresstimator::default();
+    let mut input = io::Cursor::new(&data[..]);
+    input.set_position(123);
+
+    let noisy = est.compresstimate_at_offsets(&mut input, &[0, 65536, 131072]);
+    assert_eq!(noisy.expect("noisy"), 1.0);
+    let zeros = est.compresstimate_at_offsets(&mut input, &[1 << 20, (2 << 20) - 100]);
+    assert!(zeros.expect("zeros") < 0.05);
+    assert_eq!(input.position(), 123);
+
+    assert_eq!(
+        est.compresstimate_at_offsets(&mut input, &[])
+            .expect("empty"),
+        1.0
+    );
+    let past = est.compresstimate_at_offsets(&mut input, &[0, 2 << 20]);
+    assert_eq!(past.expect_err("past").kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(input.position(), 123);
+}
+
+#[test]
+fn randomness() {
+    let mut next = xorshift(0x2545_f491);
+    let random: Vec<u8> = (0..1 << 20).map(|_| (next() >> 24) as u8).collect();
+    // Incompressible to lz4, but only using the bottom 7 bits
+    let ascii: Vec<u8> = random.iter().map(|&b| b & 0x7f).collect();
+    let text = b"an ordinary, compressible line of text\n".repeat(1 << 14);
+
+    let est = Compresstimator::default();
+    let check = |data: &[u8]| {
+        est.compresstimate_randomness(io::Cursor::new(data), data.len() as u64)
+            .expect("randomness")
+    };
+
+    let random = check(&random);
+    assert_eq!(random.class, DataClass::Random);
+    assert!(random.entropy > 7.99, "{}", random.entropy);
+
+    let ascii = check(&ascii);
+    assert_eq!(ascii.class, DataClass::Incompressible, "{:?}", ascii);
+    assert!(ascii.chi_square > RANDOM_CHI_SQUARE);
+    assert!((ascii.entropy - 7.0).abs() < 0.01, "{}", ascii.entropy);
+
+    let text = check(&text);
+    assert_eq!(text.class, DataClass::Compressible);
+    assert!(text.entropy < 5.0);
+}
+
+#[test]
+fn convergence() {
+    // Alternating 64KiB stretches of text and noise
+    let mut data = Vec::new();
+    for i in 0..128 {
+        if i % 2 == 0 {
+            data.extend(b"a line of compressible text\n".iter().cycle().take(65536));
+        } else {
+            data.extend(noise(0x3c6e_f372 + i, 65536));
+        }
+    }
+
+    let est = Compresstimator::default();
+    let len = data.len() as u64;
+    let curve = est
+        .compresstimate_convergence(io::Cursor::new(&data), len)
+        .expect("convergence");
+    let sampling = est.sampling(len);
+    assert!(!sampling.full_read);
+
+    let counts: Vec<u64> = curve.iter().map(|&(samples, _)| samples).collect();
+    let mut expected: Vec<u64> = (0..)
+        .map(|i| 1 << i)
+        .take_while(|&n| n < sampling.blocks)
+        .collect();
+    expected.push(sampling.blocks);
+    assert_eq!(counts, expected);
+
+    let ratio = est
+        .compresstimate_len(io::Cursor::new(&data), len)
+        .expect("estimate");
+    assert_eq!(curve.last().map(|&(_, ratio)| ratio), Some(ratio));
+    assert!(curve.iter().all(|&(_, ratio)| ratio > 0.0 && ratio <= 1.0));
+
+    let small = est
+        .compresstimate_convergence(io::Cursor::new(&data[..8192]), 8192)
+        .expect("small");
+    assert_eq!(small.len(), 1);
+    assert_eq!(small[0].0, 2);
+}
+
+#[test]
+fn estimate_quality() {
+    let mixed = mixed_blocks(0x68e3_1da4, b"some unremarkable text\n");
+    let uniform = b"some unremarkable text\n".repeat(1 << 19);
+
+    let est = Compresstimator::default();
+    let quality = |data: &[u8]| {
+        est.compresstimate_with_quality(io::Cursor::new(data), data.len() as u64)
+            .expect("quality")
+    };
+
+    let uniform = quality(&uniform);
+    assert!(!uniform.full_read);
+    assert!(uniform.quality > 0.95, "{:?}", uniform);
+
+    let mixed = quality(&mixed);
+    assert!(!mixed.full_read);
+    assert_eq!(mixed.samples, mixed.sampled.sampled_original / 4096);
+    assert!(mixed.quality > 0.3 && mixed.quality < 0.7, "{:?}", mixed);
+
+    let small = quality(&[0; 8192]);
+    assert!(small.full_read);
+    assert_eq!(small.quality, 1.0);
+}
+
+#[test]
+fn bootstrap() {
+    let mixed = mixed_blocks(0x0b5e_55ed, &[0]);
+
+    let est = Compresstimator::default();
+    let len = mixed.len() as u64;
+    let boot = est
+        .compresstimate_bootstrap(io::Cursor::new(&mixed), len, 1000)
+        .expect("bootstrap");
+    let samples = (boot.sampled.sampled_original / 4096) as f32;
+    // Ratios near 0 and 1 in about equal measure, a standard deviation of 0.5
+    let expected = 0.5 / samples.sqrt();
+    assert!(
+        (boot.standard_error - expected).abs() < expected * 0.2,
+        "{} vs {}",
+        boot.standard_error,
+        expected
+    );
+
+    let small = est
+        .compresstimate_bootstrap(io::Cursor::new(&mixed[..8192]), 8192, 1000)
+        .expect("small");
+    assert_eq!(small.standard_error, 0.0);
+}
+
+#[test]
+fn exhaustive_fallback() {
+    let path =
+        std::env::temp_dir().join(format!("compresstimator-fallback-{}", std::process::id()));
+    let mixed = mixed_blocks(0x2f6b_91c3, b"some unremarkable text\n");
+    std::fs::write(&path, &mixed).expect("write");
+
+    let est = Compresstimator::default();
+    let trusted = est.compresstimate_or_exhaustive(&path, 0.0);
+    let doubted = est.compresstimate_or_exhaustive(&path, 0.99);
+    std::fs::remove_file(&path).expect("remove");
+
+    let trusted = trusted.expect("trusted");
+    assert!(matches!(trusted, FallbackEstimate::Sampled(_)));
+    match doubted.expect("doubted") {
+        FallbackEstimate::Exhaustive { sampled, ratio } => {
+            assert!(sampled.quality < 0.99);
+            assert_eq!(ratio, est.base_truth(&mixed[..]).expect("base_truth"));
+            assert!((trusted.ratio() - ratio).abs() < 0.1);
+        }
+        sampled => panic!("{:?}", sampled),
+    }
+}
+
+#[test]
+fn chunked_input() {
+    // Text, then noise, in ragged chunks
+    let mut data = b"fairly ordinary text, repeated\n".repeat(1 << 15);
+    data.extend(noise(0x6a09_e667, 1 << 20));
+    let chunks = || {
+        let mut sizes = [1, 0, 4095, 70000, 333].iter().cycle();
+        let mut rest = &data[..];
+        std::iter::from_fn(move || {
+            let n = (*sizes.next().expect("cycle")).min(rest.len());
+            (!rest.is_empty()).then(|| {
+                let (chunk, tail) = rest.split_at(n);
+                rest = tail;
+                Ok(chunk.to_vec())
+            })
+        })
+    };
+
+    let est = Compresstimator::default();
+    let len = data.len() as u64;
+    let seekable = est
+        .compresstimate_len(io::Cursor::new(&data), len)
+        .expect("seekable");
+    let hinted = est
+        .compresstimate_chunks(chunks(), Some(len))
+        .expect("hinted");
+    let unhinted = est.compresstimate_chunks(chunks(), None).expect("unhinted");
+    assert!(
+        (hinted - seekable).abs() < 0.05,
+        "{} vs {}",
+        hinted,
+        seekable
+    );
+    assert!(
+        (unhinted - seekable).abs() < 0.1,
+        "{} vs {}",
+        unhinted,
+        seekable
+    );
+
+    // Padding is stripped from streamed blocks as from sampled ones
+    let mut filler = b"a line of text between padding\n".to_vec();
+    filler.resize(128, 0);
+    let padded = mixed_blocks(0x9add, &filler);
+    let len = padded.len() as u64;
+    let mut stripped = Compresstimator::default();
+    stripped.with_strip_padding(0);
+    let sampled = stripped
+        .compresstimate_len(io::Cursor::new(&padded), len)
+        .expect("sampled");
+    let streamed = stripped
+        .compresstimate_chunks(padded.chunks(70000).map(|c| Ok(c.to_vec())), Some(len))
+        .expect("streamed");
+    assert!(
+        (streamed - sampled).abs() < 0.05,
+        "{} vs {}",
+        streamed,
+        sampled
+    );
+    assert!(
+        est.compresstimate_len(io::Cursor::new(&padded), len)
+            .expect("unstripped")
+            < sampled - 0.05
+    );
+
+    let failing = vec![Ok(vec![0; 100]), Err(io::Error::other("gone"))];
+    let err = est.compresstimate_chunks(failing.into_iter(), None);
+    assert_eq!(err.expect_err("failing").to_string(), "gone");
+    assert_eq!(
+        est.compresstimate_chunks(std::iter::empty(), None)
+            .expect("empty"),
+        1.0
+    );
+}
+
+#[test]
+fn finish_errors() {
+    /// Accepts a few bytes, then fails
+    struct Failing(usize);
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.0.checked_sub(buf.len()) {
+                Some(left) => {
+                    self.0 = left;
+                    Ok(buf.len())
+                }
+                None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "sink full")),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Small enough to be held back until the stream is finished, after the
+    // frame header got through
+    let data = b"compressible text, repeated\n".repeat(100);
+    let est = Compresstimator::default();
+    let err = est
+        .compresstimate_into(io::Cursor::new(&data), data.len() as u64, Failing(64))
+        .expect_err("failing sink");
+
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    let inner = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<CompresstimatorError>());
+    match inner {
+        Some(CompresstimatorError::Finish {
+            consumed,
+            sink,
+            kind,
+            ..
+        }) => {
+            assert_eq!(*consumed, data.len() as u64);
+            assert!(sink);
+            assert_eq!(*kind, io::ErrorKind::BrokenPipe);
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn region_sampling() {
+    // Zeros, apart from 1MiB of noise in the middle of 8MiB
+    let mut data = vec![0; 8 << 20];
+    data[4 << 20..5 << 20].copy_from_slice(&noise(0x1f83_d9ab, 1 << 20));
+
+    let mut est = Compresstimator::default();
+    let len = data.len() as u64;
+    let uniform = est
+        .compresstimate_len(io::Cursor::new(&data), len)
+        .expect("uniform");
+    est.sample_strategy(SampleStrategy::Region {
+        offset: 4 << 20,
+        len: 1 << 20,
+        weight: 7,
+    });
+    let weighted = est
+        .compresstimate_len(io::Cursor::new(&data), len)
+        .expect("weighted");
+    assert!(uniform < 0.25, "{}", uniform);
+    assert!((weighted - 0.5).abs() < 0.1, "{}", weighted);
+
+    // An eighth of the blocks, weighted 7, takes half the samples
+    let chosen = region_blocks(2048, 100, 1024..1280, 7);
+    assert_eq!(chosen.len(), 100);
+    assert_eq!(
+        chosen
+            .iter()
+            .filter(|&&b| (1024..1280).contains(&b))
+            .count(),
+        50
+    );
+    assert!(chosen.windows(2).all(|w| w[0] < w[1]));
+
+    // The rest still gets one, and neither part more than it has
+    assert_eq!(
+        region_blocks(100, 10, 0..50, u32::MAX)
+            .iter()
+            .filter(|&&b| b >= 50)
+            .count(),
+        1
+    );
+    assert_eq!(
+        region_blocks(100, 10, 0..5, u32::MAX)
+            .iter()
+            .filter(|&&b| b < 5)
+            .count(),
+        5
+    );
+    assert!(region_blocks(100, 10, 0..50, 0).iter().all(|&b| b >= 50));
+    assert_eq!(region_blocks(100, 10, 40..200, 1).len(), 10);
+}
+
+#[test]
+fn sketches() {
+    // Text and noise, 64KiB at a time
+    let mut data = Vec::new();
+    for i in 0..16 {
+        if i % 4 == 0 {
+            data.extend(noise(0x510e_527f + i, 65536));
+        } else {
+            data.extend(b"ordinary text for sketching\n".iter().cycle().take(65536));
+        }
+    }
+    let mut edited = data.clone();
+    edited[300_000..300_100].fill(b'!');
+
+    let est = Compresstimator::default();
+    let sketch = |data: &[u8], windows| {
+        est.compresstimate_sketch(io::Cursor::new(data), data.len() as u64, windows)
+            .expect("sketch")
+    };
+    let distance = |a: &[f32], b: &[f32]| {
+        a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>() / a.len() as f32
+    };
+
+    let original = sketch(&data, 16);
+    assert_eq!(original.len(), 16);
+    // The first window is at the start, in noise, and the second a fifteenth
+    // of the way through, in text
+    assert_eq!(original[0], 1.0);
+    assert!(original[1] < 0.1);
+    assert!(distance(&original, &sketch(&edited, 16)) < 0.01);
+    let shifted = sketch(&data[65536..], 16);
+    assert!(distance(&original, &shifted) > 0.2);
+
+    assert_eq!(sketch(&data[..100], 3).len(), 3);
+    assert_eq!(sketch(&[], 2), [1.0, 1.0]);
+    assert!(sketch(&data, 0).is_empty());
+}
+
+#[test]
+fn running_updates() {
+    use std::sync::mpsc;
+
+    let mut next = xorshift(0x1234_5678);
+    let data: Vec<u8> = (0..32 * 1024 * 1024)
+        .map(|i| {
+            let x = next();
+            if i % 3 == 0 {
+                x as u8
+            } else {
+                b'a' + (i % 5) as u8
+            }
+        })
+        .collect();
+
+    let est = Compresstimator::default();
+    let (tx, rx) = mpsc::channel();
+    let ratio = est
+        .compresstimate_updates(io::Cursor::new(&data), data.len() as u64, &tx)
+        .expect("estimate");
+    drop(tx);
+    assert_eq!(
+        ratio,
+        est.compresstimate_len(io::Cursor::new(&data), data.len() as u64)
+            .unwrap()
+    );
+
+    let updates: Vec<(u64, f32)> = rx.iter().collect();
+    assert!(updates.len() > 2, "{:?}", updates);
+    assert!(updates.windows(2).all(|w| w[0].0 <= w[1].0));
+    let &(sampled, last) = updates.last().expect("final update");
+    assert_eq!(last, ratio);
+    assert!(sampled < data.len() as u64);
+    let &(_, running) = &updates[updates.len() - 2];
+    assert!((running - ratio).abs() < 0.1, "{} vs {}", running, ratio);
+
+    // Read in full, updates count every byte read
+    let (tx, rx) = mpsc::channel();
+    let small = &data[..1024 * 1024];
+    est.compresstimate_updates(io::Cursor::new(small), small.len() as u64, &tx)
+        .expect("small estimate");
+    drop(tx);
+    let updates: Vec<(u64, f32)> = rx.iter().collect();
+    assert!(updates.len() > 2, "{:?}", updates);
+    assert!(updates
+        .iter()
+        .all(|&(bytes, _)| bytes <= small.len() as u64));
+    assert_eq!(updates.last().map(|u| u.0), Some(small.len() as u64));
+
+    // Sampled, updates count padding read as well as what's compressed
+    let padded: Vec<u8> = data
+        .chunks(2048)
+        .flat_map(|half| half.iter().copied().chain(std::iter::repeat(0).take(2048)))
+        .collect();
+    let mut est = Compresstimator::default();
+    est.with_strip_padding(0);
+    let (tx, rx) = mpsc::channel();
+    est.compresstimate_updates(io::Cursor::new(&padded), padded.len() as u64, &tx)
+        .expect("padded estimate");
+    drop(tx);
+    let updates: Vec<(u64, f32)> = rx.iter().collect();
+    let (&(sampled, _), running) = updates.split_last().expect("final update");
+    assert!(running.iter().all(|&(bytes, _)| bytes % 4096 == 0));
+    assert!(running.last().expect("running").0 > sampled);
+}
+
+#[test]
+fn packed_bits() {
+    let mut out = vec![];
+    pack_bits(b"abbcccdddd", &mut out);
+    assert_eq!(out, b"\x02abb\xfec\xfdd");
+
+    pack_bits(&[7; 300], &mut out);
+    assert_eq!(out, [0x81, 7, 0x81, 7, 0xd5, 7]);
+
+    let literal: Vec<u8> = (0..=199).collect();
+    pack_bits(&literal, &mut out);
+    assert_eq!(out.len(), 202);
+    assert_eq!((out[0], out[129]), (127, 71));
+
+    pack_bits(&[], &mut out);
+    assert!(out.is_empty());
+}
+
+#[test]
+fn rle_preanalysis() {
+    // Bitmap-like rows: short runs of random shades
+    let mut next = xorshift(0x1234_5678);
+    let mut data = Vec::with_capacity(8 * 1024 * 1024);
+    while data.len() < 8 * 1024 * 1024 {
+        let x = next();
+        let run = 3 + (x >> 8) as usize % 6;
+        data.extend(std::iter::repeat(x as u8).take(run));
+    }
+    data.truncate(8 * 1024 * 1024);
+
+    let mut est = Compresstimator::default();
+    let plain = est.compresstimate(io::Cursor::new(&data)).unwrap();
+    est.with_rle_preanalysis(true);
+    let rle = est.compresstimate(io::Cursor::new(&data)).unwrap();
+    assert!(rle < plain - 0.1, "{} vs {}", rle, plain);
+
+    // Read in full, the same
+    let small = &data[..64 * 1024];
+    let rle_small = est.compresstimate(io::Cursor::new(small)).unwrap();
+    est.with_rle_preanalysis(false);
+    let plain_small = est.compresstimate(io::Cursor::new(small)).unwrap();
+    assert!(
+        rle_small < plain_small - 0.1,
+        "{} vs {}",
+        rle_small,
+        plain_small
+    );
+}
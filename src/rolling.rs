@@ -0,0 +1,114 @@
+//! Tracking compressibility over a sliding window of a stream
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::Compresstimator;
+
+/// The compressibility of the most recent part of a stream, fed to it a
+/// piece at a time with `push`.
+///
+/// Bytes are gathered into blocks of the estimator's block size, and each
+/// block is compressed on its own as it completes, so the ratio is a running
+/// figure over the latest whole blocks covering the window rather than a
+/// fresh estimate of it.  A partial block at the end of what's been pushed
+/// isn't counted until it fills.  Any codec, dictionary and transform on the
+/// estimator are used, but the block size isn't adapted to the window.
+///
+/// ```
+/// use compresstimator::{Compresstimator, RollingEstimator};
+///
+/// let mut rolling = RollingEstimator::new(&Compresstimator::default(), 64 * 1024);
+/// rolling.push(&[0; 128 * 1024]).expect("push");
+/// assert!(rolling.ratio().expect("full blocks") < 0.1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RollingEstimator {
+    estimator: Compresstimator,
+    max_blocks: usize,
+    partial: Vec<u8>,
+    blocks: VecDeque<u64>,
+    compressed: u64,
+}
+
+impl RollingEstimator {
+    /// Track the last `window_len` bytes pushed, rounded up to whole blocks
+    /// of at least one, compressing as `estimator` would.
+    pub fn new(estimator: &Compresstimator, window_len: u64) -> Self {
+        let block_size = estimator.get_block_size().max(1);
+        let max_blocks = window_len.div_ceil(block_size).max(1) as usize;
+
+        Self {
+            estimator: estimator.clone(),
+            max_blocks,
+            partial: Vec::with_capacity(block_size as usize),
+            blocks: VecDeque::with_capacity(max_blocks + 1),
+            compressed: 0,
+        }
+    }
+
+    /// Add `data` to the end of the stream, compressing any blocks it
+    /// completes and dropping those which fall out of the window.
+    pub fn push(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let block_size = self.estimator.get_block_size().max(1) as usize;
+
+        while !data.is_empty() {
+            let take = (block_size - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.partial.len() == block_size {
+                let compressed = self.estimator.compressed_len(&self.partial)?;
+                self.partial.clear();
+
+                self.compressed += compressed;
+                self.blocks.push_back(compressed);
+                if self.blocks.len() > self.max_blocks {
+                    self.compressed -= self.blocks.pop_front().expect("over the limit");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The ratio over the window, or `None` until a whole block has been
+    /// pushed.
+    pub fn ratio(&self) -> Option<f32> {
+        match self.window_len() {
+            0 => None,
+            len => Some((self.compressed as f32 / len as f32).min(1.0)),
+        }
+    }
+
+    /// How many bytes the ratio currently covers: up to the window length,
+    /// rounded up to whole blocks.
+    pub fn window_len(&self) -> u64 {
+        self.blocks.len() as u64 * self.estimator.get_block_size()
+    }
+}
+
+#[test]
+fn rolling_window() {
+    let est = Compresstimator::default();
+    let mut rolling = RollingEstimator::new(&est, 64 * 1024);
+    assert_eq!(rolling.ratio(), None);
+
+    // Text logs, then random bytes
+    let text = b"2024-01-01 12:00:00 INFO request served in 12ms\n".repeat(4096);
+    for chunk in text.chunks(1000) {
+        rolling.push(chunk).expect("push text");
+    }
+    assert_eq!(rolling.window_len(), 64 * 1024);
+    let before = rolling.ratio().expect("text ratio");
+    assert!(before < 0.2, "{}", before);
+
+    let noise = crate::noise(0x1234_5678, 128 * 1024);
+    rolling.push(&noise[..32 * 1024]).expect("push half");
+    let during = rolling.ratio().expect("mixed ratio");
+    assert!(during > before + 0.3 && during < 0.9, "{}", during);
+
+    rolling.push(&noise[32 * 1024..]).expect("push noise");
+    assert_eq!(rolling.ratio(), Some(1.0));
+    assert_eq!(rolling.window_len(), 64 * 1024);
+}
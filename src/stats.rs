@@ -0,0 +1,153 @@
+//! Statistics over per-block compression ratios
+
+/// The `p`th percentile (0-100) of `sorted`, interpolating linearly between
+/// the closest ranks.  Returns NaN for an empty slice.
+pub(crate) fn percentile(sorted: &[f32], p: f32) -> f32 {
+    if sorted.is_empty() {
+        return f32::NAN;
+    }
+
+    let rank = (p.clamp(0.0, 100.0) / 100.0) * (sorted.len() - 1) as f32;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let frac = rank - lower as f32;
+
+    sorted[lower] + (sorted[upper] - sorted[lower]) * frac
+}
+
+/// The standard error of the mean of `values`, a sample from a population of
+/// `population`, with the finite population correction so that sampling all
+/// of it gives zero.  Zero with fewer than two values.
+pub(crate) fn standard_error(values: &[f32], population: u64) -> f32 {
+    let n = values.len() as f64;
+    if values.len() < 2 {
+        return 0.0;
+    }
+
+    let mean = values.iter().map(|&v| f64::from(v)).sum::<f64>() / n;
+    let variance = values
+        .iter()
+        .map(|&v| (f64::from(v) - mean).powi(2))
+        .sum::<f64>()
+        / (n - 1.0);
+    let population = (population as f64).max(n);
+    let correction = if population > 1.0 {
+        (population - n) / (population - 1.0)
+    } else {
+        0.0
+    };
+
+    (variance / n * correction).sqrt() as f32
+}
+
+/// The standard error of the mean of `values`, found by drawing `resamples`
+/// resamples of the same size from them with replacement and taking the
+/// standard deviation of their means.  The draws come from a generator seeded
+/// with `seed`, so the same inputs give the same result.  Zero with fewer than
+/// two values or resamples.
+pub(crate) fn bootstrap_standard_error(values: &[f32], resamples: usize, seed: u64) -> f32 {
+    if values.len() < 2 || resamples < 2 {
+        return 0.0;
+    }
+
+    let n = values.len() as u64;
+    let mut state = seed;
+    let means: Vec<f64> = (0..resamples)
+        .map(|_| {
+            let sum: f64 = (0..n)
+                .map(|_| {
+                    // Uniform in 0..n, by scaling rather than modulo to avoid bias
+                    let i = (u128::from(crate::splitmix64(&mut state)) * u128::from(n)) >> 64;
+                    f64::from(values[i as usize])
+                })
+                .sum();
+            sum / n as f64
+        })
+        .collect();
+
+    let r = resamples as f64;
+    let mean = means.iter().sum::<f64>() / r;
+    let variance = means.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / (r - 1.0);
+    variance.sqrt() as f32
+}
+
+/// The Shannon entropy of bytes with the frequencies in `counts`, in bits per
+/// byte, between 0 and 8.  Zero if there are none.
+pub(crate) fn entropy(counts: &[u64; 256]) -> f64 {
+    let total = counts.iter().sum::<u64>() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Pearson's chi-square statistic for bytes with the frequencies in `counts`
+/// against a uniform distribution, with 255 degrees of freedom.  Zero if
+/// there are none.
+pub(crate) fn chi_square(counts: &[u64; 256]) -> f64 {
+    let total = counts.iter().sum::<u64>() as f64;
+    if total == 0.0 {
+        return 0.0;
+    }
+
+    let expected = total / 256.0;
+    counts
+        .iter()
+        .map(|&count| (count as f64 - expected).powi(2) / expected)
+        .sum()
+}
+
+#[test]
+fn percentiles() {
+    let sorted = [0.1, 0.2, 0.3, 0.4, 0.5];
+    assert_eq!(percentile(&sorted, 0.0), 0.1);
+    assert_eq!(percentile(&sorted, 50.0), 0.3);
+    assert_eq!(percentile(&sorted, 100.0), 0.5);
+    assert!((percentile(&sorted, 12.5) - 0.15).abs() < 1e-6);
+    assert!(percentile(&[], 50.0).is_nan());
+}
+
+#[test]
+fn standard_errors() {
+    let values = [0.2, 0.4, 0.6, 0.8];
+    // Sample standard deviation 0.258, over the root of 4
+    assert!((standard_error(&values, u64::MAX) - 0.1291).abs() < 1e-4);
+    assert!(standard_error(&values, 8) < standard_error(&values, 1000));
+    assert_eq!(standard_error(&values, 4), 0.0);
+    assert_eq!(standard_error(&[0.5], 100), 0.0);
+}
+
+#[test]
+fn bootstrap_standard_errors() {
+    let values = [0.2, 0.4, 0.6, 0.8];
+    // Near the population standard deviation of 0.224 over the root of 4
+    let error = bootstrap_standard_error(&values, 4000, 1);
+    assert!((error - 0.1118).abs() < 0.005, "{}", error);
+    assert_eq!(error, bootstrap_standard_error(&values, 4000, 1));
+
+    assert_eq!(bootstrap_standard_error(&[0.5; 16], 100, 1), 0.0);
+    assert_eq!(bootstrap_standard_error(&[0.5], 100, 1), 0.0);
+    assert_eq!(bootstrap_standard_error(&values, 1, 1), 0.0);
+}
+
+#[test]
+fn byte_distributions() {
+    let mut uniform = [0u64; 256];
+    uniform.iter_mut().for_each(|count| *count = 100);
+    assert!((entropy(&uniform) - 8.0).abs() < 1e-9);
+    assert_eq!(chi_square(&uniform), 0.0);
+
+    let mut skewed = [0u64; 256];
+    skewed[b'a' as usize] = 50;
+    skewed[b'b' as usize] = 50;
+    assert!((entropy(&skewed) - 1.0).abs() < 1e-9);
+    // The sum of squared counts over the expected count, less the total
+    assert!((chi_square(&skewed) - 12_700.0).abs() < 1e-6);
+
+    assert_eq!(entropy(&[0; 256]), 0.0);
+    assert_eq!(chi_square(&[0; 256]), 0.0);
+}
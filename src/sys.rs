@@ -0,0 +1,265 @@
+//! Platform-specific helpers
+
+use std::fs::File;
+use std::io::{self, IoSliceMut};
+use std::path::Path;
+
+/// What `O_DIRECT` reads need their buffers, offsets and lengths aligned to.
+/// Devices may need as little as 512 bytes, but 4096 covers them all.
+pub(crate) const DIRECT_IO_ALIGN: usize = 4096;
+
+// The direction bits of ioctl request numbers, which a few architectures
+// place differently
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )
+))]
+const IOC_READ: u64 = 2 << 29;
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )
+))]
+const IOC_WRITE: u64 = 4 << 29;
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+const IOC_READ: u64 = 2 << 30;
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+const IOC_WRITE: u64 = 1 << 30;
+
+/// The size of the block device behind `file`, if it is one and we know how to
+/// ask.
+#[cfg(target_os = "linux")]
+pub(crate) fn block_device_len(file: &File) -> io::Result<Option<u64>> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOR(0x12, 114, size_t)
+    const BLKGETSIZE64: u64 =
+        IOC_READ | ((std::mem::size_of::<usize>() as u64) << 16) | (0x12 << 8) | 114;
+
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(None);
+    }
+
+    let mut len: u64 = 0;
+    // Safety: BLKGETSIZE64 writes a single u64 through the pointer.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut len as *mut u64) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(len))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn block_device_len(_file: &File) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// The regions of `file` between `start` and `end` with data written to
+/// them, as `(offset, len)` pairs in order and clipped to the range, or
+/// `None` if the filesystem can't say.
+///
+/// This asks `FIEMAP`, syncing first so delayed allocations show up, and
+/// leaves out holes and preallocated but unwritten extents alike.
+#[cfg(target_os = "linux")]
+pub(crate) fn extents(file: &File, start: u64, end: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    const BATCH: usize = 256;
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; BATCH],
+    }
+
+    const FIEMAP_HEADER: u64 = 32;
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: u64 =
+        IOC_READ | IOC_WRITE | (FIEMAP_HEADER << 16) | ((b'f' as u64) << 8) | 11;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+
+    let mut found = vec![];
+    let mut pos = start;
+
+    while pos < end {
+        let mut map = Box::new(Fiemap {
+            start: pos,
+            length: end - pos,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); BATCH],
+        });
+
+        // Safety: the kernel writes at most extent_count extents, which is how
+        // many the struct has room for.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut *map) };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        let mapped = &map.extents[..map.mapped_extents as usize];
+        for extent in mapped {
+            let from = extent.logical.max(pos);
+            let to = extent.logical.saturating_add(extent.length).min(end);
+            if to > from && extent.flags & FIEMAP_EXTENT_UNWRITTEN == 0 {
+                found.push((from, to - from));
+            }
+        }
+
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                pos = last.logical.saturating_add(last.length);
+            }
+            _ => break,
+        }
+    }
+
+    Ok(Some(found))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn extents(_file: &File, _start: u64, _end: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Whether `preadv` is available.  Apple platforms only gained it in macOS
+/// 11, later than Rust supports, so they go without.
+pub(crate) const VECTORED_READS: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+));
+
+/// Read from `file` at `offset` into each of `bufs` in turn, without moving
+/// its position, returning the bytes read.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+))]
+pub(crate) fn preadv(file: &File, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: IoSliceMut is guaranteed ABI compatible with iovec on Unix, and
+    // preadv only writes within the buffers it describes.
+    let ret = unsafe {
+        libc::preadv(
+            file.as_raw_fd(),
+            bufs.as_mut_ptr() as *const libc::iovec,
+            bufs.len() as libc::c_int,
+            offset as libc::off_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret as usize)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+)))]
+pub(crate) fn preadv(
+    _file: &File,
+    _bufs: &mut [IoSliceMut<'_>],
+    _offset: u64,
+) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "vectored reads are not supported on this platform",
+    ))
+}
+
+/// Open `path` for reading with `O_DIRECT`, bypassing the page cache.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_direct(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux",
+    ))
+}
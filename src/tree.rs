@@ -0,0 +1,115 @@
+//! Walking and sampling directory trees
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Call `found` with every file under `root` as the walk comes to it, only
+/// holding one directory's entries at a time.  Each directory is gone
+/// through in sorted order, so the order is the same from run to run.
+/// Symlinks to directories aren't followed, to avoid loops, and directories
+/// which can't be read are counted in `unreadable` rather than failing the
+/// walk.
+pub(crate) fn visit(
+    root: &Path,
+    unreadable: &mut u64,
+    found: &mut dyn FnMut(PathBuf),
+) -> io::Result<()> {
+    if !fs::metadata(root)?.is_dir() {
+        found(root.to_path_buf());
+        return Ok(());
+    }
+
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).and_then(|rd| rd.collect::<io::Result<Vec<_>>>())
+        {
+            Ok(entries) => entries,
+            Err(_) => {
+                *unreadable += 1;
+                continue;
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => dirs.push(entry.path()),
+                Ok(ft) if ft.is_symlink() && entry.path().is_dir() => (),
+                _ => found(entry.path()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Every file under `root`, as found by `visit`, in sorted order.
+pub(crate) fn walk(root: &Path, unreadable: &mut u64) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    visit(root, unreadable, &mut |file| files.push(file))?;
+    files.sort();
+    Ok(files)
+}
+
+/// Every file under `new` which is missing from `old`, or differs from it in
+/// size or modification time, as found by `walk`.  Files only in `old` are
+/// ignored, and files whose metadata can't be read count as changed.
+pub(crate) fn changed(old: &Path, new: &Path, unreadable: &mut u64) -> io::Result<Vec<PathBuf>> {
+    fs::metadata(old)?;
+
+    let mut files = walk(new, unreadable)?;
+    files.retain(|file| {
+        let before = file
+            .strip_prefix(new)
+            .ok()
+            .and_then(|rel| fs::metadata(old.join(rel)).ok());
+        let after = fs::metadata(file).ok();
+
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                before.len() != after.len() || before.modified().ok() != after.modified().ok()
+            }
+            _ => true,
+        }
+    });
+
+    Ok(files)
+}
+
+/// Whether to sample `path`, with the given probability.  The choice depends
+/// only on the path and `seed`, not on what else is in the tree or the order
+/// it's walked, so the same seed picks the same files from run to run.
+pub(crate) fn selected(path: &Path, fraction: f64, seed: u64) -> bool {
+    // FNV-1a over the path, mixed with the seed through splitmix64
+    let hash = path
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+
+    let mut z = (hash ^ seed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    // The top 53 bits as a float in [0, 1)
+    ((z >> 11) as f64 / (1u64 << 53) as f64) < fraction
+}
+
+#[test]
+fn selection() {
+    let paths: Vec<PathBuf> = (0..10_000)
+        .map(|i| PathBuf::from(format!("f{}", i)))
+        .collect();
+    let picked =
+        |seed| -> Vec<&PathBuf> { paths.iter().filter(|p| selected(p, 0.1, seed)).collect() };
+
+    let a = picked(1);
+    assert_eq!(a, picked(1));
+    assert_ne!(a, picked(2));
+    assert!((900..1100).contains(&a.len()), "{}", a.len());
+    assert!(paths.iter().all(|p| selected(p, 1.0, 7)));
+    assert!(!paths.iter().any(|p| selected(p, 0.0, 7)));
+}